@@ -0,0 +1,345 @@
+use std::{net::SocketAddr, time::Duration};
+
+use bytes::{Buf, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::mpsc,
+    task::JoinHandle,
+    time,
+};
+
+use crate::{
+    mse::{Rc4, StreamCipher},
+    peer_connection::{
+        message_length, Handshake, Message, PeerError, PeerTimeouts, HANDSHAKE_LENGTH,
+    },
+};
+
+/// Outgoing messages queued for a peer before senders have to wait for the socket
+pub const DEFAULT_SEND_QUEUE: usize = 64;
+
+/// Takes the next complete message out of `buf`, `None` until all its bytes arrived
+pub fn decode_message(buf: &mut BytesMut) -> Result<Option<Message>, PeerError> {
+    let Some(prefix) = buf.get(..4) else {
+        return Ok(None);
+    };
+    let length = message_length(prefix.try_into().unwrap())?;
+
+    if buf.len() < 4 + length {
+        // avoids growing the buffer in small steps while a block arrives
+        buf.reserve(4 + length - buf.len());
+        return Ok(None);
+    }
+
+    buf.advance(4);
+    let frame = buf.split_to(length);
+    Ok(Some(Message::decode(&frame)?))
+}
+
+pub async fn write_handshake<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    handshake: &Handshake,
+    timeout: Duration,
+) -> Result<(), PeerError> {
+    time::timeout(timeout, writer.write_all(&handshake.encode()))
+        .await
+        .map_err(|_| PeerError::Timeout)??;
+    Ok(())
+}
+
+/// Reads handshake of the remote peer, on incoming connections it tells us the torrent
+pub async fn read_handshake<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    timeout: Duration,
+) -> Result<Handshake, PeerError> {
+    let mut buf = [0; HANDSHAKE_LENGTH];
+    time::timeout(timeout, reader.read_exact(&mut buf))
+        .await
+        .map_err(|_| PeerError::Timeout)??;
+    Handshake::decode(&buf)
+}
+
+/// Reading half of a peer connection, decodes messages as they arrive
+pub struct PeerReader<R> {
+    reader: R,
+    buf: BytesMut,
+    timeouts: PeerTimeouts,
+    /// decrypts received bytes on encrypted connections
+    cipher: Option<Rc4>,
+}
+
+impl<R: AsyncRead + Unpin> PeerReader<R> {
+    pub fn new(reader: R, timeouts: PeerTimeouts) -> Self {
+        PeerReader {
+            reader,
+            buf: BytesMut::with_capacity(4096),
+            timeouts,
+            cipher: None,
+        }
+    }
+
+    /// Waits for the next message. Fails with `PeerError::Timeout` when the peer is idle
+    /// for too long or stalls mid message.
+    pub async fn read_message(&mut self) -> Result<Message, PeerError> {
+        loop {
+            if let Some(message) = decode_message(&mut self.buf)? {
+                return Ok(message);
+            }
+
+            let timeout = if self.buf.is_empty() {
+                self.timeouts.idle
+            } else {
+                self.timeouts.read
+            };
+            let read = time::timeout(timeout, self.reader.read_buf(&mut self.buf))
+                .await
+                .map_err(|_| PeerError::Timeout)??;
+            if read == 0 {
+                return Err(PeerError::Disconnected);
+            }
+            if let Some(cipher) = &mut self.cipher {
+                let length = self.buf.len();
+                cipher.apply(&mut self.buf[length - read..]);
+            }
+        }
+    }
+}
+
+/// Writing half of a peer connection, sending waits while the socket buffer is full
+pub struct PeerWriter<W> {
+    writer: W,
+    timeouts: PeerTimeouts,
+    /// encrypts sent bytes on encrypted connections
+    cipher: Option<Rc4>,
+}
+
+impl<W: AsyncWrite + Unpin + Send + 'static> PeerWriter<W> {
+    pub fn new(writer: W, timeouts: PeerTimeouts) -> Self {
+        PeerWriter {
+            writer,
+            timeouts,
+            cipher: None,
+        }
+    }
+
+    pub async fn send_message(&mut self, message: &Message) -> Result<(), PeerError> {
+        let mut buf = message.encode();
+        if let Some(cipher) = &mut self.cipher {
+            cipher.apply(&mut buf);
+        }
+        time::timeout(self.timeouts.read, self.writer.write_all(&buf))
+            .await
+            .map_err(|_| PeerError::Timeout)??;
+        Ok(())
+    }
+
+    /// Moves writing to its own task fed by a bounded queue, so any number of tasks can send
+    /// and they slow down together with the peer. Keep-alives are sent while the queue is
+    /// quiet. The task ends when every sender is dropped or the peer fails.
+    pub fn spawn(
+        mut self,
+        capacity: usize,
+    ) -> (mpsc::Sender<Message>, JoinHandle<Result<(), PeerError>>) {
+        let (sender, mut receiver) = mpsc::channel(capacity);
+
+        let task = tokio::spawn(async move {
+            loop {
+                let message = match time::timeout(self.timeouts.keep_alive, receiver.recv()).await {
+                    Ok(Some(message)) => message,
+                    Ok(None) => return Ok(()),
+                    Err(_) => Message::KeepAlive,
+                };
+                self.send_message(&message).await?;
+            }
+        });
+
+        (sender, task)
+    }
+}
+
+/// Async counterpart of `PeerConnection`, one task per peer instead of one thread
+pub struct AsyncPeerConnection {
+    pub peer: SocketAddr,
+    pub peer_id: String,
+    pub extension_enabled: bool,
+    /// peer supports the fast extension (BEP 6)
+    pub fast_enabled: bool,
+    pub info_hash: [u8; 20],
+    pub timeouts: PeerTimeouts,
+    reader: PeerReader<OwnedReadHalf>,
+    writer: PeerWriter<OwnedWriteHalf>,
+}
+
+impl AsyncPeerConnection {
+    /// Connects to the peer and exchanges plaintext handshakes
+    pub async fn connect(
+        peer: &SocketAddr,
+        info_hash: &[u8],
+        peer_id: &[u8; 20],
+        extension_enabled: bool,
+        timeouts: PeerTimeouts,
+    ) -> Result<Self, PeerError> {
+        let mut stream = time::timeout(timeouts.connect, TcpStream::connect(peer))
+            .await
+            .map_err(|_| PeerError::Timeout)?
+            .map_err(PeerError::ConnectFailed)?;
+
+        let handshake = Handshake::new(info_hash, peer_id, extension_enabled);
+        write_handshake(&mut stream, &handshake, timeouts.read).await?;
+
+        let remote = read_handshake(&mut stream, timeouts.read).await?;
+        if remote.info_hash != handshake.info_hash {
+            return Err(PeerError::HandshakeMismatch("info hash"));
+        }
+
+        Self::new(stream, &remote, timeouts)
+    }
+
+    /// Wraps stream whose handshakes were already exchanged
+    pub fn new(
+        stream: TcpStream,
+        remote: &Handshake,
+        timeouts: PeerTimeouts,
+    ) -> Result<Self, PeerError> {
+        Self::new_encrypted(stream, remote, timeouts, None, &[])
+    }
+
+    /// Wraps stream after the encrypted handshake (MSE), `received` are decrypted bytes
+    /// the peer sent after its BitTorrent handshake
+    pub fn new_encrypted(
+        stream: TcpStream,
+        remote: &Handshake,
+        timeouts: PeerTimeouts,
+        cipher: Option<StreamCipher>,
+        received: &[u8],
+    ) -> Result<Self, PeerError> {
+        let peer = stream.peer_addr()?;
+        let (reader, writer) = stream.into_split();
+        let mut reader = PeerReader::new(reader, timeouts);
+        let mut writer = PeerWriter::new(writer, timeouts);
+        reader.buf.extend_from_slice(received);
+        if let Some(cipher) = cipher {
+            reader.cipher = Some(cipher.decrypt);
+            writer.cipher = Some(cipher.encrypt);
+        }
+
+        Ok(AsyncPeerConnection {
+            peer,
+            peer_id: hex::encode(remote.peer_id),
+            extension_enabled: remote.extension_enabled(),
+            fast_enabled: remote.fast_enabled(),
+            info_hash: remote.info_hash,
+            timeouts,
+            reader,
+            writer,
+        })
+    }
+
+    pub async fn send_message(&mut self, message: &Message) -> Result<(), PeerError> {
+        self.writer.send_message(message).await
+    }
+
+    pub async fn read_message(&mut self) -> Result<Message, PeerError> {
+        self.reader.read_message().await
+    }
+
+    /// Splits connection so reading and writing can happen in different tasks
+    pub fn into_split(self) -> (PeerReader<OwnedReadHalf>, PeerWriter<OwnedWriteHalf>) {
+        (self.reader, self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io;
+
+    use super::*;
+    use crate::peer_connection::MAX_MESSAGE_LENGTH;
+
+    fn buffer(bytes: &[u8]) -> BytesMut {
+        BytesMut::from(bytes)
+    }
+
+    #[test]
+    fn partial_prefix_waits_for_more_bytes() {
+        let mut buf = buffer(&[0, 0, 0]);
+        assert!(decode_message(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 3);
+
+        buf.extend_from_slice(&[1, 1]);
+        assert_eq!(decode_message(&mut buf).unwrap(), Some(Message::Unchoke));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn partial_frame_waits_for_more_bytes() {
+        let encoded = Message::Piece {
+            index: 1,
+            begin: 0,
+            block: vec![7; 100],
+        }
+        .encode();
+        let mut buf = buffer(&encoded[..50]);
+        assert!(decode_message(&mut buf).unwrap().is_none());
+        assert_eq!(&buf[..], &encoded[..50]);
+        assert!(buf.capacity() >= encoded.len());
+
+        buf.extend_from_slice(&encoded[50..]);
+        assert!(matches!(
+            decode_message(&mut buf).unwrap(),
+            Some(Message::Piece { index: 1, begin: 0, block }) if block == vec![7; 100]
+        ));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn several_frames_in_one_buffer() {
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(&Message::Have { index: 3 }.encode());
+        buf.extend_from_slice(&Message::KeepAlive.encode());
+        buf.extend_from_slice(&Message::Interested.encode());
+        // start of the next message
+        buf.extend_from_slice(&[0, 0]);
+
+        assert_eq!(
+            decode_message(&mut buf).unwrap(),
+            Some(Message::Have { index: 3 })
+        );
+        assert_eq!(decode_message(&mut buf).unwrap(), Some(Message::KeepAlive));
+        assert_eq!(decode_message(&mut buf).unwrap(), Some(Message::Interested));
+        assert!(decode_message(&mut buf).unwrap().is_none());
+        assert_eq!(&buf[..], &[0, 0]);
+    }
+
+    #[test]
+    fn oversized_length_is_rejected() {
+        let mut buf = buffer(&(MAX_MESSAGE_LENGTH as u32 + 1).to_be_bytes());
+        assert!(matches!(
+            decode_message(&mut buf),
+            Err(PeerError::MessageTooLarge(length)) if length == MAX_MESSAGE_LENGTH + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn idle_writer_sends_keep_alives() {
+        let (local, remote) = io::duplex(1024);
+        let timeouts = PeerTimeouts {
+            keep_alive: Duration::from_millis(50),
+            ..PeerTimeouts::default()
+        };
+        let (sender, task) = PeerWriter::new(local, timeouts).spawn(DEFAULT_SEND_QUEUE);
+        let mut reader = PeerReader::new(remote, timeouts);
+
+        sender.send(Message::Interested).await.unwrap();
+        assert_eq!(reader.read_message().await.unwrap(), Message::Interested);
+        let keep_alive = time::timeout(Duration::from_secs(5), reader.read_message()).await;
+        assert_eq!(keep_alive.unwrap().unwrap(), Message::KeepAlive);
+
+        drop(sender);
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,280 @@
+use std::{collections::HashSet, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    task::{self, JoinSet},
+    time,
+};
+
+use crate::{
+    client_config::ClientConfig,
+    discover_peers::{
+        announce_query, parse_announce_response, AnnounceEvent, AnnounceRequest, AnnounceResponse,
+        TrackerError,
+    },
+    udp_tracker::UdpTracker,
+};
+
+/// Upper bound of a single announce, including DNS lookup and UDP retransmissions
+pub const DEFAULT_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// First UDP response timeout, doubled with every retransmission. Shorter than 15 seconds
+/// of BEP 15, otherwise nothing would be retransmitted within the announce timeout.
+pub const DEFAULT_UDP_RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Announce parameters shared by all trackers of a torrent
+#[derive(Debug, Clone)]
+pub struct AnnounceParams {
+    pub info_hash: Vec<u8>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+}
+
+impl AnnounceParams {
+    fn request<'a>(&'a self, client: &'a ClientConfig) -> AnnounceRequest<'a> {
+        AnnounceRequest {
+            info_hash: &self.info_hash,
+            uploaded: self.uploaded,
+            downloaded: self.downloaded,
+            left: self.left,
+            event: self.event,
+            tracker_id: None,
+            client,
+        }
+    }
+}
+
+/// Outcome of announcing to every tier
+#[derive(Debug, Default)]
+pub struct TiersAnnounce {
+    /// peers of all successful announces, without duplicates
+    pub peers: Vec<SocketAddr>,
+    pub responses: Vec<(String, AnnounceResponse)>,
+    pub errors: Vec<(String, TrackerError)>,
+}
+
+/// Tracker client for async code, HTTP and UDP announces never block the runtime
+pub struct AsyncTrackerClient {
+    client: ClientConfig,
+    http: reqwest::Client,
+    pub announce_timeout: Duration,
+    pub udp_retransmit_timeout: Duration,
+}
+
+impl AsyncTrackerClient {
+    pub fn new(client: &ClientConfig) -> Result<Self, TrackerError> {
+        Ok(AsyncTrackerClient {
+            client: client.clone(),
+            http: reqwest::Client::builder()
+                .user_agent(client.user_agent.as_str())
+                .build()?,
+            announce_timeout: DEFAULT_ANNOUNCE_TIMEOUT,
+            udp_retransmit_timeout: DEFAULT_UDP_RETRANSMIT_TIMEOUT,
+        })
+    }
+
+    /// Announces to all tiers at the same time, within a tier trackers are tried in order
+    /// until one of them answers (BEP 12). Dropping the returned future cancels every
+    /// announce still in flight, UDP ones give up on their own within `announce_timeout`.
+    pub async fn announce_tiers(
+        self: &Arc<Self>,
+        tiers: &[Vec<String>],
+        params: &AnnounceParams,
+    ) -> TiersAnnounce {
+        let mut tasks = JoinSet::new();
+        for tier in tiers {
+            let tracker_client = self.clone();
+            let tier = tier.clone();
+            let params = params.clone();
+            tasks.spawn(async move { tracker_client.announce_tier(&tier, &params).await });
+        }
+
+        let mut result = TiersAnnounce::default();
+        let mut seen_peers = HashSet::new();
+
+        while let Some(tier_result) = tasks.join_next().await {
+            let Ok(tier_result) = tier_result else {
+                continue;
+            };
+
+            for (tracker_url, response) in tier_result {
+                match response {
+                    Ok(response) => {
+                        result.peers.extend(
+                            response
+                                .peers
+                                .iter()
+                                .filter(|peer| seen_peers.insert(**peer)),
+                        );
+                        result.responses.push((tracker_url, response));
+                    }
+                    Err(e) => result.errors.push((tracker_url, e)),
+                }
+            }
+        }
+
+        result
+    }
+
+    async fn announce_tier(
+        &self,
+        tier: &[String],
+        params: &AnnounceParams,
+    ) -> Vec<(String, Result<AnnounceResponse, TrackerError>)> {
+        let mut attempts = Vec::new();
+
+        for tracker_url in tier {
+            let response = self.announce(tracker_url, params).await;
+            let succeeded = response.is_ok();
+            attempts.push((tracker_url.clone(), response));
+            if succeeded {
+                break;
+            }
+        }
+
+        attempts
+    }
+
+    /// Announces to a single `http(s)://` or `udp://` tracker within `announce_timeout`
+    pub async fn announce(
+        &self,
+        tracker_url: &str,
+        params: &AnnounceParams,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let announce = async {
+            if tracker_url.starts_with("udp://") {
+                self.udp_announce(tracker_url, params).await
+            } else {
+                self.http_announce(tracker_url, &params.request(&self.client))
+                    .await
+            }
+        };
+
+        time::timeout(self.announce_timeout, announce)
+            .await
+            .unwrap_or(Err(TrackerError::Timeout))
+    }
+
+    async fn http_announce(
+        &self,
+        tracker_url: &str,
+        request: &AnnounceRequest<'_>,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let body = self
+            .http
+            .get(tracker_url)
+            .query(&announce_query(request))
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        parse_announce_response(&body)
+    }
+
+    /// Runs the blocking UDP tracker client on a blocking thread, its deadline ends it
+    /// even when the announce future is dropped
+    async fn udp_announce(
+        &self,
+        tracker_url: &str,
+        params: &AnnounceParams,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let (tracker_url, params, client) =
+            (tracker_url.to_string(), params.clone(), self.client.clone());
+        let (base_timeout, deadline) = (self.udp_retransmit_timeout, self.announce_timeout);
+
+        task::spawn_blocking(move || {
+            let mut tracker = UdpTracker::new(&tracker_url)?;
+            tracker.base_timeout = base_timeout;
+            tracker.deadline = Some(deadline);
+            tracker.announce(&params.request(&client))
+        })
+        .await
+        .map_err(|e| TrackerError::Io(io::Error::other(e)))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use super::*;
+    use crate::tracker_server::{
+        ServerAnnounce, ServerAnnounceEvent, TrackerServer, TrackerServerConfig,
+    };
+
+    const INFO_HASH: [u8; 20] = *b"async-tracker-test-1";
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+
+    /// Local tracker which already knows `peers`, returns its HTTP and UDP announce urls
+    fn stand_in_tracker(peers: &[SocketAddr]) -> (Arc<TrackerServer>, String, String) {
+        let server = TrackerServer::new(TrackerServerConfig::default());
+        for (i, peer) in peers.iter().enumerate() {
+            server
+                .announce(ServerAnnounce {
+                    info_hash: INFO_HASH.to_vec(),
+                    peer_id: vec![i as u8; 20],
+                    address: *peer,
+                    left: 100,
+                    event: ServerAnnounceEvent::Started,
+                    numwant: None,
+                })
+                .unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let http_url = format!("http://{}/announce", listener.local_addr().unwrap());
+        server.serve_http(listener);
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let udp_url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        server.serve_udp(socket);
+
+        (server, http_url, udp_url)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tiers_fall_back_and_peers_are_deduplicated() {
+        let (_first, _, first_udp_url) = stand_in_tracker(&[peer(7001), peer(7002)]);
+        let (_second, second_http_url, _) = stand_in_tracker(&[peer(7002), peer(7003)]);
+        // bound but never answering
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_url = format!("udp://{}/announce", silent.local_addr().unwrap());
+
+        let mut tracker_client = AsyncTrackerClient::new(&ClientConfig::default()).unwrap();
+        tracker_client.announce_timeout = Duration::from_millis(500);
+        tracker_client.udp_retransmit_timeout = Duration::from_millis(100);
+        let tiers = vec![
+            vec![silent_url.clone(), first_udp_url.clone()],
+            vec![second_http_url.clone()],
+        ];
+        let params = AnnounceParams {
+            info_hash: INFO_HASH.to_vec(),
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            event: Some(AnnounceEvent::Started),
+        };
+
+        let result = Arc::new(tracker_client)
+            .announce_tiers(&tiers, &params)
+            .await;
+
+        let mut peers = result.peers.clone();
+        peers.sort();
+        assert_eq!(peers, vec![peer(7001), peer(7002), peer(7003)]);
+        let mut responding: Vec<_> = result.responses.iter().map(|(url, _)| url).collect();
+        responding.sort();
+        let mut expected = vec![&first_udp_url, &second_http_url];
+        expected.sort();
+        assert_eq!(responding, expected);
+        assert!(matches!(
+            result.errors.as_slice(),
+            [(url, TrackerError::Timeout)] if *url == silent_url
+        ));
+    }
+}
@@ -2,6 +2,7 @@ use serde_json::Map;
 
 #[derive(Debug)]
 pub enum BenDecodeErrors {
+    IntegerDecodingError,
     StringDecodingError,
     MissingValueForDictKey,
     End,
@@ -16,17 +17,19 @@ pub fn decode_bencoded_value(
     match chars.next() {
         // integer
         Some(b'i') => {
-            let not_e: String =
-                String::from_utf8(chars.take_while(|c| c != &b'e').collect()).unwrap();
+            let not_e: String = String::from_utf8(chars.take_while(|c| c != &b'e').collect())
+                .map_err(|_| BenDecodeErrors::IntegerDecodingError)?;
 
-            return Ok(serde_json::Value::Number(
-                not_e.parse::<i64>().unwrap().into(),
-            ));
+            match not_e.parse::<i64>() {
+                Ok(number) => Ok(serde_json::Value::Number(number.into())),
+                _ => Err(BenDecodeErrors::IntegerDecodingError),
+            }
         }
         // string
         Some(c) if c.is_ascii_digit() => {
             let mut digits: String =
-                String::from_utf8(chars.by_ref().take_while(|c| c != &b':').collect()).unwrap();
+                String::from_utf8(chars.by_ref().take_while(|c| c != &b':').collect())
+                    .map_err(|_| BenDecodeErrors::StringDecodingError)?;
             digits.insert(0, c as char);
 
             let length: usize = match digits.parse() {
@@ -34,9 +37,13 @@ pub fn decode_bencoded_value(
                 _ => return Err(BenDecodeErrors::StringDecodingError),
             };
 
-            let string: String =
-                unsafe { String::from_utf8_unchecked(chars.take(length).collect()) };
-            return Ok(serde_json::Value::String(string));
+            let bytes: Vec<u8> = chars.take(length).collect();
+            if bytes.len() != length {
+                return Err(BenDecodeErrors::StringDecodingError);
+            }
+
+            let string: String = unsafe { String::from_utf8_unchecked(bytes) };
+            Ok(serde_json::Value::String(string))
         }
         // list
         Some(b'l') => {
@@ -68,13 +75,26 @@ pub fn decode_bencoded_value(
             }
         }
         // terminator
-        Some(b'e') => {
-            return Err(BenDecodeErrors::End);
-        }
+        Some(b'e') => Err(BenDecodeErrors::End),
         w => {
             println!("unexpected char");
             println!("{:?}", w);
-            return Err(BenDecodeErrors::UnexepctedChar);
+            Err(BenDecodeErrors::UnexepctedChar)
         }
     }
 }
+
+pub fn encode_string(bytes: &[u8]) -> Vec<u8> {
+    let mut encoded = format!("{}:", bytes.len()).into_bytes();
+    encoded.extend_from_slice(bytes);
+    encoded
+}
+
+pub fn encode_list(items: impl IntoIterator<Item = Vec<u8>>) -> Vec<u8> {
+    let mut encoded = vec![b'l'];
+    items
+        .into_iter()
+        .for_each(|item| encoded.extend_from_slice(&item));
+    encoded.push(b'e');
+    encoded
+}
@@ -0,0 +1,267 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{sync::watch, task::JoinHandle, time};
+
+use crate::random_u32;
+
+/// Peers are re-ranked this often
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Optimistic unchoke moves to another peer every third round
+pub const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Unchoked peers of a torrent, including the optimistic one
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+struct ChokerPeer {
+    address: IpAddr,
+    interested: bool,
+    unchoked: bool,
+    /// bytes since the last round
+    uploaded: u64,
+    choked: watch::Sender<bool>,
+}
+
+impl ChokerPeer {
+    fn set_unchoked(&mut self, unchoked: bool) {
+        self.unchoked = unchoked;
+        self.choked.send_if_modified(|choked| {
+            let modified = *choked == unchoked;
+            *choked = !unchoked;
+            modified
+        });
+    }
+}
+
+#[derive(Default)]
+struct ChokerState {
+    peers: HashMap<u64, ChokerPeer>,
+    /// bytes our own connections downloaded from each address since the last round,
+    /// they are separate from the connections peers open to us
+    downloaded: HashMap<IpAddr, u64>,
+    next_id: u64,
+    optimistic: Option<u64>,
+    rounds: u32,
+}
+
+/// Tit-for-tat choker of a torrent (BEP 3). Every `CHOKE_INTERVAL` the interested peers
+/// we download from fastest are unchoked, and one more random peer gets a chance through
+/// the optimistic slot. Downloads are matched to peers by ip address. When seeding, or
+/// before we downloaded anything from them, peers we upload to fastest are preferred.
+pub struct Choker {
+    upload_slots: usize,
+    state: Mutex<ChokerState>,
+}
+
+/// Connection side of the choker, the peer is forgotten when it's dropped
+pub struct ChokerPeerHandle {
+    id: u64,
+    choker: Arc<Choker>,
+    /// true while the peer is choked
+    pub choked: watch::Receiver<bool>,
+}
+
+impl Choker {
+    pub fn new(upload_slots: usize) -> Arc<Self> {
+        Arc::new(Choker {
+            upload_slots: upload_slots.max(1),
+            state: Mutex::new(ChokerState::default()),
+        })
+    }
+
+    /// Re-ranks peers every `CHOKE_INTERVAL`, `seeding` tells which rates to rank by
+    pub fn start(self: &Arc<Self>, seeding: impl Fn() -> bool + Send + 'static) -> JoinHandle<()> {
+        let choker = self.clone();
+        tokio::spawn(async move {
+            let mut interval = time::interval(CHOKE_INTERVAL);
+            loop {
+                interval.tick().await;
+                choker.rechoke(seeding());
+            }
+        })
+    }
+
+    /// New connections start choked
+    pub fn add_peer(self: &Arc<Self>, address: IpAddr) -> ChokerPeerHandle {
+        let (sender, choked) = watch::channel(true);
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.peers.insert(
+            id,
+            ChokerPeer {
+                address,
+                interested: false,
+                unchoked: false,
+                uploaded: 0,
+                choked: sender,
+            },
+        );
+
+        ChokerPeerHandle {
+            id,
+            choker: self.clone(),
+            choked,
+        }
+    }
+
+    /// Records bytes we downloaded from the peer at `address`
+    pub fn record_download(&self, address: IpAddr, bytes: u64) {
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .downloaded
+            .entry(address)
+            .or_default() += bytes;
+    }
+
+    pub fn rechoke(&self, seeding: bool) {
+        let mut state = self.state.lock().unwrap();
+        let rounds_per_optimistic =
+            (OPTIMISTIC_UNCHOKE_INTERVAL.as_secs() / CHOKE_INTERVAL.as_secs()) as u32;
+        let rotate_optimistic = state.rounds.is_multiple_of(rounds_per_optimistic);
+        state.rounds += 1;
+
+        let interested: Vec<_> = state
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.interested)
+            .map(|(id, peer)| {
+                let downloaded = state.downloaded.get(&peer.address).copied();
+                (*id, downloaded.unwrap_or(0), peer.uploaded)
+            })
+            .collect();
+        // seeds have nothing to download, they prefer peers taking data fastest,
+        // and so do we while none of the peers gave us anything
+        let by_download = !seeding && interested.iter().any(|(_, downloaded, _)| *downloaded > 0);
+        let mut ranked: Vec<_> = interested
+            .into_iter()
+            .map(|(id, downloaded, uploaded)| (id, if by_download { downloaded } else { uploaded }))
+            .collect();
+        ranked.sort_unstable_by_key(|(_, rate)| std::cmp::Reverse(*rate));
+
+        // one slot is kept for the optimistic unchoke
+        let mut unchoked: Vec<_> = ranked
+            .iter()
+            .take(self.upload_slots - 1)
+            .map(|(id, _)| *id)
+            .collect();
+
+        let optimistic = state.optimistic.filter(|id| {
+            !rotate_optimistic && state.peers.get(id).is_some_and(|peer| peer.interested)
+        });
+        let optimistic = optimistic.or_else(|| {
+            let candidates: Vec<_> = ranked
+                .iter()
+                .map(|(id, _)| *id)
+                .filter(|id| !unchoked.contains(id))
+                .collect();
+            (!candidates.is_empty()).then(|| candidates[random_u32() as usize % candidates.len()])
+        });
+        state.optimistic = optimistic;
+        unchoked.extend(optimistic.filter(|id| !unchoked.contains(id)));
+
+        state.downloaded.clear();
+        for (id, peer) in state.peers.iter_mut() {
+            peer.uploaded = 0;
+            peer.set_unchoked(unchoked.contains(id));
+        }
+    }
+
+    fn update(&self, id: u64, update: impl FnOnce(&mut ChokerPeer)) {
+        if let Some(peer) = self.state.lock().unwrap().peers.get_mut(&id) {
+            update(peer);
+        }
+    }
+}
+
+impl ChokerPeerHandle {
+    /// Interested peer is unchoked right away while there is a free slot
+    pub fn set_interested(&self, interested: bool) {
+        let mut state = self.choker.state.lock().unwrap();
+        let unchoked_count = state.peers.values().filter(|peer| peer.unchoked).count();
+
+        let Some(peer) = state.peers.get_mut(&self.id) else {
+            return;
+        };
+        peer.interested = interested;
+
+        if interested && unchoked_count < self.choker.upload_slots {
+            peer.set_unchoked(true);
+        } else if !interested {
+            // uninterested peer leaves its slot to others
+            peer.set_unchoked(false);
+        }
+    }
+
+    pub fn record_upload(&self, bytes: u64) {
+        self.choker.update(self.id, |peer| peer.uploaded += bytes);
+    }
+}
+
+impl Drop for ChokerPeerHandle {
+    fn drop(&mut self) {
+        let mut state = self.choker.state.lock().unwrap();
+        state.peers.remove(&self.id);
+        if state.optimistic == Some(self.id) {
+            state.optimistic = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn interested_peers(choker: &Arc<Choker>, count: u8) -> Vec<ChokerPeerHandle> {
+        (1..=count)
+            .map(|i| {
+                let peer = choker.add_peer(IpAddr::from([10, 0, 0, i]));
+                peer.set_interested(true);
+                peer
+            })
+            .collect()
+    }
+
+    fn unchoked(peers: &[ChokerPeerHandle]) -> Vec<usize> {
+        (0..peers.len())
+            .filter(|i| !*peers[*i].choked.borrow())
+            .collect()
+    }
+
+    #[test]
+    fn peers_we_download_from_are_unchoked() {
+        // one regular slot and the optimistic one
+        let choker = Choker::new(2);
+        let peers = interested_peers(&choker, 4);
+
+        choker.record_download(IpAddr::from([10, 0, 0, 3]), 1000);
+        peers[0].record_upload(5000);
+        choker.rechoke(false);
+
+        assert!(unchoked(&peers).contains(&2));
+        assert_eq!(unchoked(&peers).len(), 2);
+    }
+
+    #[test]
+    fn upload_rate_ranks_without_downloads() {
+        let choker = Choker::new(2);
+        let peers = interested_peers(&choker, 4);
+
+        peers[1].record_upload(5000);
+        choker.rechoke(false);
+        assert!(unchoked(&peers).contains(&1));
+
+        // seeds ignore what they downloaded
+        choker.record_download(IpAddr::from([10, 0, 0, 3]), 1000);
+        peers[3].record_upload(5000);
+        choker.rechoke(true);
+        assert!(unchoked(&peers).contains(&3));
+    }
+}
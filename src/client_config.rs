@@ -0,0 +1,68 @@
+use std::net::IpAddr;
+
+use crate::{
+    choker::DEFAULT_UPLOAD_SLOTS, mse::EncryptionPolicy, peer_connection::PeerTimeouts,
+    random_bytes, random_u32,
+};
+
+/// Azureus-style client prefix, `-` + two letter client id + four digit version + `-`
+pub const CLIENT_PREFIX: &str = "-RB0010-";
+
+pub const DEFAULT_PORT: u16 = 6881;
+
+pub const DEFAULT_USER_AGENT: &str = "bittorrent-rust/0.1.0";
+
+/// Identity of our client, sent to trackers and in peer handshakes
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub peer_id: [u8; 20],
+    /// port we accept peer connections on
+    pub port: u16,
+    /// random per session value, lets trackers recognize us when our ip changes
+    pub key: u32,
+    /// number of peers we ask trackers for, tracker default when `None`
+    pub numwant: Option<u32>,
+    /// announced ip, trackers use the address of the request when `None`
+    pub ip: Option<IpAddr>,
+    /// `User-Agent` header of HTTP tracker requests
+    pub user_agent: String,
+    pub peer_timeouts: PeerTimeouts,
+    /// peers of a torrent we upload to at the same time
+    pub upload_slots: usize,
+    /// whether peer connections use Message Stream Encryption
+    pub encryption: EncryptionPolicy,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            peer_id: generate_peer_id(CLIENT_PREFIX),
+            port: DEFAULT_PORT,
+            key: random_u32(),
+            numwant: None,
+            ip: None,
+            user_agent: String::from(DEFAULT_USER_AGENT),
+            peer_timeouts: PeerTimeouts::default(),
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            encryption: EncryptionPolicy::default(),
+        }
+    }
+}
+
+/// Generates peer id starting with `prefix` followed by random alphanumeric characters
+pub fn generate_peer_id(prefix: &str) -> [u8; 20] {
+    const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+    let mut peer_id = [0; 20];
+    let prefix = &prefix.as_bytes()[..prefix.len().min(20)];
+    peer_id[..prefix.len()].copy_from_slice(prefix);
+
+    for (byte, random) in peer_id[prefix.len()..]
+        .iter_mut()
+        .zip(random_bytes(20 - prefix.len()))
+    {
+        *byte = ALPHABET[random as usize % ALPHABET.len()];
+    }
+
+    peer_id
+}
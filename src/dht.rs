@@ -0,0 +1,1051 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{discover_peers::parse_compact_peers, random_bytes, secure_random_bytes, sha1_it};
+
+/// Bucket size, also number of closest nodes a lookup converges to
+pub const K: usize = 8;
+
+/// Queries sent in parallel during iterative lookups
+pub const ALPHA: usize = 3;
+
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Random transaction ids keep other hosts from guessing responses to our queries
+const TRANSACTION_ID_LENGTH: usize = 4;
+
+/// Node which didn't respond for this long can be replaced in a full bucket
+pub const NODE_STALE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Announced peers are forgotten after this time
+pub const PEER_EXPIRY: Duration = Duration::from_secs(30 * 60);
+
+/// Active torrents are looked up and announced again this often, before nodes forget us
+pub const REANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// How long the receiving thread blocks before checking whether the node is still used
+const RECEIVE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tokens are valid for up to two rotations
+pub const TOKEN_ROTATION: Duration = Duration::from_secs(5 * 60);
+
+pub const DEFAULT_BOOTSTRAP_NODES: [&str; 3] = [
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+pub type NodeId = [u8; 20];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NodeInfo {
+    pub id: NodeId,
+    pub address: SocketAddr,
+}
+
+pub fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut distance = [0; 20];
+    for (i, byte) in distance.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+    distance
+}
+
+#[derive(Debug, Clone)]
+pub struct DhtConfig {
+    pub bind_address: SocketAddr,
+    /// `host:port` of nodes used to join the network
+    pub bootstrap_nodes: Vec<String>,
+    /// routing table is loaded from and saved to this file
+    pub state_path: Option<PathBuf>,
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        DhtConfig {
+            bind_address: (Ipv4Addr::UNSPECIFIED, 6881).into(),
+            bootstrap_nodes: DEFAULT_BOOTSTRAP_NODES.map(String::from).to_vec(),
+            state_path: None,
+        }
+    }
+}
+
+struct RoutingNode {
+    info: NodeInfo,
+    last_seen: Instant,
+}
+
+/// Kademlia routing table, bucket `i` holds nodes whose distance from us has `i` leading zero bits
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<RoutingNode>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        RoutingTable {
+            own_id,
+            buckets: (0..160).map(|_| Vec::with_capacity(K)).collect(),
+        }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let distance = distance(&self.own_id, id);
+        let leading_zeros = distance
+            .iter()
+            .position(|byte| *byte != 0)
+            .map(|i| i * 8 + distance[i].leading_zeros() as usize)?;
+        Some(leading_zeros)
+    }
+
+    /// Adds or refreshes node, full buckets only accept newcomers in place of stale nodes
+    pub fn insert(&mut self, info: NodeInfo) {
+        let Some(index) = self.bucket_index(&info.id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(node) = bucket.iter_mut().find(|node| node.info.id == info.id) {
+            node.info.address = info.address;
+            node.last_seen = Instant::now();
+            return;
+        }
+
+        let node = RoutingNode {
+            info,
+            last_seen: Instant::now(),
+        };
+
+        if bucket.len() < K {
+            bucket.push(node);
+        } else if let Some(stale) = bucket
+            .iter_mut()
+            .find(|node| node.last_seen.elapsed() > NODE_STALE_AFTER)
+        {
+            *stale = node;
+        }
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.bucket_index(id) {
+            self.buckets[index].retain(|node| node.info.id != *id);
+        }
+    }
+
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<NodeInfo> {
+        let mut nodes: Vec<_> = self.nodes().collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = NodeInfo> + '_ {
+        self.buckets.iter().flatten().map(|node| node.info)
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KrpcArgs {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    info_hash: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    implied_port: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct KrpcResponse {
+    id: ByteBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nodes: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    values: Option<Vec<ByteBuf>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<ByteBuf>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct KrpcMessage {
+    t: ByteBuf,
+    y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    q: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    a: Option<KrpcArgs>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    r: Option<KrpcResponse>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    e: Option<(i64, String)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DhtState {
+    id: ByteBuf,
+    nodes: ByteBuf,
+}
+
+struct TokenSecrets {
+    current: Vec<u8>,
+    previous: Vec<u8>,
+    rotated_at: Instant,
+}
+
+/// Result of an iterative `get_peers` lookup
+#[derive(Debug, Default)]
+pub struct PeersLookup {
+    pub peers: Vec<SocketAddr>,
+    /// closest nodes which gave us a token, these are the ones we announce to
+    pub nodes_with_tokens: Vec<(NodeInfo, Vec<u8>)>,
+}
+
+/// Queries waiting for a response by transaction id, with the address the response
+/// must come from
+type PendingQueries = HashMap<Vec<u8>, (SocketAddr, Sender<KrpcResponse>)>;
+
+/// Mainline DHT node (BEP 5), answers queries of other nodes on a background thread
+pub struct DhtNode {
+    id: NodeId,
+    socket: UdpSocket,
+    config: DhtConfig,
+    routing_table: Mutex<RoutingTable>,
+    pending: Mutex<PendingQueries>,
+    token_secrets: Mutex<TokenSecrets>,
+    announced_peers: Mutex<HashMap<NodeId, HashMap<SocketAddr, Instant>>>,
+}
+
+impl DhtNode {
+    /// Binds the socket, restores routing table from `state_path` and starts answering queries.
+    /// Call `bootstrap` to join the network.
+    pub fn start(config: DhtConfig) -> io::Result<Arc<Self>> {
+        let state = config
+            .state_path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|state| serde_bencode::from_bytes::<DhtState>(&state).ok());
+
+        let id: NodeId = state
+            .as_ref()
+            .and_then(|state| state.id.as_slice().try_into().ok())
+            .unwrap_or_else(|| random_bytes(20).try_into().unwrap());
+
+        let mut routing_table = RoutingTable::new(id);
+        if let Some(state) = &state {
+            for node in parse_compact_nodes(&state.nodes) {
+                routing_table.insert(node);
+            }
+        }
+
+        let node = Arc::new(DhtNode {
+            id,
+            socket: UdpSocket::bind(config.bind_address)?,
+            config,
+            routing_table: Mutex::new(routing_table),
+            pending: Mutex::new(HashMap::new()),
+            token_secrets: Mutex::new(TokenSecrets {
+                current: secure_random_bytes(16),
+                previous: secure_random_bytes(16),
+                rotated_at: Instant::now(),
+            }),
+            announced_peers: Mutex::new(HashMap::new()),
+        });
+
+        // the thread must not keep the node alive, it ends once the node is dropped
+        let socket = node.socket.try_clone()?;
+        socket.set_read_timeout(Some(RECEIVE_POLL_INTERVAL))?;
+        let weak = Arc::downgrade(&node);
+        thread::spawn(move || Self::receive_loop(socket, weak));
+
+        Ok(node)
+    }
+
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn nodes_count(&self) -> usize {
+        self.routing_table.lock().unwrap().len()
+    }
+
+    /// Joins the network through configured bootstrap nodes and nodes restored from state
+    pub fn bootstrap(&self) {
+        let bootstrap_addresses: Vec<SocketAddr> = self
+            .config
+            .bootstrap_nodes
+            .iter()
+            .filter_map(|node| node.to_socket_addrs().ok())
+            .flatten()
+            .filter(SocketAddr::is_ipv4)
+            .collect();
+
+        // ids of bootstrap nodes are unknown, so they are asked directly
+        let responses: Vec<_> = bootstrap_addresses
+            .iter()
+            .map(|address| self.send_query(*address, "find_node", self.find_node_args(&self.id)))
+            .collect();
+        for response in responses {
+            response.wait();
+        }
+
+        self.lookup(&self.id, false);
+        println!("DHT bootstrapped with {} nodes", self.nodes_count());
+    }
+
+    pub fn ping(&self, address: SocketAddr) -> Option<NodeId> {
+        let args = KrpcArgs {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+        let response = self.send_query(address, "ping", args).wait()?;
+        response.id.as_slice().try_into().ok()
+    }
+
+    /// Iterative `get_peers` lookup converging on nodes closest to the info hash
+    pub fn get_peers(&self, info_hash: &NodeId) -> PeersLookup {
+        self.lookup(info_hash, true)
+    }
+
+    /// Looks up peers and announces us as a peer to the closest nodes,
+    /// `port` is the port we accept peer connections on
+    pub fn announce_peer(&self, info_hash: &NodeId, port: u16) -> Vec<SocketAddr> {
+        let lookup = self.get_peers(info_hash);
+
+        let responses: Vec<_> = lookup
+            .nodes_with_tokens
+            .iter()
+            .map(|(node, token)| {
+                let args = KrpcArgs {
+                    id: ByteBuf::from(self.id.to_vec()),
+                    info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                    port: Some(port as i64),
+                    token: Some(ByteBuf::from(token.clone())),
+                    ..Default::default()
+                };
+                self.send_query(node.address, "announce_peer", args)
+            })
+            .collect();
+        for response in responses {
+            response.wait();
+        }
+
+        lookup.peers
+    }
+
+    /// Saves own id and routing table so the next start doesn't depend on bootstrap nodes
+    pub fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.config.state_path else {
+            return Ok(());
+        };
+
+        let nodes: Vec<_> = self.routing_table.lock().unwrap().nodes().collect();
+        let state = DhtState {
+            id: ByteBuf::from(self.id.to_vec()),
+            nodes: ByteBuf::from(encode_compact_nodes(&nodes)),
+        };
+
+        fs::write(path, serde_bencode::to_bytes(&state).unwrap())
+    }
+
+    fn lookup(&self, target: &NodeId, get_peers: bool) -> PeersLookup {
+        let mut shortlist: BTreeMap<NodeId, NodeInfo> = self
+            .routing_table
+            .lock()
+            .unwrap()
+            .closest(target, K)
+            .into_iter()
+            .map(|node| (distance(&node.id, target), node))
+            .collect();
+        let mut queried: HashSet<NodeId> = HashSet::new();
+        let mut responded: BTreeMap<NodeId, (NodeInfo, Vec<u8>)> = BTreeMap::new();
+        let mut peers: HashSet<SocketAddr> = HashSet::new();
+
+        loop {
+            // only the K closest known nodes are worth asking
+            let candidates: Vec<NodeInfo> = shortlist
+                .values()
+                .take(K)
+                .filter(|node| !queried.contains(&node.id))
+                .take(ALPHA)
+                .copied()
+                .collect();
+
+            if candidates.is_empty() {
+                break;
+            }
+
+            let queries: Vec<_> = candidates
+                .iter()
+                .map(|node| {
+                    queried.insert(node.id);
+                    let (method, args) = if get_peers {
+                        let args = KrpcArgs {
+                            id: ByteBuf::from(self.id.to_vec()),
+                            info_hash: Some(ByteBuf::from(target.to_vec())),
+                            ..Default::default()
+                        };
+                        ("get_peers", args)
+                    } else {
+                        ("find_node", self.find_node_args(target))
+                    };
+                    (*node, self.send_query(node.address, method, args))
+                })
+                .collect();
+
+            for (node, response) in queries {
+                let Some(response) = response.wait() else {
+                    shortlist.remove(&distance(&node.id, target));
+                    self.routing_table.lock().unwrap().remove(&node.id);
+                    continue;
+                };
+
+                if let Some(nodes) = &response.nodes {
+                    for node in parse_compact_nodes(nodes) {
+                        shortlist.insert(distance(&node.id, target), node);
+                    }
+                }
+                for values in response.values.iter().flatten() {
+                    peers.extend(parse_compact_peers(values, 6).into_iter().flatten());
+                }
+                if let Some(token) = response.token {
+                    responded.insert(distance(&node.id, target), (node, token.into_vec()));
+                }
+            }
+        }
+
+        PeersLookup {
+            peers: peers.into_iter().collect(),
+            nodes_with_tokens: responded.into_values().take(K).collect(),
+        }
+    }
+
+    fn find_node_args(&self, target: &NodeId) -> KrpcArgs {
+        KrpcArgs {
+            id: ByteBuf::from(self.id.to_vec()),
+            target: Some(ByteBuf::from(target.to_vec())),
+            ..Default::default()
+        }
+    }
+
+    fn send_query(&self, address: SocketAddr, method: &str, args: KrpcArgs) -> PendingQuery<'_> {
+        let (sender, receiver) = mpsc::channel();
+        let transaction_id = {
+            let mut pending = self.pending.lock().unwrap();
+            let transaction_id = loop {
+                let transaction_id = random_bytes(TRANSACTION_ID_LENGTH);
+                if !pending.contains_key(&transaction_id) {
+                    break transaction_id;
+                }
+            };
+            pending.insert(transaction_id.clone(), (address, sender));
+            transaction_id
+        };
+
+        let message = KrpcMessage {
+            t: ByteBuf::from(transaction_id.clone()),
+            y: String::from("q"),
+            q: Some(method.to_string()),
+            a: Some(args),
+            r: None,
+            e: None,
+        };
+        let _ = self
+            .socket
+            .send_to(&serde_bencode::to_bytes(&message).unwrap(), address);
+
+        PendingQuery {
+            node: self,
+            transaction_id,
+            receiver,
+        }
+    }
+
+    /// Takes the pending query a message answers, only if it came from the queried address
+    fn take_pending(
+        &self,
+        transaction_id: &[u8],
+        from: SocketAddr,
+    ) -> Option<Sender<KrpcResponse>> {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get(transaction_id) {
+            Some((address, _)) if *address == from => {
+                pending.remove(transaction_id).map(|(_, sender)| sender)
+            }
+            _ => None,
+        }
+    }
+
+    fn receive_loop(socket: UdpSocket, node: Weak<Self>) {
+        let mut buf = [0; 2048];
+        loop {
+            let received = socket.recv_from(&mut buf);
+            let Some(node) = node.upgrade() else {
+                return;
+            };
+
+            let (size, from) = match received {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                // e.g. ICMP port unreachable of a dead node on some platforms
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => {
+                    println!("DHT socket failed: {}", e);
+                    return;
+                }
+            };
+
+            let Ok(message) = serde_bencode::from_bytes::<KrpcMessage>(&buf[..size]) else {
+                continue;
+            };
+
+            match message.y.as_str() {
+                "q" => node.handle_query(message, from),
+                "r" => node.handle_response(message, from),
+                "e" => {
+                    // dropping the sender makes the waiting query fail right away
+                    node.take_pending(&message.t, from);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn handle_response(&self, message: KrpcMessage, from: SocketAddr) {
+        let Some(sender) = self.take_pending(&message.t, from) else {
+            return;
+        };
+        let Some(response) = message.r else {
+            return;
+        };
+
+        if let Ok(id) = response.id.as_slice().try_into() {
+            self.routing_table
+                .lock()
+                .unwrap()
+                .insert(NodeInfo { id, address: from });
+        }
+
+        let _ = sender.send(response);
+    }
+
+    fn handle_query(&self, message: KrpcMessage, from: SocketAddr) {
+        let (Some(method), Some(args)) = (message.q, message.a) else {
+            return;
+        };
+
+        if let Ok(id) = args.id.as_slice().try_into() {
+            self.routing_table
+                .lock()
+                .unwrap()
+                .insert(NodeInfo { id, address: from });
+        }
+
+        let mut response = KrpcResponse {
+            id: ByteBuf::from(self.id.to_vec()),
+            ..Default::default()
+        };
+
+        let error = match method.as_str() {
+            "ping" => None,
+            "find_node" => match args
+                .target
+                .and_then(|target| target.to_vec().try_into().ok())
+            {
+                Some(target) => {
+                    response.nodes = Some(ByteBuf::from(self.closest_compact_nodes(&target)));
+                    None
+                }
+                None => Some((203, "Missing target")),
+            },
+            "get_peers" => {
+                match args
+                    .info_hash
+                    .and_then(|info_hash| info_hash.to_vec().try_into().ok())
+                {
+                    Some(info_hash) => {
+                        response.token = Some(ByteBuf::from(self.token(&from)));
+                        let peers = self.stored_peers(&info_hash);
+                        if peers.is_empty() {
+                            response.nodes =
+                                Some(ByteBuf::from(self.closest_compact_nodes(&info_hash)));
+                        } else {
+                            response.values = Some(peers);
+                        }
+                        None
+                    }
+                    None => Some((203, "Missing info_hash")),
+                }
+            }
+            "announce_peer" => {
+                let info_hash: Option<NodeId> = args
+                    .info_hash
+                    .and_then(|info_hash| info_hash.to_vec().try_into().ok());
+                let valid_token = args
+                    .token
+                    .is_some_and(|token| self.valid_token(&token, &from));
+                let port = match args.implied_port {
+                    Some(implied_port) if implied_port != 0 => Some(from.port()),
+                    _ => args.port.and_then(|port| u16::try_from(port).ok()),
+                };
+
+                match (info_hash, valid_token, port) {
+                    (Some(info_hash), true, Some(port)) => {
+                        self.announced_peers
+                            .lock()
+                            .unwrap()
+                            .entry(info_hash)
+                            .or_default()
+                            .insert(SocketAddr::new(from.ip(), port), Instant::now());
+                        None
+                    }
+                    (_, false, _) => Some((203, "Bad token")),
+                    _ => Some((203, "Missing info_hash or port")),
+                }
+            }
+            _ => Some((204, "Method Unknown")),
+        };
+
+        let reply = match error {
+            None => KrpcMessage {
+                t: message.t,
+                y: String::from("r"),
+                q: None,
+                a: None,
+                r: Some(response),
+                e: None,
+            },
+            Some((code, text)) => KrpcMessage {
+                t: message.t,
+                y: String::from("e"),
+                q: None,
+                a: None,
+                r: None,
+                e: Some((code, text.to_string())),
+            },
+        };
+
+        let _ = self
+            .socket
+            .send_to(&serde_bencode::to_bytes(&reply).unwrap(), from);
+    }
+
+    fn closest_compact_nodes(&self, target: &NodeId) -> Vec<u8> {
+        let nodes = self.routing_table.lock().unwrap().closest(target, K);
+        encode_compact_nodes(&nodes)
+    }
+
+    fn stored_peers(&self, info_hash: &NodeId) -> Vec<ByteBuf> {
+        let mut announced_peers = self.announced_peers.lock().unwrap();
+        let Some(peers) = announced_peers.get_mut(info_hash) else {
+            return Vec::new();
+        };
+        peers.retain(|_, announced_at| announced_at.elapsed() < PEER_EXPIRY);
+
+        peers
+            .keys()
+            .filter_map(|peer| match peer {
+                SocketAddr::V4(peer) => {
+                    let mut compact = peer.ip().octets().to_vec();
+                    compact.extend_from_slice(&peer.port().to_be_bytes());
+                    Some(ByteBuf::from(compact))
+                }
+                SocketAddr::V6(_) => None,
+            })
+            .collect()
+    }
+
+    fn token(&self, address: &SocketAddr) -> Vec<u8> {
+        let mut secrets = self.token_secrets.lock().unwrap();
+        if secrets.rotated_at.elapsed() > TOKEN_ROTATION {
+            secrets.previous = std::mem::replace(&mut secrets.current, secure_random_bytes(16));
+            secrets.rotated_at = Instant::now();
+        }
+        token_for(address, &secrets.current)
+    }
+
+    fn valid_token(&self, token: &[u8], address: &SocketAddr) -> bool {
+        let secrets = self.token_secrets.lock().unwrap();
+        token == token_for(address, &secrets.current)
+            || token == token_for(address, &secrets.previous)
+    }
+}
+
+/// Query waiting for its response, forgotten once it is answered or dropped
+struct PendingQuery<'a> {
+    node: &'a DhtNode,
+    transaction_id: Vec<u8>,
+    receiver: Receiver<KrpcResponse>,
+}
+
+impl PendingQuery<'_> {
+    /// `None` when the node didn't answer within `QUERY_TIMEOUT` or answered with an error
+    fn wait(&self) -> Option<KrpcResponse> {
+        self.receiver.recv_timeout(QUERY_TIMEOUT).ok()
+    }
+}
+
+impl Drop for PendingQuery<'_> {
+    fn drop(&mut self) {
+        self.node
+            .pending
+            .lock()
+            .unwrap()
+            .remove(&self.transaction_id);
+    }
+}
+
+fn token_for(address: &SocketAddr, secret: &[u8]) -> Vec<u8> {
+    let mut input = address.ip().to_string().into_bytes();
+    input.extend_from_slice(secret);
+    sha1_it(&input)[..8].to_vec()
+}
+
+/// Compact node info is 20 byte id followed by compact IPv4 address and port
+fn parse_compact_nodes(nodes: &[u8]) -> Vec<NodeInfo> {
+    nodes
+        .chunks_exact(26)
+        .filter_map(|node| {
+            let address = parse_compact_peers(&node[20..], 6)?.pop()?;
+            Some(NodeInfo {
+                id: node[..20].try_into().unwrap(),
+                address,
+            })
+        })
+        .collect()
+}
+
+fn encode_compact_nodes(nodes: &[NodeInfo]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(nodes.len() * 26);
+    for node in nodes {
+        if let SocketAddr::V4(address) = node.address {
+            encoded.extend_from_slice(&node.id);
+            encoded.extend_from_slice(&address.ip().octets());
+            encoded.extend_from_slice(&address.port().to_be_bytes());
+        }
+    }
+    encoded
+}
+
+/// Looks up and announces a torrent every `REANNOUNCE_INTERVAL` until dropped,
+/// peers of every lookup are passed to `on_peers`
+pub struct DhtSession {
+    stop: Sender<()>,
+}
+
+impl DhtSession {
+    /// Bootstraps a node and runs the first lookup right away, `announce_port` as in `find_peers`
+    pub fn start(
+        config: DhtConfig,
+        info_hash: NodeId,
+        announce_port: Option<u16>,
+        mut on_peers: impl FnMut(Vec<SocketAddr>) + Send + 'static,
+    ) -> io::Result<Self> {
+        let node = DhtNode::start(config)?;
+        let (stop, stopped) = mpsc::channel();
+
+        thread::spawn(move || {
+            node.bootstrap();
+            loop {
+                let peers = match announce_port {
+                    Some(port) => node.announce_peer(&info_hash, port),
+                    None => node.get_peers(&info_hash).peers,
+                };
+                on_peers(peers);
+                if let Err(e) = node.save() {
+                    println!("Failed to save DHT state: {}", e);
+                }
+
+                // a dropped session disconnects the channel
+                if stopped.recv_timeout(REANNOUNCE_INTERVAL) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+            }
+        });
+
+        Ok(DhtSession { stop })
+    }
+}
+
+impl Drop for DhtSession {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+    }
+}
+
+/// Convenience for one-off lookups, starts a node, bootstraps it and asks for peers.
+/// With `announce_port` we are announced as a peer accepting connections on that port.
+pub fn find_peers(
+    config: DhtConfig,
+    info_hash: &NodeId,
+    announce_port: Option<u16>,
+) -> io::Result<Vec<SocketAddr>> {
+    let node = DhtNode::start(config)?;
+    node.bootstrap();
+    let peers = match announce_port {
+        Some(port) => node.announce_peer(info_hash, port),
+        None => node.get_peers(info_hash).peers,
+    };
+    node.save()?;
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_node(bootstrap: Option<SocketAddr>, state_path: Option<PathBuf>) -> Arc<DhtNode> {
+        let node = DhtNode::start(DhtConfig {
+            bind_address: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            bootstrap_nodes: bootstrap.iter().map(SocketAddr::to_string).collect(),
+            state_path,
+        })
+        .unwrap();
+        node.bootstrap();
+        node
+    }
+
+    /// Starts a node every other node bootstraps from, followed by `count` joining nodes
+    fn local_network(count: usize) -> Vec<Arc<DhtNode>> {
+        let first = local_node(None, None);
+        let first_address = first.local_addr().unwrap();
+        let mut nodes = vec![first];
+        nodes.extend((0..count).map(|_| local_node(Some(first_address), None)));
+        nodes
+    }
+
+    fn response(transaction_id: &[u8], id: &NodeId) -> Vec<u8> {
+        let message = KrpcMessage {
+            t: ByteBuf::from(transaction_id.to_vec()),
+            y: String::from("r"),
+            q: None,
+            a: None,
+            r: Some(KrpcResponse {
+                id: ByteBuf::from(id.to_vec()),
+                ..Default::default()
+            }),
+            e: None,
+        };
+        serde_bencode::to_bytes(&message).unwrap()
+    }
+
+    #[test]
+    fn bootstrapped_nodes_learn_each_other() {
+        let nodes = local_network(4);
+        for node in &nodes {
+            assert!(node.nodes_count() >= 1);
+        }
+        let first = &nodes[0];
+        assert_eq!(first.nodes_count(), 4);
+        assert_eq!(nodes[1].ping(first.local_addr().unwrap()), Some(first.id()));
+    }
+
+    #[test]
+    fn announced_peer_is_found_by_other_nodes() {
+        let nodes = local_network(5);
+        let info_hash: NodeId = random_bytes(20).try_into().unwrap();
+
+        assert!(nodes[1].get_peers(&info_hash).peers.is_empty());
+        nodes[1].announce_peer(&info_hash, 6881);
+
+        let lookup = nodes[4].get_peers(&info_hash);
+        assert_eq!(
+            lookup.peers,
+            vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 6881))]
+        );
+    }
+
+    #[test]
+    fn announce_needs_token_from_get_peers() {
+        let nodes = local_network(1);
+        let (storing, announcing) = (&nodes[0], &nodes[1]);
+        let address = storing.local_addr().unwrap();
+        let info_hash: NodeId = random_bytes(20).try_into().unwrap();
+        let announce_args = |token: Vec<u8>| KrpcArgs {
+            id: ByteBuf::from(announcing.id().to_vec()),
+            info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+            port: Some(6881),
+            token: Some(ByteBuf::from(token)),
+            ..Default::default()
+        };
+
+        let rejected = announcing.send_query(address, "announce_peer", announce_args(vec![0; 8]));
+        assert!(rejected.wait().is_none());
+        assert!(storing.stored_peers(&info_hash).is_empty());
+
+        let token = announcing
+            .send_query(
+                address,
+                "get_peers",
+                KrpcArgs {
+                    id: ByteBuf::from(announcing.id().to_vec()),
+                    info_hash: Some(ByteBuf::from(info_hash.to_vec())),
+                    ..Default::default()
+                },
+            )
+            .wait()
+            .and_then(|response| response.token)
+            .unwrap();
+        let accepted =
+            announcing.send_query(address, "announce_peer", announce_args(token.to_vec()));
+        assert!(accepted.wait().is_some());
+        assert_eq!(storing.stored_peers(&info_hash).len(), 1);
+
+        // tokens are bound to the address they were handed out to
+        let elsewhere = SocketAddr::from(([10, 0, 0, 1], 6881));
+        assert!(!storing.valid_token(&token, &elsewhere));
+    }
+
+    #[test]
+    fn saved_state_is_restored() {
+        let directory = tempfile::tempdir().unwrap();
+        let state_path = directory.path().join("dht.dat");
+        let nodes = local_network(3);
+
+        let node = local_node(
+            Some(nodes[0].local_addr().unwrap()),
+            Some(state_path.clone()),
+        );
+        node.save().unwrap();
+
+        let restarted = DhtNode::start(DhtConfig {
+            bind_address: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+            bootstrap_nodes: Vec::new(),
+            state_path: Some(state_path),
+        })
+        .unwrap();
+        assert_eq!(restarted.id(), node.id());
+        assert_eq!(restarted.nodes_count(), node.nodes_count());
+
+        // restored nodes are enough to join without bootstrap nodes
+        restarted.bootstrap();
+        assert!(restarted.nodes_count() >= 4);
+    }
+
+    #[test]
+    fn response_from_other_address_is_ignored() {
+        let node = local_node(None, None);
+        let queried = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let queried_id: NodeId = random_bytes(20).try_into().unwrap();
+
+        let query = node.send_query(
+            queried.local_addr().unwrap(),
+            "ping",
+            KrpcArgs {
+                id: ByteBuf::from(node.id().to_vec()),
+                ..Default::default()
+            },
+        );
+        let mut buf = [0; 2048];
+        let (size, from) = queried.recv_from(&mut buf).unwrap();
+        let request: KrpcMessage = serde_bencode::from_bytes(&buf[..size]).unwrap();
+        assert_eq!(request.t.len(), TRANSACTION_ID_LENGTH);
+
+        spoofer
+            .send_to(
+                &response(&request.t, &random_bytes(20).try_into().unwrap()),
+                from,
+            )
+            .unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(query.receiver.try_recv().is_err());
+
+        queried
+            .send_to(&response(&request.t, &queried_id), from)
+            .unwrap();
+        assert_eq!(query.wait().unwrap().id.as_slice(), queried_id);
+    }
+
+    #[test]
+    fn unanswered_query_is_forgotten() {
+        let node = local_node(None, None);
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        assert_eq!(node.ping(silent.local_addr().unwrap()), None);
+        assert!(node.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn dropped_node_releases_its_socket() {
+        let node = local_node(None, None);
+        let address = node.local_addr().unwrap();
+        drop(node);
+
+        thread::sleep(RECEIVE_POLL_INTERVAL * 2);
+        assert!(UdpSocket::bind(address).is_ok());
+    }
+
+    #[test]
+    fn session_announces_and_reports_peers() {
+        let nodes = local_network(4);
+        let info_hash: NodeId = random_bytes(20).try_into().unwrap();
+        nodes[1].announce_peer(&info_hash, 6882);
+
+        let (sender, found) = mpsc::channel();
+        let session = DhtSession::start(
+            DhtConfig {
+                bind_address: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+                bootstrap_nodes: vec![nodes[0].local_addr().unwrap().to_string()],
+                state_path: None,
+            },
+            info_hash,
+            Some(6881),
+            move |peers| {
+                let _ = sender.send(peers);
+            },
+        )
+        .unwrap();
+
+        let peers = found.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(peers, vec![SocketAddr::from((Ipv4Addr::LOCALHOST, 6882))]);
+        let mut announced = nodes[4].get_peers(&info_hash).peers;
+        announced.sort();
+        assert_eq!(
+            announced,
+            vec![
+                SocketAddr::from((Ipv4Addr::LOCALHOST, 6881)),
+                SocketAddr::from((Ipv4Addr::LOCALHOST, 6882))
+            ]
+        );
+
+        // no second lookup before `REANNOUNCE_INTERVAL`, dropping ends the session
+        drop(session);
+        assert!(found.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}
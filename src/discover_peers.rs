@@ -1,52 +1,555 @@
-use crate::bencode::decode_bencoded_value;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
 
-pub fn discover_peers(info_hash: &[u8], left: usize, tracker_url: &str) -> Vec<String> {
-    let info_hash_encoded: String = unsafe { String::from_utf8_unchecked(info_hash.to_vec()) };
-    let response = reqwest::blocking::Client::new()
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    bencode::decode_bencoded_value,
+    client_config::ClientConfig,
+    udp_tracker::{self, UdpTracker},
+};
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    #[error("tracker request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("tracker responded with invalid bencode")]
+    InvalidBencode,
+    #[error("tracker response is missing `{0}`")]
+    MissingField(&'static str),
+    #[error("tracker response has malformed `{0}`")]
+    MalformedField(&'static str),
+    #[error("tracker failure: {0}")]
+    Failure(String),
+    #[error("tracker io failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid tracker url {0}")]
+    InvalidUrl(String),
+    #[error("tracker responded with malformed packet")]
+    MalformedPacket,
+    #[error("tracker did not respond")]
+    Timeout,
+    #[error("tracker does not support scrape")]
+    ScrapeNotSupported,
+}
+
+#[derive(Debug)]
+pub struct AnnounceResponse {
+    /// how long we should wait before announcing again
+    pub interval: Duration,
+    /// we must not re-announce more often than this
+    pub min_interval: Option<Duration>,
+    /// number of seeders
+    pub complete: Option<u64>,
+    /// number of leechers
+    pub incomplete: Option<u64>,
+    /// has to be sent back on next announces if present
+    pub tracker_id: Option<String>,
+    pub warning_message: Option<String>,
+    pub peers: Vec<SocketAddr>,
+}
+
+/// Swarm statistics of a single torrent returned by scrape
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScrapeStats {
+    /// number of seeders
+    pub complete: u64,
+    /// number of times the torrent was downloaded
+    pub downloaded: u64,
+    /// number of leechers
+    pub incomplete: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
+
+pub struct AnnounceRequest<'a> {
+    pub info_hash: &'a [u8],
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+    pub tracker_id: Option<&'a str>,
+    pub client: &'a ClientConfig,
+}
+
+/// One-shot announce without any transfer statistics
+pub fn discover_peers(
+    client: &ClientConfig,
+    info_hash: &[u8],
+    left: usize,
+    tracker_url: &str,
+) -> Result<AnnounceResponse, TrackerError> {
+    announce(
+        tracker_url,
+        &AnnounceRequest {
+            client,
+            info_hash,
+            uploaded: 0,
+            downloaded: 0,
+            left: left as u64,
+            event: None,
+            tracker_id: None,
+        },
+    )
+}
+
+/// Announce somebody waits for, UDP trackers get `udp_tracker::DEFAULT_DEADLINE` to answer
+pub fn announce(
+    tracker_url: &str,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
+    if tracker_url.starts_with("udp://") {
+        return udp_tracker::announce(tracker_url, request);
+    }
+    http_announce(tracker_url, request)
+}
+
+/// Re-announce nobody waits for, UDP trackers get the full BEP 15 retransmission schedule
+pub fn announce_in_background(
+    tracker_url: &str,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
+    if tracker_url.starts_with("udp://") {
+        return udp_tracker::announce_in_background(tracker_url, request);
+    }
+    http_announce(tracker_url, request)
+}
+
+fn http_announce(
+    tracker_url: &str,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
+    let response = http_client(request.client)?
         .get(tracker_url)
-        .query(&[
-            ("info_hash", info_hash_encoded.as_str()),
-            ("peer_id", "00112233445566778899"),
-            ("port", "6881"),
-            ("uploaded", "0"),
-            ("downloaded", "0"),
-            ("left", &left.to_string()),
-            ("compact", "1"),
-        ])
-        .send()
-        .unwrap();
-    let body = response.bytes().unwrap();
-    let iterator = &mut body.iter().copied();
-    let value = decode_bencoded_value(iterator).unwrap();
-    let encoded_peers = value.as_object().unwrap()["peers"]
+        .query(&announce_query(request))
+        .send()?
+        .error_for_status()?;
+    let body = response.bytes()?;
+
+    parse_announce_response(&body)
+}
+
+/// Query parameters of an HTTP announce
+pub fn announce_query(request: &AnnounceRequest) -> Vec<(&'static str, String)> {
+    let client = request.client;
+    let info_hash_encoded: String =
+        unsafe { String::from_utf8_unchecked(request.info_hash.to_vec()) };
+    let peer_id_encoded: String = unsafe { String::from_utf8_unchecked(client.peer_id.to_vec()) };
+
+    let mut query = vec![
+        ("info_hash", info_hash_encoded),
+        ("peer_id", peer_id_encoded),
+        ("port", client.port.to_string()),
+        ("uploaded", request.uploaded.to_string()),
+        ("downloaded", request.downloaded.to_string()),
+        ("left", request.left.to_string()),
+        ("compact", String::from("1")),
+        ("key", format!("{:08x}", client.key)),
+    ];
+    if let Some(event) = request.event {
+        query.push(("event", event.as_str().to_string()));
+    }
+    if let Some(tracker_id) = request.tracker_id {
+        query.push(("trackerid", tracker_id.to_string()));
+    }
+    if let Some(numwant) = client.numwant {
+        query.push(("numwant", numwant.to_string()));
+    }
+    if let Some(ip) = client.ip {
+        query.push(("ip", ip.to_string()));
+    }
+
+    query
+}
+
+/// Info hashes sent in a single HTTP scrape request, keeps the url reasonably short
+pub const HTTP_SCRAPE_BATCH_SIZE: usize = 50;
+
+/// Info hashes fitting into a single UDP scrape packet (BEP 15)
+pub const UDP_SCRAPE_BATCH_SIZE: usize = 74;
+
+/// Asks tracker for swarm statistics of many torrents at once, without announcing.
+/// Torrents unknown to the tracker are missing in the result.
+pub fn scrape(
+    client: &ClientConfig,
+    tracker_url: &str,
+    info_hashes: &[&[u8]],
+) -> Result<HashMap<Vec<u8>, ScrapeStats>, TrackerError> {
+    let mut stats = HashMap::new();
+
+    if tracker_url.starts_with("udp://") {
+        let tracker = UdpTracker::new(tracker_url)?;
+        for batch in info_hashes.chunks(UDP_SCRAPE_BATCH_SIZE) {
+            let batch_stats = tracker.scrape(batch)?;
+            stats.extend(batch.iter().map(|hash| hash.to_vec()).zip(batch_stats));
+        }
+        return Ok(stats);
+    }
+
+    let scrape_url = scrape_url(tracker_url)?;
+    let client = http_client(client)?;
+
+    for batch in info_hashes.chunks(HTTP_SCRAPE_BATCH_SIZE) {
+        let query: Vec<_> = batch
+            .iter()
+            .map(|info_hash| {
+                let info_hash_encoded: String =
+                    unsafe { String::from_utf8_unchecked(info_hash.to_vec()) };
+                ("info_hash", info_hash_encoded)
+            })
+            .collect();
+
+        let body = client
+            .get(&scrape_url)
+            .query(&query)
+            .send()?
+            .error_for_status()?
+            .bytes()?;
+
+        stats.extend(parse_scrape_response(&body)?);
+    }
+
+    Ok(stats)
+}
+
+fn http_client(client: &ClientConfig) -> Result<reqwest::blocking::Client, TrackerError> {
+    Ok(reqwest::blocking::Client::builder()
+        .user_agent(client.user_agent.as_str())
+        .build()?)
+}
+
+/// Scrape url is derived from announce url by replacing the last `announce` path segment (BEP 48)
+pub fn scrape_url(tracker_url: &str) -> Result<String, TrackerError> {
+    let path_end = tracker_url.find('?').unwrap_or(tracker_url.len());
+    let (path, query) = tracker_url.split_at(path_end);
+    let (base, last_segment) = path
+        .rsplit_once('/')
+        .ok_or_else(|| TrackerError::InvalidUrl(tracker_url.to_string()))?;
+
+    match last_segment.strip_prefix("announce") {
+        Some(suffix) => Ok(format!("{}/scrape{}{}", base, suffix, query)),
+        None => Err(TrackerError::ScrapeNotSupported),
+    }
+}
+
+fn parse_scrape_response(body: &[u8]) -> Result<HashMap<Vec<u8>, ScrapeStats>, TrackerError> {
+    let value = decode_bencoded_value(&mut body.iter().copied())
+        .map_err(|_| TrackerError::InvalidBencode)?;
+    let dict = value.as_object().ok_or(TrackerError::InvalidBencode)?;
+
+    if let Some(failure_reason) = dict.get("failure reason") {
+        let failure_reason = failure_reason
+            .as_str()
+            .ok_or(TrackerError::MalformedField("failure reason"))?;
+        return Err(TrackerError::Failure(failure_reason.to_string()));
+    }
+
+    let files = dict
+        .get("files")
+        .ok_or(TrackerError::MissingField("files"))?
+        .as_object()
+        .ok_or(TrackerError::MalformedField("files"))?;
+
+    files
+        .iter()
+        .map(|(info_hash, file)| {
+            let stats = ScrapeStats {
+                complete: optional_u64(file.get("complete"), "complete")?.unwrap_or(0),
+                downloaded: optional_u64(file.get("downloaded"), "downloaded")?.unwrap_or(0),
+                incomplete: optional_u64(file.get("incomplete"), "incomplete")?.unwrap_or(0),
+            };
+            Ok((info_hash.as_bytes().to_vec(), stats))
+        })
+        .collect()
+}
+
+pub fn parse_announce_response(body: &[u8]) -> Result<AnnounceResponse, TrackerError> {
+    let value = decode_bencoded_value(&mut body.iter().copied())
+        .map_err(|_| TrackerError::InvalidBencode)?;
+    let dict = value.as_object().ok_or(TrackerError::InvalidBencode)?;
+
+    if let Some(failure_reason) = dict.get("failure reason") {
+        let failure_reason = failure_reason
+            .as_str()
+            .ok_or(TrackerError::MalformedField("failure reason"))?;
+        return Err(TrackerError::Failure(failure_reason.to_string()));
+    }
+
+    let interval = dict
+        .get("interval")
+        .ok_or(TrackerError::MissingField("interval"))?
+        .as_u64()
+        .ok_or(TrackerError::MalformedField("interval"))?;
+
+    let mut peers = match dict.get("peers") {
+        // compact form, 6 bytes per peer (BEP 23)
+        Some(Value::String(encoded_peers)) => parse_compact_peers(encoded_peers.as_bytes(), 6)
+            .ok_or(TrackerError::MalformedField("peers"))?,
+        // original form, list of dictionaries with `ip`, `port` and `peer id`
+        Some(Value::Array(peers)) => peers
+            .iter()
+            .filter_map(|peer| parse_dictionary_peer(peer).transpose())
+            .collect::<Result<_, _>>()?,
+        Some(_) => return Err(TrackerError::MalformedField("peers")),
+        None => Vec::new(),
+    };
+
+    // compact IPv6 peers, 18 bytes per peer (BEP 7)
+    match dict.get("peers6") {
+        Some(Value::String(encoded_peers)) => peers.extend(
+            parse_compact_peers(encoded_peers.as_bytes(), 18)
+                .ok_or(TrackerError::MalformedField("peers6"))?,
+        ),
+        Some(_) => return Err(TrackerError::MalformedField("peers6")),
+        None => {}
+    }
+
+    if !dict.contains_key("peers") && !dict.contains_key("peers6") {
+        return Err(TrackerError::MissingField("peers"));
+    }
+
+    Ok(AnnounceResponse {
+        interval: Duration::from_secs(interval),
+        min_interval: optional_u64(dict.get("min interval"), "min interval")?
+            .map(Duration::from_secs),
+        complete: optional_u64(dict.get("complete"), "complete")?,
+        incomplete: optional_u64(dict.get("incomplete"), "incomplete")?,
+        tracker_id: optional_string(dict.get("tracker id"), "tracker id")?,
+        warning_message: optional_string(dict.get("warning message"), "warning message")?,
+        peers,
+    })
+}
+
+fn optional_u64(value: Option<&Value>, field: &'static str) -> Result<Option<u64>, TrackerError> {
+    value
+        .map(|value| value.as_u64().ok_or(TrackerError::MalformedField(field)))
+        .transpose()
+}
+
+fn optional_string(
+    value: Option<&Value>,
+    field: &'static str,
+) -> Result<Option<String>, TrackerError> {
+    value
+        .map(|value| {
+            value
+                .as_str()
+                .map(String::from)
+                .ok_or(TrackerError::MalformedField(field))
+        })
+        .transpose()
+}
+
+/// Parses concatenated compact peers, `peer_size` is 6 for IPv4 and 18 for IPv6
+pub fn parse_compact_peers(encoded_peers: &[u8], peer_size: usize) -> Option<Vec<SocketAddr>> {
+    if !encoded_peers.len().is_multiple_of(peer_size) {
+        return None;
+    }
+
+    Some(
+        encoded_peers
+            .chunks(peer_size)
+            .map(parse_compact_peer)
+            .collect(),
+    )
+}
+
+/// Encodes peers in compact form, returns IPv4 and IPv6 peers separately
+pub fn encode_compact_peers(peers: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let mut peers4 = Vec::new();
+    let mut peers6 = Vec::new();
+
+    for peer in peers {
+        match peer {
+            SocketAddr::V4(peer) => {
+                peers4.extend_from_slice(&peer.ip().octets());
+                peers4.extend_from_slice(&peer.port().to_be_bytes());
+            }
+            SocketAddr::V6(peer) => {
+                peers6.extend_from_slice(&peer.ip().octets());
+                peers6.extend_from_slice(&peer.port().to_be_bytes());
+            }
+        }
+    }
+
+    (peers4, peers6)
+}
+
+/// Parses peer in compact form, 4 byte IPv4 or 16 byte IPv6 address followed by 2 byte port
+pub fn parse_compact_peer(encoded_peer: &[u8]) -> SocketAddr {
+    let (ip, port) = encoded_peer.split_at(encoded_peer.len() - 2);
+    let port = u16::from_be_bytes([port[0], port[1]]);
+
+    let ip: IpAddr = match ip.len() {
+        4 => <[u8; 4]>::try_from(ip).unwrap().into(),
+        16 => <[u8; 16]>::try_from(ip).unwrap().into(),
+        _ => panic!("compact peer has to be 6 or 18 bytes long"),
+    };
+
+    SocketAddr::new(ip, port)
+}
+
+/// `ip` can be IPv4, IPv6 or a DNS name, peers with unresolvable names are skipped
+fn parse_dictionary_peer(peer: &Value) -> Result<Option<SocketAddr>, TrackerError> {
+    let ip = peer["ip"]
         .as_str()
-        .expect("peers can be parse to string")
-        .as_bytes()
-        .chunks(6);
-    let mut peers = Vec::new();
-
-    for encoded_peer in encoded_peers {
-        println!("Encoded Peer: {:?}", encoded_peer);
-        let peer_address = parse_peer_address(encoded_peer);
-        peers.push(peer_address);
-    }
-
-    return peers;
-}
-
-fn parse_peer_address(encoded_peer: &[u8]) -> String {
-    let mut iterator = encoded_peer.iter();
-    let first_octet = iterator.next().unwrap();
-    let second_octet = iterator.next().unwrap();
-    let third_octet = iterator.next().unwrap();
-    let fourth_octet = iterator.next().unwrap();
-
-    let first_byte_port = *iterator.next().unwrap() as u16;
-    let second_byte_port = *iterator.next().unwrap() as u16;
-    let port = (first_byte_port << 8) | second_byte_port;
-    let peer_address = format!(
-        "{}.{}.{}.{}:{}",
-        first_octet, second_octet, third_octet, fourth_octet, port
-    );
-    peer_address
+        .ok_or(TrackerError::MalformedField("ip"))?;
+    let port = peer["port"]
+        .as_u64()
+        .and_then(|port| u16::try_from(port).ok())
+        .ok_or(TrackerError::MalformedField("port"))?;
+
+    match ip.parse::<IpAddr>() {
+        Ok(ip) => Ok(Some(SocketAddr::new(ip, port))),
+        Err(_) => Ok((ip, port)
+            .to_socket_addrs()
+            .ok()
+            .and_then(|mut addresses| addresses.next())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(fields: &[&[u8]]) -> Vec<u8> {
+        [&b"d"[..], &fields.concat(), b"e"].concat()
+    }
+
+    #[test]
+    fn compact_peers() {
+        let body = response(&[
+            b"8:intervali1800e",
+            b"5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x00\x50",
+            b"6:peers618:\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x1a\xe2",
+        ]);
+
+        let response = parse_announce_response(&body).unwrap();
+        assert_eq!(response.interval, Duration::from_secs(1800));
+        assert_eq!(
+            response.peers,
+            [
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "10.0.0.2:80".parse().unwrap(),
+                "[::1]:6882".parse().unwrap(),
+            ]
+        );
+        assert_eq!(response.min_interval, None);
+        assert_eq!(response.tracker_id, None);
+    }
+
+    #[test]
+    fn dictionary_peers() {
+        let body = response(&[
+            b"8:intervali60e",
+            b"5:peersl",
+            b"d2:ip9:127.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881ee",
+            b"d2:ip3:::14:porti6882ee",
+            b"e",
+        ]);
+
+        assert_eq!(
+            parse_announce_response(&body).unwrap().peers,
+            [
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "[::1]:6882".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn optional_fields() {
+        let body = response(&[
+            b"8:completei5e",
+            b"10:incompletei3e",
+            b"8:intervali1800e",
+            b"12:min intervali900e",
+            b"5:peers0:",
+            b"10:tracker id3:abc",
+            b"15:warning message4:slow",
+        ]);
+
+        let response = parse_announce_response(&body).unwrap();
+        assert_eq!(response.min_interval, Some(Duration::from_secs(900)));
+        assert_eq!(response.complete, Some(5));
+        assert_eq!(response.incomplete, Some(3));
+        assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+        assert_eq!(response.warning_message.as_deref(), Some("slow"));
+        assert!(response.peers.is_empty());
+    }
+
+    #[test]
+    fn invalid_responses() {
+        assert!(matches!(
+            parse_announce_response(&response(&[b"14:failure reason9:not found"])),
+            Err(TrackerError::Failure(reason)) if reason == "not found"
+        ));
+        assert!(matches!(
+            parse_announce_response(&response(&[b"5:peers0:"])),
+            Err(TrackerError::MissingField("interval"))
+        ));
+        assert!(matches!(
+            parse_announce_response(&response(&[b"8:intervali60e"])),
+            Err(TrackerError::MissingField("peers"))
+        ));
+        assert!(matches!(
+            parse_announce_response(&response(&[b"8:intervali60e", b"5:peers5:aaaaa"])),
+            Err(TrackerError::MalformedField("peers"))
+        ));
+        assert!(matches!(
+            parse_announce_response(&response(&[b"8:intervali60e", b"6:peers66:aaaaaa"])),
+            Err(TrackerError::MalformedField("peers6"))
+        ));
+        assert!(matches!(
+            parse_announce_response(b"garbage"),
+            Err(TrackerError::InvalidBencode)
+        ));
+    }
+
+    #[test]
+    fn scrape_url_replaces_announce() {
+        for (tracker_url, expected) in [
+            ("http://a/announce", "http://a/scrape"),
+            ("http://a/x/announce", "http://a/x/scrape"),
+            ("http://a/announce.php", "http://a/scrape.php"),
+            ("http://a/x/announce.php", "http://a/x/scrape.php"),
+            ("http://a/announce?x2%0644", "http://a/scrape?x2%0644"),
+        ] {
+            assert_eq!(scrape_url(tracker_url).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn scrape_is_not_supported_without_announce() {
+        for tracker_url in [
+            "http://a/a",
+            "http://a/announce/x",
+            "http://a/x%064announce",
+            "http://a/x/Announce",
+        ] {
+            assert!(matches!(
+                scrape_url(tracker_url),
+                Err(TrackerError::ScrapeNotSupported)
+            ));
+        }
+    }
 }
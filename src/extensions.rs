@@ -0,0 +1,412 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    client_config::ClientConfig,
+    peer_connection::{Message, PeerConnection, PeerError},
+    upload::MAX_PENDING_REQUESTS,
+};
+
+/// Extended message id of the extension handshake, everything else is negotiated (BEP 10)
+pub const EXTENSION_HANDSHAKE_ID: u8 = 0;
+
+/// Extension handshake dictionary, keys we don't know are ignored
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ExtensionHandshake {
+    /// extension names and ids their messages are sent to, 0 disables an extension
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    /// client name and version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+    /// port the sender accepts connections on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<i64>,
+    /// outstanding requests the sender queues
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+    /// receiver's ip as seen by the sender, 4 or 16 bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub yourip: Option<ByteBuf>,
+    /// size of the info dictionary (BEP 9)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+}
+
+impl ExtensionHandshake {
+    /// Id the sender wants to receive messages of the extension under
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m
+            .get(name)
+            .and_then(|id| u8::try_from(*id).ok())
+            .filter(|id| *id != EXTENSION_HANDSHAKE_ID)
+    }
+}
+
+/// Extension living on top of the extension protocol, e.g. ut_metadata or ut_pex
+pub trait Extension {
+    /// key of the extension in the `m` dictionary
+    fn name(&self) -> &'static str;
+
+    /// Adds extension specific keys like `metadata_size` to our handshake
+    fn extend_handshake(&self, _handshake: &mut ExtensionHandshake) {}
+
+    /// Peer's handshake arrived, `peer_extension_id` is `None` when it doesn't support us
+    fn on_handshake(
+        &mut self,
+        _connection: &mut PeerConnection,
+        _handshake: &ExtensionHandshake,
+        _peer_extension_id: Option<u8>,
+    ) -> Result<(), PeerError> {
+        Ok(())
+    }
+
+    /// Message the peer sent to the id we advertised for this extension
+    fn on_message(
+        &mut self,
+        connection: &mut PeerConnection,
+        payload: &[u8],
+    ) -> Result<(), PeerError>;
+
+    /// Called before every read, lets extensions send periodic messages
+    fn poll(&mut self, _connection: &mut PeerConnection) -> Result<(), PeerError> {
+        Ok(())
+    }
+}
+
+/// What `ExtensionRegistry::handle_message` did with a message
+#[derive(Debug, PartialEq)]
+pub enum Dispatched<'m> {
+    /// extension handshake or message of a registered extension
+    Handled,
+    /// message of an extension registered with `advertise`, the caller handles it
+    Advertised { id: u8, payload: &'m [u8] },
+    /// anything else, including extended messages to ids we never advertised
+    Unhandled,
+}
+
+/// Extensions of a single connection, ids we advertise follow registration order
+#[derive(Default)]
+pub struct ExtensionRegistry<'a> {
+    /// advertised extensions are the ones without a handler
+    extensions: Vec<(&'static str, Option<Box<dyn Extension + 'a>>)>,
+    peer_handshake: Option<ExtensionHandshake>,
+}
+
+impl<'a> ExtensionRegistry<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns id the peer sends messages of the extension to
+    pub fn register(&mut self, extension: impl Extension + 'a) -> u8 {
+        self.push(extension.name(), Some(Box::new(extension)))
+    }
+
+    /// Advertises an extension whose messages the caller handles itself,
+    /// they come back from `handle_message` as `Dispatched::Advertised`
+    pub fn advertise(&mut self, name: &'static str) -> u8 {
+        self.push(name, None)
+    }
+
+    fn push(&mut self, name: &'static str, extension: Option<Box<dyn Extension + 'a>>) -> u8 {
+        assert!(
+            self.extensions.len() < u8::MAX as usize,
+            "Too many extensions"
+        );
+        self.extensions.push((name, extension));
+        self.extensions.len() as u8
+    }
+
+    fn handlers(&mut self) -> impl Iterator<Item = &mut Box<dyn Extension + 'a>> {
+        self.extensions
+            .iter_mut()
+            .filter_map(|(_, extension)| extension.as_mut())
+    }
+
+    /// Advertises registered extensions, must be called only when peer supports
+    /// the extension protocol
+    pub fn send_handshake(
+        &self,
+        connection: &mut PeerConnection,
+        client: &ClientConfig,
+    ) -> Result<(), PeerError> {
+        let mut handshake = ExtensionHandshake {
+            m: self
+                .extensions
+                .iter()
+                .zip(1..)
+                .map(|((name, _), id)| (name.to_string(), id))
+                .collect(),
+            v: Some(ByteBuf::from(client.user_agent.as_bytes())),
+            p: Some(client.port.into()),
+            reqq: Some(MAX_PENDING_REQUESTS as i64),
+            yourip: connection
+                .tcp_stream
+                .peer_addr()
+                .ok()
+                .map(|peer| match peer.ip() {
+                    IpAddr::V4(ip) => ByteBuf::from(ip.octets()),
+                    IpAddr::V6(ip) => ByteBuf::from(ip.octets()),
+                }),
+            metadata_size: None,
+        };
+        for (_, extension) in &self.extensions {
+            if let Some(extension) = extension {
+                extension.extend_handshake(&mut handshake);
+            }
+        }
+
+        connection.send_message(&Message::Extended {
+            id: EXTENSION_HANDSHAKE_ID,
+            payload: serde_bencode::to_bytes(&handshake).unwrap(),
+        })
+    }
+
+    /// Handshake of the peer once it arrived
+    pub fn peer_handshake(&self) -> Option<&ExtensionHandshake> {
+        self.peer_handshake.as_ref()
+    }
+
+    /// Dispatches extended messages to their extensions
+    pub fn handle_message<'m>(
+        &mut self,
+        connection: &mut PeerConnection,
+        message: &'m Message,
+    ) -> Result<Dispatched<'m>, PeerError> {
+        let Message::Extended { id, payload } = message else {
+            return Ok(Dispatched::Unhandled);
+        };
+
+        if *id == EXTENSION_HANDSHAKE_ID {
+            // peers may send the handshake again, e.g. to disable an extension
+            let handshake: ExtensionHandshake = serde_bencode::from_bytes(payload)
+                .map_err(|e| PeerError::ProtocolViolation(format!("extension handshake: {}", e)))?;
+            for (name, extension) in &mut self.extensions {
+                if let Some(extension) = extension {
+                    let peer_extension_id = handshake.extension_id(name);
+                    extension.on_handshake(connection, &handshake, peer_extension_id)?;
+                }
+            }
+            self.peer_handshake = Some(handshake);
+            return Ok(Dispatched::Handled);
+        }
+
+        match self.extensions.get_mut(*id as usize - 1) {
+            Some((_, Some(extension))) => {
+                extension.on_message(connection, payload)?;
+                Ok(Dispatched::Handled)
+            }
+            Some((_, None)) => Ok(Dispatched::Advertised { id: *id, payload }),
+            None => Ok(Dispatched::Unhandled),
+        }
+    }
+
+    pub fn poll(&mut self, connection: &mut PeerConnection) -> Result<(), PeerError> {
+        for extension in self.handlers() {
+            extension.poll(connection)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{Ipv4Addr, TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+    use crate::{
+        mse::EncryptionPolicy,
+        peer_connection::{message_length, Handshake, PeerTimeouts, HANDSHAKE_LENGTH},
+    };
+
+    const INFO_HASH: [u8; 20] = *b"extension-registry-t";
+
+    /// Connection to a local stand-in peer, with the peer's end of it
+    fn loopback() -> (PeerConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let remote = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut handshake = [0; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).unwrap();
+            stream
+                .write_all(&Handshake::new(&INFO_HASH, &[2; 20], true).encode())
+                .unwrap();
+            stream
+        });
+
+        let connection = PeerConnection::handshake(
+            &address,
+            &INFO_HASH,
+            &[1; 20],
+            true,
+            PeerTimeouts::default(),
+            EncryptionPolicy::Disable,
+        )
+        .unwrap();
+        (connection, remote.join().unwrap())
+    }
+
+    fn read_message(stream: &mut TcpStream) -> Message {
+        let mut prefix = [0; 4];
+        stream.read_exact(&mut prefix).unwrap();
+        let mut frame = vec![0; message_length(prefix).unwrap()];
+        stream.read_exact(&mut frame).unwrap();
+        Message::decode(&frame).unwrap()
+    }
+
+    /// Remembers what the registry hands to it
+    struct Recorder<'r> {
+        name: &'static str,
+        peer_extension_ids: &'r mut Vec<Option<u8>>,
+        payloads: &'r mut Vec<Vec<u8>>,
+    }
+
+    impl Extension for Recorder<'_> {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn extend_handshake(&self, handshake: &mut ExtensionHandshake) {
+            handshake.metadata_size = Some(1234);
+        }
+
+        fn on_handshake(
+            &mut self,
+            _connection: &mut PeerConnection,
+            _handshake: &ExtensionHandshake,
+            peer_extension_id: Option<u8>,
+        ) -> Result<(), PeerError> {
+            self.peer_extension_ids.push(peer_extension_id);
+            Ok(())
+        }
+
+        fn on_message(
+            &mut self,
+            _connection: &mut PeerConnection,
+            payload: &[u8],
+        ) -> Result<(), PeerError> {
+            self.payloads.push(payload.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn handshake_advertises_extensions_and_client() {
+        let (mut connection, mut remote) = loopback();
+        let client = ClientConfig {
+            port: 51413,
+            ..ClientConfig::default()
+        };
+        let (mut peer_extension_ids, mut payloads) = (Vec::new(), Vec::new());
+        let mut registry = ExtensionRegistry::new();
+        registry.advertise("ut_pex");
+        registry.register(Recorder {
+            name: "ut_metadata",
+            peer_extension_ids: &mut peer_extension_ids,
+            payloads: &mut payloads,
+        });
+
+        registry.send_handshake(&mut connection, &client).unwrap();
+
+        let Message::Extended { id, payload } = read_message(&mut remote) else {
+            panic!("Expected an extended message");
+        };
+        assert_eq!(id, EXTENSION_HANDSHAKE_ID);
+        let mut expected = format!(
+            "d1:md11:ut_metadatai2e6:ut_pexi1ee13:metadata_sizei1234e1:pi51413e4:reqqi{}e1:v{}:{}6:yourip4:",
+            MAX_PENDING_REQUESTS,
+            client.user_agent.len(),
+            client.user_agent
+        )
+        .into_bytes();
+        expected.extend_from_slice(&[127, 0, 0, 1, b'e']);
+        assert_eq!(payload, expected);
+
+        let handshake: ExtensionHandshake = serde_bencode::from_bytes(&payload).unwrap();
+        assert_eq!(handshake.extension_id("ut_pex"), Some(1));
+        assert_eq!(handshake.extension_id("ut_metadata"), Some(2));
+        assert_eq!(
+            handshake.v.unwrap().as_slice(),
+            client.user_agent.as_bytes()
+        );
+        assert_eq!(handshake.p, Some(51413));
+        assert_eq!(handshake.reqq, Some(MAX_PENDING_REQUESTS as i64));
+        assert_eq!(
+            handshake.yourip.unwrap().as_slice(),
+            Ipv4Addr::LOCALHOST.octets()
+        );
+        assert_eq!(handshake.metadata_size, Some(1234));
+    }
+
+    #[test]
+    fn messages_are_dispatched_by_negotiated_id() {
+        let (mut connection, _remote) = loopback();
+        let (mut peer_extension_ids, mut payloads) = (Vec::new(), Vec::new());
+        let mut registry = ExtensionRegistry::new();
+        assert_eq!(
+            registry.register(Recorder {
+                name: "ut_metadata",
+                peer_extension_ids: &mut peer_extension_ids,
+                payloads: &mut payloads,
+            }),
+            1
+        );
+        assert_eq!(registry.advertise("ut_pex"), 2);
+
+        // the peer picks its own ids for the same extensions
+        let peer_handshake = ExtensionHandshake {
+            m: BTreeMap::from([
+                (String::from("ut_metadata"), 3),
+                (String::from("ut_pex"), 0),
+            ]),
+            ..Default::default()
+        };
+        let handshake = Message::Extended {
+            id: EXTENSION_HANDSHAKE_ID,
+            payload: serde_bencode::to_bytes(&peer_handshake).unwrap(),
+        };
+        let metadata = Message::Extended {
+            id: 1,
+            payload: vec![1, 2],
+        };
+        let pex = Message::Extended {
+            id: 2,
+            payload: vec![3],
+        };
+        let unknown = Message::Extended {
+            id: 3,
+            payload: vec![4],
+        };
+
+        let mut dispatch = |message| registry.handle_message(&mut connection, message).unwrap();
+        assert_eq!(dispatch(&handshake), Dispatched::Handled);
+        assert_eq!(dispatch(&metadata), Dispatched::Handled);
+        assert_eq!(
+            dispatch(&pex),
+            Dispatched::Advertised {
+                id: 2,
+                payload: &[3]
+            }
+        );
+        assert_eq!(dispatch(&unknown), Dispatched::Unhandled);
+        assert_eq!(dispatch(&Message::Unchoke), Dispatched::Unhandled);
+        assert_eq!(
+            registry
+                .peer_handshake()
+                .and_then(|handshake| handshake.extension_id("ut_metadata")),
+            Some(3)
+        );
+        drop(registry);
+
+        assert_eq!(peer_extension_ids, vec![Some(3)]);
+        assert_eq!(payloads, vec![vec![1, 2]]);
+    }
+}
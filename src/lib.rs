@@ -1,15 +1,68 @@
+use std::{
+    collections::hash_map::RandomState,
+    fs::File,
+    hash::{BuildHasher, Hasher},
+    io::Read,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
 use sha1::{Digest, Sha1};
 
+pub mod async_peer_connection;
+pub mod async_tracker;
 pub mod bencode;
+pub mod choker;
+pub mod client_config;
+pub mod dht;
 pub mod discover_peers;
+pub mod extensions;
+pub mod local_discovery;
 pub mod magnet_link;
 pub mod meta_info_file;
+pub mod mse;
 pub mod peer_connection;
+pub mod peer_pool;
+pub mod peer_server;
+pub mod piece_store;
 pub mod pieces;
+pub mod tracker_server;
+pub mod tracker_session;
+pub mod udp_tracker;
+pub mod upload;
+pub mod ut_metadata;
+pub mod ut_pex;
 
 pub fn sha1_it(bytes: &Vec<u8>) -> Vec<u8> {
     let mut hasher = Sha1::new();
-    hasher.update(&bytes);
+    hasher.update(bytes);
     let hash = hasher.finalize();
     hash.to_vec()
 }
+
+/// Random bytes derived from OS seeded SipHash keys, fine for ids and nonces,
+/// predictable enough that secrets have to come from `secure_random_bytes`
+pub fn random_bytes(len: usize) -> Vec<u8> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut bytes = Vec::with_capacity(len + 8);
+    while bytes.len() < len {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        bytes.extend_from_slice(&hasher.finish().to_le_bytes());
+    }
+    bytes.truncate(len);
+    bytes
+}
+
+pub fn random_u32() -> u32 {
+    u32::from_le_bytes(random_bytes(4).try_into().unwrap())
+}
+
+/// Bytes of the OS CSPRNG, for secrets like keys and token secrets
+pub fn secure_random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0; len];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut bytes))
+        .expect("Failed to read /dev/urandom");
+    bytes
+}
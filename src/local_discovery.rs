@@ -0,0 +1,336 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    peer_pool::{PeerPool, PeerSource},
+    random_bytes,
+};
+
+pub const LSD_PORT: u16 = 6771;
+
+pub const LSD_IPV4_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+
+pub const LSD_IPV6_GROUP: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+/// Every torrent is announced this often (BEP 14)
+pub const LSD_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Torrent must not be announced more often than this
+pub const LSD_MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Info hashes in a single announce, keeps the datagram well under a typical MTU
+const MAX_HASHES_PER_ANNOUNCE: usize = 20;
+
+struct LsdTorrent {
+    peer_pool: Arc<PeerPool>,
+    last_announce: Option<Instant>,
+}
+
+/// Local Service Discovery (BEP 14), announces our torrents to the LAN over multicast
+/// and adds peers announcing the same torrents to their pools
+pub struct LocalDiscovery {
+    /// port we accept peer connections on
+    port: u16,
+    /// identifies our own announces looped back to us
+    cookie: String,
+    torrents: Mutex<HashMap<Vec<u8>, LsdTorrent>>,
+    socket4: UdpSocket,
+    socket6: Option<UdpSocket>,
+}
+
+impl LocalDiscovery {
+    /// Joins both multicast groups and starts announcing. Only one process per machine
+    /// can listen on the LSD port, announcing works even when listening doesn't.
+    pub fn start(port: u16) -> io::Result<Arc<Self>> {
+        let discovery = Arc::new(LocalDiscovery {
+            port,
+            cookie: hex::encode(random_bytes(8)),
+            torrents: Mutex::new(HashMap::new()),
+            socket4: UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?,
+            socket6: UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok(),
+        });
+
+        match listen4() {
+            Ok(socket) => discovery.receive(socket),
+            Err(e) => println!("LSD failed to listen on IPv4: {}", e),
+        }
+        match listen6() {
+            Ok(socket) => discovery.receive(socket),
+            Err(e) => println!("LSD failed to listen on IPv6: {}", e),
+        }
+
+        let announcer = discovery.clone();
+        thread::spawn(move || loop {
+            thread::sleep(LSD_MIN_ANNOUNCE_INTERVAL);
+            announcer.announce_due();
+        });
+
+        Ok(discovery)
+    }
+
+    /// Starts announcing the torrent, local peers announcing it are added to `peer_pool`
+    pub fn add_torrent(&self, info_hash: &[u8], peer_pool: Arc<PeerPool>) {
+        self.torrents.lock().unwrap().insert(
+            info_hash.to_vec(),
+            LsdTorrent {
+                peer_pool,
+                last_announce: None,
+            },
+        );
+        self.announce_due();
+    }
+
+    pub fn remove_torrent(&self, info_hash: &[u8]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    fn announce_due(&self) {
+        let info_hashes: Vec<_> = {
+            let mut torrents = self.torrents.lock().unwrap();
+            torrents
+                .iter_mut()
+                .filter(|(_, torrent)| {
+                    torrent.last_announce.is_none_or(|last_announce| {
+                        last_announce.elapsed() >= LSD_ANNOUNCE_INTERVAL
+                    })
+                })
+                .map(|(info_hash, torrent)| {
+                    torrent.last_announce = Some(Instant::now());
+                    info_hash.clone()
+                })
+                .collect()
+        };
+
+        for info_hashes in info_hashes.chunks(MAX_HASHES_PER_ANNOUNCE) {
+            let group4 = SocketAddrV4::new(LSD_IPV4_GROUP, LSD_PORT);
+            let announce = self.announce_message(&group4.to_string(), info_hashes);
+            if let Err(e) = self.socket4.send_to(&announce, group4) {
+                println!("LSD failed to announce on IPv4: {}", e);
+            }
+
+            // hosts without IPv6 routes are common, so failures are not worth reporting
+            if let Some(socket6) = &self.socket6 {
+                let group6 = SocketAddrV6::new(LSD_IPV6_GROUP, LSD_PORT, 0, 0);
+                let announce = self.announce_message(&group6.to_string(), info_hashes);
+                let _ = socket6.send_to(&announce, group6);
+            }
+        }
+    }
+
+    fn announce_message(&self, host: &str, info_hashes: &[Vec<u8>]) -> Vec<u8> {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            host, self.port
+        );
+        for info_hash in info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        message.push_str(&format!("cookie: {}\r\n\r\n\r\n", self.cookie));
+        message.into_bytes()
+    }
+
+    fn receive(self: &Arc<Self>, socket: UdpSocket) {
+        let discovery = self.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 1500];
+            loop {
+                let (size, from) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        println!("LSD socket failed: {}", e);
+                        return;
+                    }
+                };
+                discovery.handle_announce(&buf[..size], from);
+            }
+        });
+    }
+
+    /// `from` keeps the scope id, link-local IPv6 peers can't be reached without it
+    fn handle_announce(&self, announce: &[u8], from: SocketAddr) {
+        let Some(announce) = parse_announce(announce) else {
+            return;
+        };
+        if announce.cookie.as_deref() == Some(self.cookie.as_str()) {
+            return;
+        }
+
+        let mut peer = from;
+        peer.set_port(announce.port);
+        let torrents = self.torrents.lock().unwrap();
+        for info_hash in &announce.info_hashes {
+            if let Some(torrent) = torrents.get(info_hash) {
+                if torrent.peer_pool.add([peer], PeerSource::Lsd) > 0 {
+                    println!("LSD found local peer {}", peer);
+                }
+            }
+        }
+    }
+}
+
+struct LsdAnnounce {
+    port: u16,
+    info_hashes: Vec<Vec<u8>>,
+    cookie: Option<String>,
+}
+
+/// Parses `BT-SEARCH` message, header names are case insensitive like in HTTP
+fn parse_announce(announce: &[u8]) -> Option<LsdAnnounce> {
+    let announce = std::str::from_utf8(announce).ok()?;
+    let mut lines = announce.split("\r\n");
+
+    if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+        return None;
+    }
+
+    let mut port = None;
+    let mut info_hashes = Vec::new();
+    let mut cookie = None;
+
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => port = value.parse().ok(),
+            "infohash" => {
+                if let Some(info_hash) = hex::decode(value).ok().filter(|hash| hash.len() == 20) {
+                    info_hashes.push(info_hash);
+                }
+            }
+            "cookie" => cookie = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    Some(LsdAnnounce {
+        port: port.filter(|port| *port != 0)?,
+        info_hashes,
+        cookie,
+    })
+}
+
+/// Binding to the group address filters out other traffic to the port where the OS allows it
+fn listen4() -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((LSD_IPV4_GROUP, LSD_PORT))
+        .or_else(|_| UdpSocket::bind((Ipv4Addr::UNSPECIFIED, LSD_PORT)))?;
+    socket.join_multicast_v4(&LSD_IPV4_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket)
+}
+
+fn listen6() -> io::Result<UdpSocket> {
+    // wildcard IPv6 socket would also take the IPv4 port on dual stack hosts
+    let socket = UdpSocket::bind(SocketAddrV6::new(LSD_IPV6_GROUP, LSD_PORT, 0, 0))?;
+    socket.join_multicast_v6(&LSD_IPV6_GROUP, 0)?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+    fn announce(headers: &str) -> Vec<u8> {
+        format!("BT-SEARCH * HTTP/1.1\r\n{}\r\n\r\n", headers).into_bytes()
+    }
+
+    fn discovery() -> LocalDiscovery {
+        LocalDiscovery {
+            port: 6881,
+            cookie: String::from("ours"),
+            torrents: Mutex::new(HashMap::new()),
+            socket4: UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap(),
+            socket6: None,
+        }
+    }
+
+    /// Discovery looking for peers of `INFO_HASH`
+    fn discovery_of_torrent() -> (LocalDiscovery, Arc<PeerPool>) {
+        let discovery = discovery();
+        let peer_pool = Arc::new(PeerPool::new());
+        discovery.add_torrent(&hex::decode(INFO_HASH).unwrap(), peer_pool.clone());
+        (discovery, peer_pool)
+    }
+
+    #[test]
+    fn headers_are_case_insensitive() {
+        let announce = parse_announce(&announce(&format!(
+            "HOST: 239.192.152.143:6771\r\nport: 6882\r\nINFOHASH: {}\r\nCookie:  theirs ",
+            INFO_HASH
+        )))
+        .unwrap();
+
+        assert_eq!(announce.port, 6882);
+        assert_eq!(announce.info_hashes, vec![hex::decode(INFO_HASH).unwrap()]);
+        assert_eq!(announce.cookie.as_deref(), Some("theirs"));
+    }
+
+    #[test]
+    fn own_message_parses_back() {
+        let discovery = discovery();
+        let info_hashes = vec![vec![1; 20], vec![2; 20]];
+
+        let message = discovery.announce_message("239.192.152.143:6771", &info_hashes);
+        let announce = parse_announce(&message).unwrap();
+        assert_eq!(announce.port, 6881);
+        assert_eq!(announce.info_hashes, info_hashes);
+        assert_eq!(announce.cookie.as_deref(), Some("ours"));
+    }
+
+    #[test]
+    fn bad_ports_and_hashes_are_rejected() {
+        for port in ["0", "65536", "-1", "x", ""] {
+            let headers = format!("Port: {}\r\nInfohash: {}", port, INFO_HASH);
+            assert!(parse_announce(&announce(&headers)).is_none(), "{}", port);
+        }
+        assert!(parse_announce(&announce(&format!("Infohash: {}", INFO_HASH))).is_none());
+        assert!(parse_announce(b"NOTIFY * HTTP/1.1\r\nPort: 6881\r\n\r\n").is_none());
+
+        let announce = parse_announce(&announce("Port: 6881\r\nInfohash: 0123\r\nInfohash: zz"));
+        assert!(announce.unwrap().info_hashes.is_empty());
+    }
+
+    #[test]
+    fn own_announces_are_ignored() {
+        let (discovery, peer_pool) = discovery_of_torrent();
+        let from = SocketAddr::from((Ipv4Addr::new(192, 168, 1, 2), 40000));
+
+        let headers = |cookie: &str| {
+            format!(
+                "Port: 6882\r\nInfohash: {}\r\ncookie: {}",
+                INFO_HASH, cookie
+            )
+        };
+        discovery.handle_announce(&announce(&headers("ours")), from);
+        assert!(peer_pool.is_empty());
+
+        discovery.handle_announce(&announce(&headers("theirs")), from);
+        assert_eq!(
+            peer_pool.peers(),
+            vec![SocketAddr::from((Ipv4Addr::new(192, 168, 1, 2), 6882))]
+        );
+    }
+
+    #[test]
+    fn link_local_peer_keeps_scope_id() {
+        let (discovery, peer_pool) = discovery_of_torrent();
+        let link_local = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2);
+        let from = SocketAddr::V6(SocketAddrV6::new(link_local, 40000, 0, 3));
+
+        let headers = format!("Port: 6882\r\nInfohash: {}", INFO_HASH);
+        discovery.handle_announce(&announce(&headers), from);
+        assert_eq!(
+            peer_pool.peers(),
+            vec![SocketAddr::V6(SocketAddrV6::new(link_local, 6882, 0, 3))]
+        );
+    }
+}
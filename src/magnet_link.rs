@@ -1,9 +1,16 @@
-use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use crate::{
+    client_config::ClientConfig,
+    meta_info_file::MetaInfo,
+    ut_metadata::{fetch_metadata_from_peers, MetadataError},
+};
 
 pub struct MagnetLink {
-    pub tracker_url: String,
+    pub trackers: Vec<String>,
+    pub web_seeds: Vec<String>,
     pub hash: Vec<u8>,
-    pub file_name: String,
+    pub file_name: Option<String>,
 }
 
 pub fn parse_magnet_link_url(magnet_link_url: &str) -> MagnetLink {
@@ -11,20 +18,45 @@ pub fn parse_magnet_link_url(magnet_link_url: &str) -> MagnetLink {
 
     assert_eq!(prefix, "magnet");
 
-    // decode url
-    let parse = url::form_urlencoded::parse(magnet_params_encoded.as_bytes());
-    let decoded_url: HashMap<String, String> = parse.into_owned().collect();
-
-    assert_eq!(decoded_url.len(), 3);
+    let mut hash = None;
+    let mut trackers = Vec::new();
+    let mut web_seeds = Vec::new();
+    let mut file_name = None;
 
-    let (xt_prefix, hash) = decoded_url.get("xt").expect("Expected xt").split_at(9);
-    assert_eq!(xt_prefix, "urn:btih:");
-    let tracker_url = decoded_url.get("tr").expect("Expected tr");
-    let file_name = decoded_url.get("dn").expect("Expected file_name");
+    // decode url, `tr` and `ws` can be repeated
+    for (key, value) in url::form_urlencoded::parse(magnet_params_encoded.as_bytes()).into_owned() {
+        match key.as_str() {
+            "xt" => {
+                let (xt_prefix, encoded_hash) = value.split_at(9);
+                assert_eq!(xt_prefix, "urn:btih:");
+                hash = Some(hex::decode(encoded_hash).expect("failed to parse hash"));
+            }
+            "tr" => trackers.push(value),
+            "ws" => web_seeds.push(value),
+            "dn" => file_name = Some(value),
+            _ => {}
+        }
+    }
 
     MagnetLink {
-        tracker_url: tracker_url.to_string(),
-        hash: hex::decode(hash).expect("failed to parse hash"),
-        file_name: file_name.to_string(),
+        trackers,
+        web_seeds,
+        hash: hash.expect("Expected xt"),
+        file_name,
     }
 }
+
+/// Resolves info dictionary of the magnet and returns content of an equivalent .torrent file
+pub fn magnet_to_torrent(
+    client: &ClientConfig,
+    magnet_link: &MagnetLink,
+    peers: &[SocketAddr],
+) -> Result<Vec<u8>, MetadataError> {
+    let metadata = fetch_metadata_from_peers(client, peers, &magnet_link.hash)?;
+
+    Ok(MetaInfo::torrent_file(
+        &metadata,
+        &magnet_link.trackers,
+        &magnet_link.web_seeds,
+    ))
+}
@@ -1,26 +1,55 @@
 use std::{
+    collections::HashMap,
     env,
     fs::File,
-    io::{IoSlice, Write},
-    path::PathBuf,
+    io::{self, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, UdpSocket},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
 };
 
 use bittorrent_starter_rust::{
-    bencode::decode_bencoded_value, discover_peers::discover_peers,
-    magnet_link::parse_magnet_link_url, meta_info_file::MetaInfo, peer_connection::PeerConnection,
-    pieces::download_piece,
+    async_tracker::{AnnounceParams, AsyncTrackerClient},
+    bencode::decode_bencoded_value,
+    choker::Choker,
+    client_config::ClientConfig,
+    dht::{self, DhtConfig, DhtSession},
+    discover_peers::{discover_peers, scrape},
+    local_discovery::{LocalDiscovery, LSD_ANNOUNCE_INTERVAL},
+    magnet_link::{magnet_to_torrent, parse_magnet_link_url},
+    meta_info_file::MetaInfo,
+    peer_connection::{PeerConnection, PeerError},
+    peer_pool::{PeerPool, PeerSource},
+    peer_server::{PeerServer, PeerServerConfig},
+    piece_store::PieceStore,
+    pieces::{download_piece, PieceQueue},
+    tracker_server::{TrackerServer, TrackerServerConfig},
+    tracker_session::{TrackerSession, TransferStats},
+    upload::serve_peer,
 };
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use serde_json::json;
+use tokio::runtime::Runtime;
+
+/// Routing table is kept between runs, so we don't start from bootstrap nodes every time
+const DHT_STATE_FILE: &str = "bittorrent-dht.dat";
+
+/// Peers we download from at the same time
+const DOWNLOAD_CONNECTIONS: usize = 8;
+
+/// Download gives up when no peer was available for this long
+const PEER_WAIT_TIMEOUT: Duration = Duration::from_secs(3 * 60);
 
 fn main() {
     let args: Vec<String> = env::args().collect();
     let command = &args[1];
     let file_path = &args[2];
+    let client = client_config(&args);
 
     if command == "decode" {
         // Uncomment this block to pass the first stage
-        let mut encoded_value = args[2].bytes().into_iter();
+        let mut encoded_value = args[2].bytes();
         let decoded_value = decode_bencoded_value(&mut encoded_value).unwrap();
         println!("{}", json!(decoded_value));
     } else if command == "info" {
@@ -28,122 +57,534 @@ fn main() {
         print!("{}", info);
     } else if command == "peers" {
         let info = MetaInfo::from_path(&PathBuf::from(file_path));
-        let peers = discover_peers(&info.hash, info.piece_length, &info.tracker_url);
-        println!("{:?}", peers);
+        let tracker_client =
+            Arc::new(AsyncTrackerClient::new(&client).expect("Failed to create tracker client"));
+        let params = AnnounceParams {
+            info_hash: info.hash.clone(),
+            uploaded: 0,
+            downloaded: 0,
+            left: info.length as u64,
+            event: None,
+        };
+
+        let runtime = Runtime::new().expect("Failed to start tokio runtime");
+        let result = runtime.block_on(tracker_client.announce_tiers(&info.announce_list, &params));
+        for (tracker_url, e) in &result.errors {
+            println!("Failed to announce to {}: {}", tracker_url, e);
+        }
+        println!("{:?}", result.peers);
     } else if command == "handshake" {
         let info = MetaInfo::from_path(&PathBuf::from(file_path));
-        let peer = &args[3];
+        let peer: SocketAddr = args[3].parse().expect("Failed to parse peer address");
 
-        let connection = PeerConnection::handshake(peer, &info.hash, false);
+        let connection = PeerConnection::handshake(
+            &peer,
+            &info.hash,
+            &client.peer_id,
+            false,
+            client.peer_timeouts,
+            client.encryption,
+        )
+        .expect("Failed to handshake with peer");
         println!("Handshaked with Peer ID: {}", connection.peer_id);
     } else if command == "download_piece" {
         let (save_to, torrent_info_path, piece_number) = (&args[3], &args[4], &args[5]);
         let piece_index: usize = piece_number.parse().expect("Failed to parse piece index");
 
         let info = MetaInfo::from_path(&PathBuf::from(torrent_info_path));
-        let discover_peers = discover_peers(&info.hash, info.piece_length, &info.tracker_url);
-        let peers = discover_peers;
+        let peers = discover_peers(&client, &info.hash, info.piece_length, &info.tracker_url)
+            .expect("Failed to announce to tracker")
+            .peers;
         println!("Peers {:?}", peers);
         let peer = peers
-            .get((piece_index % 3) as usize)
+            .get(piece_index % 3)
             .expect("Expected at least one peer");
 
-        let piece = download_piece(peer, &info, piece_index).1;
+        let peer_pool = PeerPool::new();
+        peer_pool.add(peers.iter().copied(), PeerSource::Tracker);
+        let piece = download_piece(
+            &client,
+            peer,
+            &info,
+            &PieceQueue::new([piece_index]),
+            &peer_pool,
+        )
+        .expect("Failed to download piece")
+        .1;
 
         let mut file = File::create(save_to).expect("Failed to open file");
-        file.write(&piece).unwrap();
+        file.write_all(&piece).unwrap();
         file.flush().expect("Failed to flush file");
         println!("Piece {} downloaded to {}.", piece_index, save_to);
     } else if command == "download" {
         let (save_to, torrent_info_path) = (&args[3], &args[4]);
 
         let info = MetaInfo::from_path(&PathBuf::from(torrent_info_path));
-        let peers = discover_peers(&info.hash, info.piece_length, &info.tracker_url);
-        println!("Peers {:?}", peers);
+        let store =
+            Arc::new(PieceStore::create(Path::new(save_to), &info).expect("Failed to create file"));
+        let stats = Arc::new(TransferStats::new(info.length as u64));
+
+        // verified pieces are uploaded to peers connecting to us while we download
+        let runtime = Runtime::new().expect("Failed to start tokio runtime");
+        // peers we download from fastest get unchoked first
+        let choker = Choker::new(client.upload_slots);
+        let _peer_server = serve_torrent(&runtime, &client, &info.hash, &store, &stats, &choker)
+            .map_err(|e| println!("Failed to accept peers: {}", e))
+            .ok();
 
-        save_torrent_to_file(info, peers, save_to);
+        // tracker, LSD and PEX keep adding peers to the pool while we download
+        let peer_pool = Arc::new(PeerPool::new());
+        let session = TrackerSession::start(
+            &client,
+            &info.announce_list,
+            &info.hash,
+            stats.clone(),
+            peer_pool.clone(),
+        );
+        let lsd_enabled = !info.private && !args.iter().any(|arg| arg == "--no-lsd");
+        let local_discovery = start_local_discovery(&client, &info.hash, &peer_pool, lsd_enabled);
+        // announcing to the DHT makes us findable even when the tracker gave us enough peers,
+        // download workers wait for the peers of its first lookup
+        let _dht_session = (!info.private)
+            .then(|| start_dht_session(&client, &info.hash, &peer_pool))
+            .flatten();
+        if peer_pool.is_empty() && local_discovery.is_some() {
+            println!("Waiting for local peers");
+            peer_pool.wait_for_peers(LSD_ANNOUNCE_INTERVAL);
+        }
+        println!("Peers {:?}", peer_pool.peers());
+
+        save_torrent_to_file(&client, &info, &peer_pool, &store, &stats, Some(&choker));
+        session.completed();
         println!("Downloaded {} to {}.", torrent_info_path, save_to);
-    } else if command == "magnet_parse" {
-        let magnet_link = &args[2];
+    } else if command == "seed" {
+        let data_path = &args[3];
 
-        let magnet_link = parse_magnet_link_url(magnet_link);
+        let info = MetaInfo::from_path(&PathBuf::from(file_path));
+        let store =
+            Arc::new(PieceStore::open(Path::new(data_path), &info).expect("Failed to open data"));
+        println!(
+            "Verified {}/{} pieces of {}",
+            store.verify(),
+            store.pieces_count(),
+            data_path
+        );
+        let stats = Arc::new(TransferStats::new(store.missing_bytes()));
 
-        println!("Tracker URL: {}", magnet_link.tracker_url);
-        println!("Info Hash: {}", hex::encode(magnet_link.hash));
-    } else if command == "magnet_info" {
+        let runtime = Runtime::new().expect("Failed to start tokio runtime");
+        let choker = Choker::new(client.upload_slots);
+        let _peer_server = serve_torrent(&runtime, &client, &info.hash, &store, &stats, &choker)
+            .expect("Failed to accept peers");
+
+        // seeds only announce themselves, peers connect to us
+        let peer_pool = Arc::new(PeerPool::new());
+        let _session = TrackerSession::start(
+            &client,
+            &info.announce_list,
+            &info.hash,
+            stats.clone(),
+            peer_pool.clone(),
+        );
+        let lsd_enabled = !info.private && !args.iter().any(|arg| arg == "--no-lsd");
+        let _local_discovery = start_local_discovery(&client, &info.hash, &peer_pool, lsd_enabled);
+        let _dht_session = (!info.private)
+            .then(|| start_dht_session(&client, &info.hash, &peer_pool))
+            .flatten();
+
+        println!("Seeding {} on port {}", data_path, client.port);
+        runtime.block_on(std::future::pending::<()>());
+    } else if command == "magnet_parse" || command == "magnet_info" {
         let magnet_link = &args[2];
 
         let magnet_link = parse_magnet_link_url(magnet_link);
 
-        println!("Tracker URL: {}", magnet_link.tracker_url);
+        if let Some(tracker_url) = magnet_link.trackers.first() {
+            println!("Tracker URL: {}", tracker_url);
+        }
         println!("Info Hash: {}", hex::encode(magnet_link.hash));
     } else if command == "magnet_handshake" {
         let magnet_link_url = &args[2];
 
         let magnet_link = parse_magnet_link_url(magnet_link_url);
 
-        // some random number, because we don't know the length before fetching metadata
-        let peers = discover_peers(&magnet_link.hash, 999, &magnet_link.tracker_url);
-
-        let peer_for_metadata = peers.first().unwrap();
+        let peer_pool = Arc::new(PeerPool::new());
+        let lsd_enabled = !args.iter().any(|arg| arg == "--no-lsd");
+        let local_discovery =
+            start_local_discovery(&client, &magnet_link.hash, &peer_pool, lsd_enabled);
+        magnet_peers(
+            &client,
+            &magnet_link.hash,
+            &magnet_link.trackers,
+            &peer_pool,
+        );
+        if peer_pool.is_empty() && local_discovery.is_some() {
+            println!("Waiting for local peers");
+            peer_pool.wait_for_peers(LSD_ANNOUNCE_INTERVAL);
+        }
 
-        let info = MetaInfo::from_magnet_link(&magnet_link, peer_for_metadata);
+        let info = MetaInfo::from_magnet_link(&client, &magnet_link, &peer_pool.peers())
+            .expect("Failed to fetch metadata from peers");
 
         let file_name = info
             .file_name
             .clone()
             .unwrap_or(String::from("missing_file_name"));
 
-        save_torrent_to_file(info, peers, &file_name);
+        let stats = Arc::new(TransferStats::new(info.length as u64));
+        let session = (!info.announce_list.is_empty()).then(|| {
+            TrackerSession::start(
+                &client,
+                &info.announce_list,
+                &info.hash,
+                stats.clone(),
+                peer_pool.clone(),
+            )
+        });
+
+        let store =
+            PieceStore::create(Path::new(&file_name), &info).expect("Failed to create file");
+        save_torrent_to_file(&client, &info, &peer_pool, &store, &stats, None);
+        if let Some(session) = &session {
+            session.completed();
+        }
+    } else if command == "magnet_to_torrent" {
+        let (magnet_link_url, save_to) = (&args[2], &args[3]);
+
+        let magnet_link = parse_magnet_link_url(magnet_link_url);
+
+        let peer_pool = PeerPool::new();
+        magnet_peers(
+            &client,
+            &magnet_link.hash,
+            &magnet_link.trackers,
+            &peer_pool,
+        );
+
+        let torrent = magnet_to_torrent(&client, &magnet_link, &peer_pool.peers())
+            .expect("Failed to fetch metadata from peers");
+
+        let mut file = File::create(save_to).expect("Failed to open file");
+        file.write_all(&torrent).unwrap();
+        file.flush().expect("Failed to flush file");
+        println!("Saved torrent to {}", save_to);
+    } else if command == "dht_peers" {
+        let info_hash = hex::decode(&args[2]).expect("Failed to decode info hash");
+        let peers = dht_peers(&client, &info_hash, false);
+        println!("{:?}", peers);
+    } else if command == "scrape" {
+        let torrents: Vec<_> = args[2..]
+            .iter()
+            .filter(|arg| !arg.starts_with("--"))
+            .map(|torrent_info_path| MetaInfo::from_path(&PathBuf::from(torrent_info_path)))
+            .collect();
+
+        let mut torrents_by_tracker: HashMap<&str, Vec<&MetaInfo>> = HashMap::new();
+        for info in &torrents {
+            torrents_by_tracker
+                .entry(&info.tracker_url)
+                .or_default()
+                .push(info);
+        }
+
+        for (tracker_url, torrents) in torrents_by_tracker {
+            let info_hashes: Vec<&[u8]> =
+                torrents.iter().map(|info| info.hash.as_slice()).collect();
+
+            match scrape(&client, tracker_url, &info_hashes) {
+                Ok(stats) => {
+                    for info_hash in info_hashes {
+                        match stats.get(info_hash) {
+                            Some(stats) => println!(
+                                "{} seeders: {} leechers: {} downloaded: {}",
+                                hex::encode(info_hash),
+                                stats.complete,
+                                stats.incomplete,
+                                stats.downloaded
+                            ),
+                            None => println!("{} unknown to tracker", hex::encode(info_hash)),
+                        }
+                    }
+                }
+                Err(e) => println!("Failed to scrape {}: {}", tracker_url, e),
+            }
+        }
+    } else if command == "tracker" {
+        let address: SocketAddr = args[2].parse().expect("Failed to parse tracker address");
+
+        // torrents given after the address are the only ones tracked
+        let torrent_paths: Vec<_> = args[3..]
+            .iter()
+            .filter(|arg| !arg.starts_with("--"))
+            .collect();
+        let whitelist = (!torrent_paths.is_empty()).then(|| {
+            torrent_paths
+                .iter()
+                .map(|torrent_info_path| {
+                    MetaInfo::from_path(&PathBuf::from(torrent_info_path)).hash
+                })
+                .collect()
+        });
+
+        let server = TrackerServer::new(TrackerServerConfig {
+            whitelist,
+            ..Default::default()
+        });
+
+        let http = server.serve_http(TcpListener::bind(address).expect("Failed to bind tcp"));
+        let udp = server.serve_udp(UdpSocket::bind(address).expect("Failed to bind udp"));
+        println!(
+            "Tracker listening on http://{0}/announce and udp://{0}/announce",
+            address
+        );
+
+        http.join().unwrap();
+        udp.join().unwrap();
     } else {
         println!("unknown command: {}", command)
     }
 }
 
-fn save_torrent_to_file(info: MetaInfo, peers: Vec<String>, save_to: &String) {
-    let pieces_count = int_div_with_ceil(info.length, info.piece_length);
-
-    let pieces_indexes: Vec<_> = (0..pieces_count).collect();
-    let chunk_size: usize = int_div_with_ceil(pieces_count, peers.len());
-    let chunks_piece_indexes = pieces_indexes.chunks(chunk_size);
-
-    let jobs: Vec<_> = chunks_piece_indexes.zip(peers.into_iter()).collect();
-
-    println!("Scheduled piece indexes to download per peer {:?}", jobs);
-
-    let mut pieces: Vec<_> = jobs
-        .into_par_iter()
-        .map(|(indexes, peer)| {
-            indexes
-                .into_iter()
-                .map(|piece_index| {
-                    let result = download_piece(&peer, &info, *piece_index);
-                    println!(
-                        "Peer {} downloaded {}/{}",
-                        peer,
-                        piece_index + 1,
-                        pieces_count
-                    );
-                    return result;
-                })
-                .collect::<Vec<_>>()
-        })
-        .flatten()
-        .collect();
+/// Client settings overridden by `--port=`, `--numwant=`, `--ip=`, `--user-agent=`
+/// and `--encryption=` flags after the command arguments
+fn client_config(args: &[String]) -> ClientConfig {
+    let mut client = ClientConfig::default();
+    let flag = |name: &str| {
+        args.iter()
+            .find_map(|arg| arg.strip_prefix(name)?.strip_prefix('='))
+    };
+
+    if let Some(port) = flag("--port") {
+        client.port = port.parse().expect("Failed to parse port");
+    }
+    if let Some(numwant) = flag("--numwant") {
+        client.numwant = Some(numwant.parse().expect("Failed to parse numwant"));
+    }
+    if let Some(ip) = flag("--ip") {
+        client.ip = Some(ip.parse().expect("Failed to parse ip"));
+    }
+    if let Some(user_agent) = flag("--user-agent") {
+        client.user_agent = user_agent.to_string();
+    }
+    if let Some(policy) = flag("--encryption") {
+        client.encryption = policy.parse().expect("Failed to parse encryption policy");
+    }
+
+    client
+}
 
-    pieces.sort_unstable_by_key(|x| x.0);
+/// Adds peers of a magnet link from its first tracker, DHT when there is none or it failed
+fn magnet_peers(
+    client: &ClientConfig,
+    info_hash: &[u8],
+    trackers: &[String],
+    peer_pool: &PeerPool,
+) {
+    if let Some(tracker_url) = trackers.first() {
+        // some random number, because we don't know the length before fetching metadata
+        match discover_peers(client, info_hash, 999, tracker_url) {
+            Ok(response) if !response.peers.is_empty() => {
+                peer_pool.add(response.peers, PeerSource::Tracker);
+                return;
+            }
+            Ok(_) => println!("Tracker returned no peers"),
+            Err(e) => println!("Failed to announce to tracker: {}", e),
+        }
+    }
+
+    peer_pool.add(dht_peers(client, info_hash, false), PeerSource::Dht);
+}
+
+/// Announces the torrent on the LAN, local peers are added to `peer_pool` as they show up
+fn start_local_discovery(
+    client: &ClientConfig,
+    info_hash: &[u8],
+    peer_pool: &Arc<PeerPool>,
+    enabled: bool,
+) -> Option<Arc<LocalDiscovery>> {
+    if !enabled {
+        return None;
+    }
+
+    let local_discovery = LocalDiscovery::start(client.port)
+        .map_err(|e| println!("Failed to start local service discovery: {}", e))
+        .ok()?;
+    local_discovery.add_torrent(info_hash, peer_pool.clone());
+    Some(local_discovery)
+}
 
-    let io_vector: Vec<_> = pieces.iter().map(|x| IoSlice::new(&x.1)).collect();
+/// Peers of the torrent from the DHT, with `announce` we are added to them on our port
+fn dht_peers(client: &ClientConfig, info_hash: &[u8], announce: bool) -> Vec<SocketAddr> {
+    let info_hash = info_hash.try_into().expect("Info hash must be 20 bytes");
+    dht::find_peers(
+        dht_config(client),
+        &info_hash,
+        announce.then_some(client.port),
+    )
+    .expect("Failed to start DHT node")
+}
 
-    let mut file = File::create(save_to).expect("Failed to open file");
-    file.write_vectored(&io_vector).unwrap();
-    file.flush().expect("Failed to flush file");
-    println!("Saved torrent to {}", save_to);
+/// Keeps announcing us on the DHT while the torrent is active, found peers go to the pool
+fn start_dht_session(
+    client: &ClientConfig,
+    info_hash: &[u8],
+    peer_pool: &Arc<PeerPool>,
+) -> Option<DhtSession> {
+    let info_hash = info_hash.try_into().expect("Info hash must be 20 bytes");
+    let peer_pool = peer_pool.clone();
+    DhtSession::start(
+        dht_config(client),
+        info_hash,
+        Some(client.port),
+        move |peers| {
+            peer_pool.add(peers, PeerSource::Dht);
+        },
+    )
+    .map_err(|e| println!("Failed to start DHT node: {}", e))
+    .ok()
 }
 
-fn int_div_with_ceil(a: usize, b: usize) -> usize {
-    match (a / b, a % b) {
-        (count, reminder) if reminder > 0 => return count + 1,
-        (count, _) => return count,
+fn dht_config(client: &ClientConfig) -> DhtConfig {
+    let mut config = DhtConfig {
+        bind_address: (Ipv4Addr::UNSPECIFIED, client.port).into(),
+        state_path: Some(env::temp_dir().join(DHT_STATE_FILE)),
+        ..Default::default()
     };
+    // comma separated `host:port` list, e.g. to use a local DHT
+    if let Ok(bootstrap_nodes) = env::var("DHT_BOOTSTRAP_NODES") {
+        config.bootstrap_nodes = bootstrap_nodes.split(',').map(String::from).collect();
+    }
+    config
+}
+
+/// Accepts peers on our port and uploads pieces of `store` to them
+fn serve_torrent(
+    runtime: &Runtime,
+    client: &ClientConfig,
+    info_hash: &[u8],
+    store: &Arc<PieceStore>,
+    stats: &Arc<TransferStats>,
+    choker: &Arc<Choker>,
+) -> io::Result<Arc<PeerServer>> {
+    let listener = runtime.block_on(tokio::net::TcpListener::bind((
+        Ipv4Addr::UNSPECIFIED,
+        client.port,
+    )))?;
+
+    let _guard = runtime.enter();
+    let peer_server = PeerServer::new(client, PeerServerConfig::default());
+    peer_server.serve(listener);
+    let mut incoming = peer_server.add_torrent(info_hash);
+
+    let seeding_store = store.clone();
+    choker.start(move || seeding_store.is_complete());
+
+    let (store, stats, choker) = (store.clone(), stats.clone(), choker.clone());
+    runtime.spawn(async move {
+        while let Some(peer) = incoming.recv().await {
+            let (store, stats, choker) = (store.clone(), stats.clone(), choker.clone());
+            tokio::spawn(async move {
+                // `peer` keeps its connection slot until serving ends
+                let address = peer.connection.peer;
+                let connection = peer.connection;
+                match serve_peer(connection, store, stats, &choker).await {
+                    Ok(()) | Err(PeerError::Disconnected) => {}
+                    Err(e) => println!("Stopped uploading to {}: {}", address, e),
+                }
+            });
+        }
+    });
+
+    Ok(peer_server)
+}
+
+fn save_torrent_to_file(
+    client: &ClientConfig,
+    info: &MetaInfo,
+    peer_pool: &PeerPool,
+    store: &PieceStore,
+    stats: &TransferStats,
+    choker: Option<&Choker>,
+) {
+    let pieces_count = store.pieces_count();
+    // pieces failed with one peer go back to the queue for another one
+    let pending_pieces =
+        PieceQueue::new((0..pieces_count).filter(|index| !store.has_piece(*index)));
+
+    // each worker keeps one connection at a time, workers wait for peers which are added
+    // later or come out of their backoff
+    thread::scope(|scope| {
+        for _ in 0..DOWNLOAD_CONNECTIONS {
+            scope.spawn(|| {
+                download_pieces(
+                    client,
+                    info,
+                    peer_pool,
+                    &pending_pieces,
+                    store,
+                    stats,
+                    choker,
+                )
+            });
+        }
+    });
+
+    if !store.is_complete() {
+        panic!(
+            "Ran out of peers with {} of {} pieces downloaded",
+            store.have_count(),
+            pieces_count
+        );
+    }
+    store.flush().expect("Failed to flush file");
+}
+
+/// Downloads pieces from peers of the pool until the queue is empty or no peer shows up
+/// for `PEER_WAIT_TIMEOUT`
+fn download_pieces(
+    client: &ClientConfig,
+    info: &MetaInfo,
+    peer_pool: &PeerPool,
+    pending_pieces: &PieceQueue,
+    store: &PieceStore,
+    stats: &TransferStats,
+    choker: Option<&Choker>,
+) {
+    // a piece failing with another worker goes back to the queue, that worker retries it
+    while !pending_pieces.is_empty() {
+        let Some(peer) = peer_pool.wait_for_candidate(PEER_WAIT_TIMEOUT) else {
+            return;
+        };
+
+        match download_piece(client, &peer, info, pending_pieces, peer_pool) {
+            Ok((piece_index, piece)) => {
+                peer_pool.disconnected(&peer);
+                store
+                    .write_piece(piece_index, &piece)
+                    .expect("Failed to write piece");
+                stats.record_download(piece.len() as u64);
+                if let Some(choker) = choker {
+                    choker.record_download(peer.ip(), piece.len() as u64);
+                }
+                println!(
+                    "Peer {} downloaded {}/{}",
+                    peer,
+                    piece_index + 1,
+                    store.pieces_count()
+                );
+            }
+            // the last pieces were taken by other workers meanwhile
+            Err(PeerError::MissingPieces) if pending_pieces.is_empty() => {
+                peer_pool.disconnected(&peer);
+            }
+            // bad data or messages, everything else may work out later
+            Err(e @ (PeerError::ProtocolViolation(_) | PeerError::MessageTooLarge(_))) => {
+                println!("Banning peer {}: {}", peer, e);
+                peer_pool.ban(&peer);
+            }
+            Err(e) => {
+                println!("Retrying peer {} later: {}", peer, e);
+                peer_pool.retry_later(&peer);
+            }
+        }
+    }
 }
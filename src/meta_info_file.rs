@@ -1,36 +1,42 @@
 use std::{
     fmt::Display,
     fs,
+    net::SocketAddr,
     path::{Path, PathBuf},
 };
 
-use serde::{Deserialize, Serialize};
-
 use crate::{
-    bencode::{self, decode_bencoded_value, BenDecodeErrors},
+    bencode::{decode_bencoded_value, encode_list, encode_string, BenDecodeErrors},
+    client_config::ClientConfig,
     magnet_link::MagnetLink,
-    peer_connection::{MessageType, PeerConnection},
     sha1_it,
+    ut_metadata::{fetch_metadata_from_peers, MetadataError},
 };
 
 #[derive(Debug)]
 pub struct MetaInfo {
     pub tracker_url: String,
+    /// tiers of `announce-list` (BEP 12), just `tracker_url` when missing
+    pub announce_list: Vec<Vec<String>>,
     pub length: usize,
     pub hash: Vec<u8>,
     pub piece_length: usize,
     // FIXME: sub optimal this should be Vec<Vec<u8>>
     pub piece_hashes: Vec<String>,
     pub file_name: Option<String>,
+    /// peers must come from trackers only, no DHT, PEX or LSD (BEP 27)
+    pub private: bool,
+    /// bencoded info dictionary, served to peers asking for it via ut_metadata
+    pub metadata: Vec<u8>,
 }
 
 impl Display for MetaInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        println!("Tracker URL: {}", self.tracker_url);
-        println!("Length: {}", self.length);
-        println!("Info Hash: {}", hex::encode(&self.hash));
-        println!("Piece Length: {}", self.piece_length);
-        println!("Piece Hashes");
+        writeln!(f, "Tracker URL: {}", self.tracker_url)?;
+        writeln!(f, "Length: {}", self.length)?;
+        writeln!(f, "Info Hash: {}", hex::encode(&self.hash))?;
+        writeln!(f, "Piece Length: {}", self.piece_length)?;
+        writeln!(f, "Piece Hashes")?;
         for piece in &self.piece_hashes {
             write!(f, "{}", piece)?;
         }
@@ -38,27 +44,24 @@ impl Display for MetaInfo {
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct MetadataHandshakePayload {
-    ut_metadata: i32,
-}
-
-#[derive(Serialize, Deserialize)]
-struct MetadataHandshakePayloadEnvelope {
-    m: MetadataHandshakePayload,
-}
-
-#[derive(Serialize, Deserialize)]
-struct MetadataMessagePayload {
-    msg_type: i32,
-    piece: i32,
-}
-
 impl MetaInfo {
     pub fn from_path(file_path: &Path) -> Self {
         let info = read_metainfo_file(&PathBuf::from(file_path)).unwrap();
 
         let announce = info["announce"].as_str().unwrap();
+        let announce_list = match info["announce-list"].as_array() {
+            Some(tiers) => tiers
+                .iter()
+                .filter_map(|tier| tier.as_array())
+                .map(|tier| {
+                    tier.iter()
+                        .filter_map(|tracker_url| tracker_url.as_str().map(String::from))
+                        .collect::<Vec<_>>()
+                })
+                .filter(|tier| !tier.is_empty())
+                .collect(),
+            None => vec![vec![announce.to_string()]],
+        };
         let length = info["info"].as_object().unwrap()["length"]
             .as_u64()
             .unwrap();
@@ -75,104 +78,179 @@ impl MetaInfo {
             .unwrap()
             .as_bytes()
             .chunks(20)
-            .map(|x| hex::encode(x))
+            .map(hex::encode)
             .collect();
 
-        return MetaInfo {
+        MetaInfo {
             tracker_url: announce.to_string(),
+            announce_list,
             length: length as usize,
             hash: hash.to_vec(),
             piece_length: piece_length as usize,
             file_name: Option::None,
             piece_hashes: pieces,
-        };
+            private: info["info"]["private"].as_i64() == Some(1),
+            metadata: bencoded_info,
+        }
     }
 
-    pub fn from_magnet_link(magnet_link: &MagnetLink, peer: &String) -> Self {
-        let mut peer_connection = PeerConnection::handshake(peer, &magnet_link.hash, true);
+    /// Resolves info dictionary of a magnet link from given peers
+    pub fn from_magnet_link(
+        client: &ClientConfig,
+        magnet_link: &MagnetLink,
+        peers: &[SocketAddr],
+    ) -> Result<Self, MetadataError> {
+        let metadata = fetch_metadata_from_peers(client, peers, &magnet_link.hash)?;
+        Self::from_metadata(metadata, &magnet_link.trackers)
+    }
 
-        let message = peer_connection.read_message();
-        assert_eq!(message.message_type, MessageType::BitField);
+    /// Parses a verified info dictionary, e.g. one fetched via ut_metadata
+    pub fn from_metadata(metadata: Vec<u8>, trackers: &[String]) -> Result<Self, MetadataError> {
+        let info = decode_bencoded_value(&mut metadata.clone().into_iter())
+            .map_err(|_| MetadataError::InvalidMessage)?;
+        // multi-file torrents have `files` instead of `length`, we only download single files
+        let length = info["length"]
+            .as_u64()
+            .ok_or(MetadataError::InvalidInfo("length"))?;
+        let name = info["name"]
+            .as_str()
+            .ok_or(MetadataError::InvalidInfo("name"))?;
+        let piece_length = info["piece length"]
+            .as_u64()
+            .filter(|piece_length| *piece_length > 0)
+            .ok_or(MetadataError::InvalidInfo("piece length"))?;
+        let pieces = info["pieces"]
+            .as_str()
+            .ok_or(MetadataError::InvalidInfo("pieces"))?;
+
+        Ok(MetaInfo {
+            tracker_url: trackers.first().cloned().unwrap_or_default(),
+            // same as in exported .torrent files, every tracker is a separate tier
+            announce_list: trackers
+                .iter()
+                .map(|tracker_url| vec![tracker_url.clone()])
+                .collect(),
+            length: length as usize,
+            hash: sha1_it(&metadata),
+            file_name: Some(String::from(name)),
+            piece_length: piece_length as usize,
+            piece_hashes: pieces.as_bytes().chunks(20).map(hex::encode).collect(),
+            private: info["private"].as_i64() == Some(1),
+            metadata,
+        })
+    }
 
-        // we could choose different peer
-        assert_eq!(peer_connection.extension_enabled, true);
+    /// Builds .torrent file around verbatim info dictionary, so its info hash stays the same
+    pub fn torrent_file(metadata: &[u8], trackers: &[String], web_seeds: &[String]) -> Vec<u8> {
+        // keys of bencoded dictionary have to be sorted
+        let mut torrent = vec![b'd'];
 
-        let payload = MetadataHandshakePayloadEnvelope {
-            m: MetadataHandshakePayload { ut_metadata: 1 },
-        };
+        if let Some(tracker_url) = trackers.first() {
+            torrent.extend(encode_string(b"announce"));
+            torrent.extend(encode_string(tracker_url.as_bytes()));
 
-        let mut handshake_message = vec![0; 1];
-        handshake_message.extend_from_slice(&serde_bencode::to_bytes(&payload).unwrap());
-        peer_connection.send_message(MessageType::Extended, handshake_message);
+            // every tracker of a magnet link is a separate tier (BEP 12)
+            torrent.extend(encode_string(b"announce-list"));
+            torrent.extend(encode_list(trackers.iter().map(|tracker_url| {
+                encode_list([encode_string(tracker_url.as_bytes())])
+            })));
+        }
 
-        let message = peer_connection.read_message();
-        assert_eq!(message.message_type, MessageType::Extended);
+        torrent.extend(encode_string(b"info"));
+        torrent.extend_from_slice(metadata);
 
-        let payload = decode_bencoded_value(&mut message.payload.into_iter()).unwrap();
+        if !web_seeds.is_empty() {
+            torrent.extend(encode_string(b"url-list"));
+            torrent.extend(encode_list(
+                web_seeds
+                    .iter()
+                    .map(|web_seed| encode_string(web_seed.as_bytes())),
+            ));
+        }
 
-        println!("Peer ID: {}", peer_connection.peer_id);
-        println!(
-            "Peer Metadata Extension ID: {}",
-            payload["m"]["ut_metadata"]
-        );
+        torrent.push(b'e');
+        torrent
+    }
+}
 
-        let peer_extension_id = &payload["m"]["ut_metadata"];
+fn read_metainfo_file(file_path: &Path) -> Result<serde_json::Value, BenDecodeErrors> {
+    let content = fs::read(file_path).unwrap();
 
-        let peer_extension_id: u8 = match peer_extension_id {
-            serde_json::Value::Number(n) => u8::try_from(n.as_i64().unwrap()).unwrap(),
-            _ => panic!("wrong value"),
-        };
+    decode_bencoded_value(&mut content.into_iter())
+}
 
-        let mut payload: Vec<u8> = vec![peer_extension_id; 1];
-        payload.extend(
-            serde_bencode::to_bytes(&MetadataMessagePayload {
-                msg_type: 0,
-                piece: 0,
-            })
-            .unwrap(),
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn metadata_of_single_file_torrent() {
+        let metadata = b"d6:lengthi40000e4:name8:data.bin12:piece lengthi32768e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbb7:privatei1ee".to_vec();
+        let trackers = vec![
+            String::from("http://a/announce"),
+            String::from("udp://b:80"),
+        ];
+
+        let info = MetaInfo::from_metadata(metadata.clone(), &trackers).unwrap();
+        assert_eq!(info.length, 40000);
+        assert_eq!(info.file_name.as_deref(), Some("data.bin"));
+        assert_eq!(info.piece_length, 32768);
+        assert_eq!(info.piece_hashes.len(), 2);
+        assert_eq!(info.piece_hashes[0], hex::encode([b'a'; 20]));
+        assert!(info.private);
+        assert_eq!(info.hash, sha1_it(&metadata));
+        assert_eq!(info.tracker_url, "http://a/announce");
+        assert_eq!(
+            info.announce_list,
+            vec![vec![trackers[0].clone()], vec![trackers[1].clone()]]
         );
-        peer_connection.send_message(MessageType::Extended, payload);
-
-        let message = peer_connection.read_message();
-        assert_eq!(message.message_type, MessageType::Extended);
-
-        let response =
-            bencode::decode_bencoded_value(&mut message.payload.clone().into_iter()).unwrap();
-        // FIXME: here could be multiple pieces
-        let piece_length = response["total_size"].as_i64().unwrap();
-
-        let (_, piece_data) = message
-            .payload
-            .split_at(message.payload.len() - piece_length as usize);
-
-        // FIXME: not optimal
-        let piece_content =
-            bencode::decode_bencoded_value(&mut piece_data.to_vec().into_iter()).unwrap();
-
-        // dbg!(piece_content);
-        let hash = sha1_it(&piece_data.to_vec());
-
-        assert_eq!(&hash, &magnet_link.hash);
-
-        return MetaInfo {
-            tracker_url: magnet_link.tracker_url.to_owned(),
-            length: piece_content["length"].as_i64().unwrap() as usize,
-            hash: hash,
-            file_name: Some(String::from(piece_content["name"].as_str().unwrap())),
-            piece_length: piece_content["piece length"].as_i64().unwrap() as usize,
-            piece_hashes: piece_content["pieces"]
-                .as_str()
-                .unwrap()
-                .as_bytes()
-                .chunks(20)
-                .map(|x| hex::encode(x))
-                .collect(),
-        };
     }
-}
 
-fn read_metainfo_file(file_path: &Path) -> Result<serde_json::Value, BenDecodeErrors> {
-    let content = fs::read(file_path).unwrap();
+    #[test]
+    fn metadata_of_multi_file_torrent_is_an_error() {
+        let metadata = b"d5:filesld6:lengthi5e4:pathl1:aeee4:name3:dir12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae".to_vec();
+        assert!(matches!(
+            MetaInfo::from_metadata(metadata, &[]),
+            Err(MetadataError::InvalidInfo("length"))
+        ));
+    }
+
+    #[test]
+    fn torrent_file_keeps_info_hash() {
+        let metadata = b"d6:lengthi40000e4:name8:data.bin12:piece lengthi32768e6:pieces40:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbe".to_vec();
+        let trackers = vec![
+            String::from("http://a/announce"),
+            String::from("udp://b:80"),
+        ];
+        let web_seeds = vec![String::from("http://c/data.bin")];
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&MetaInfo::torrent_file(&metadata, &trackers, &web_seeds))
+            .unwrap();
 
-    return decode_bencoded_value(&mut content.into_iter());
+        let info = MetaInfo::from_path(file.path());
+        assert_eq!(info.hash, sha1_it(&metadata));
+        assert_eq!(info.metadata, metadata);
+        assert_eq!(info.length, 40000);
+        assert_eq!(info.tracker_url, "http://a/announce");
+        assert_eq!(
+            info.announce_list,
+            vec![vec![trackers[0].clone()], vec![trackers[1].clone()]]
+        );
+        let torrent = read_metainfo_file(file.path()).unwrap();
+        assert_eq!(
+            torrent["url-list"],
+            serde_json::json!(["http://c/data.bin"])
+        );
+    }
+
+    #[test]
+    fn torrent_file_without_trackers_or_web_seeds() {
+        let metadata =
+            b"d6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let torrent = MetaInfo::torrent_file(metadata, &[], &[]);
+        assert_eq!(torrent, [&b"d4:info"[..], metadata, b"e"].concat());
+    }
 }
@@ -0,0 +1,558 @@
+use std::{
+    io::{Read, Write},
+    str::FromStr,
+};
+
+use crate::{peer_connection::PeerError, random_u32, secure_random_bytes, sha1_it};
+
+/// Prime of the Diffie-Hellman key exchange, the generator is 2
+const DH_PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+
+/// Public keys and the shared secret are 768 bit big endian numbers
+const KEY_LENGTH: usize = 96;
+
+/// Verification constant, encrypted zeros let the other side find where padding ends
+const VC: [u8; 8] = [0; 8];
+
+/// Padding after public keys and inside the encrypted handshake is at most this long
+const MAX_PADDING: usize = 512;
+
+/// RC4 keystream bytes thrown away before use, the beginning of it is weak
+const RC4_DISCARD: usize = 1024;
+
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+/// What we do about Message Stream Encryption
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum EncryptionPolicy {
+    /// encrypted handshake first, plaintext when the peer doesn't understand it
+    #[default]
+    Prefer,
+    /// RC4 encrypted connections only
+    Require,
+    /// plaintext connections only
+    Disable,
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "prefer" => Ok(EncryptionPolicy::Prefer),
+            "require" => Ok(EncryptionPolicy::Require),
+            "disable" => Ok(EncryptionPolicy::Disable),
+            _ => Err(format!("unknown encryption policy {}", policy)),
+        }
+    }
+}
+
+impl EncryptionPolicy {
+    fn crypto_provide(&self) -> u32 {
+        match self {
+            EncryptionPolicy::Require => CRYPTO_RC4,
+            _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0; 256];
+        for (i, byte) in state.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Rc4 { state, i: 0, j: 0 }
+    }
+
+    /// Encrypts or decrypts `data` in place
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let k = self.state
+                [self.state[self.i as usize].wrapping_add(self.state[self.j as usize]) as usize];
+            *byte ^= k;
+        }
+    }
+}
+
+/// RC4 streams of both directions once the handshake selected RC4
+pub struct StreamCipher {
+    pub encrypt: Rc4,
+    pub decrypt: Rc4,
+}
+
+/// Encrypted handshake accepted from the initiator
+pub struct Accepted {
+    pub info_hash: [u8; 20],
+    /// `None` when the initiator asked to continue in plaintext
+    pub cipher: Option<StreamCipher>,
+    /// decrypted payload sent along the handshake, the start of the BitTorrent handshake
+    pub initial_payload: Vec<u8>,
+}
+
+/// Runs the initiator side of the handshake (MSE/PE) and sends `initial_payload`,
+/// our BitTorrent handshake, inside it. Returns `None` when the peer chose plaintext.
+pub fn initiate<S: Read + Write>(
+    stream: &mut S,
+    info_hash: &[u8],
+    policy: EncryptionPolicy,
+    initial_payload: &[u8],
+) -> Result<Option<StreamCipher>, PeerError> {
+    initiate_with(stream, info_hash, policy.crypto_provide(), initial_payload)
+}
+
+/// `initiate` offering the `crypto_provide` methods
+fn initiate_with<S: Read + Write>(
+    stream: &mut S,
+    info_hash: &[u8],
+    crypto_provide: u32,
+    initial_payload: &[u8],
+) -> Result<Option<StreamCipher>, PeerError> {
+    let (private_key, public_key) = generate_keys();
+    stream.write_all(&public_key)?;
+    stream.write_all(&random_padding())?;
+
+    let mut peer_public_key = [0; KEY_LENGTH];
+    stream.read_exact(&mut peer_public_key)?;
+    let secret = shared_secret(&peer_public_key, &private_key);
+
+    let mut encrypt = rc4(b"keyA", &secret, info_hash);
+    let mut decrypt = rc4(b"keyB", &secret, info_hash);
+
+    let mut message = hash(&[b"req1", &secret]);
+    message.extend(
+        hash(&[b"req2", info_hash])
+            .iter()
+            .zip(hash(&[b"req3", &secret]))
+            .map(|(a, b)| a ^ b),
+    );
+    let mut encrypted = VC.to_vec();
+    encrypted.extend_from_slice(&crypto_provide.to_be_bytes());
+    // no padding, the initial payload is enough to make the handshake length vary
+    encrypted.extend_from_slice(&0u16.to_be_bytes());
+    encrypted.extend_from_slice(&(initial_payload.len() as u16).to_be_bytes());
+    encrypted.extend_from_slice(initial_payload);
+    encrypt.apply(&mut encrypted);
+    message.extend(encrypted);
+    stream.write_all(&message)?;
+
+    // peer's padding ends where encrypted VC starts
+    let mut encrypted_vc = VC;
+    decrypt.apply(&mut encrypted_vc);
+    synchronize(stream, &encrypted_vc)?;
+
+    let mut select = [0; 6];
+    stream.read_exact(&mut select)?;
+    decrypt.apply(&mut select);
+    let crypto_select = u32::from_be_bytes(select[..4].try_into().unwrap());
+    let padding_length = u16::from_be_bytes([select[4], select[5]]) as usize;
+    if padding_length > MAX_PADDING {
+        return Err(PeerError::Encryption("padding too long"));
+    }
+    let mut padding = vec![0; padding_length];
+    stream.read_exact(&mut padding)?;
+    decrypt.apply(&mut padding);
+
+    match crypto_select {
+        _ if crypto_select & crypto_provide == 0 => Err(PeerError::Encryption(
+            "peer selected method we didn't provide",
+        )),
+        CRYPTO_RC4 => Ok(Some(StreamCipher { encrypt, decrypt })),
+        CRYPTO_PLAINTEXT => Ok(None),
+        _ => Err(PeerError::Encryption("peer selected unsupported method")),
+    }
+}
+
+/// Runs the receiver side of the handshake, `received` are bytes already read from the
+/// stream. The initiator tells which of `info_hashes` it wants only in hashed form.
+pub fn accept<S: Read + Write>(
+    stream: &mut S,
+    received: &[u8],
+    info_hashes: &[[u8; 20]],
+    policy: EncryptionPolicy,
+) -> Result<Accepted, PeerError> {
+    let mut peer_public_key = [0; KEY_LENGTH];
+    peer_public_key[..received.len()].copy_from_slice(received);
+    stream.read_exact(&mut peer_public_key[received.len()..])?;
+
+    let (private_key, public_key) = generate_keys();
+    stream.write_all(&public_key)?;
+    stream.write_all(&random_padding())?;
+    let secret = shared_secret(&peer_public_key, &private_key);
+
+    // initiator's padding ends where the hash of the secret starts
+    synchronize(stream, &hash(&[b"req1", &secret]))?;
+
+    let mut hashes = [0; 20];
+    stream.read_exact(&mut hashes)?;
+    let req2: Vec<_> = hashes
+        .iter()
+        .zip(hash(&[b"req3", &secret]))
+        .map(|(a, b)| a ^ b)
+        .collect();
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", info_hash.as_slice()]) == req2)
+        .ok_or(PeerError::Encryption("unknown info hash"))?;
+
+    let mut encrypt = rc4(b"keyB", &secret, &info_hash);
+    let mut decrypt = rc4(b"keyA", &secret, &info_hash);
+
+    let mut header = [0; 14];
+    stream.read_exact(&mut header)?;
+    decrypt.apply(&mut header);
+    if header[..8] != VC {
+        return Err(PeerError::Encryption("invalid verification constant"));
+    }
+    let crypto_provide = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    let padding_length = u16::from_be_bytes([header[12], header[13]]) as usize;
+    if padding_length > MAX_PADDING {
+        return Err(PeerError::Encryption("padding too long"));
+    }
+
+    let mut padding = vec![0; padding_length + 2];
+    stream.read_exact(&mut padding)?;
+    decrypt.apply(&mut padding);
+    let payload_length =
+        u16::from_be_bytes([padding[padding_length], padding[padding_length + 1]]) as usize;
+    let mut initial_payload = vec![0; payload_length];
+    stream.read_exact(&mut initial_payload)?;
+    decrypt.apply(&mut initial_payload);
+
+    let crypto_select = if crypto_provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if crypto_provide & CRYPTO_PLAINTEXT != 0 && policy != EncryptionPolicy::Require {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(PeerError::Encryption("no acceptable method provided"));
+    };
+
+    let mut response = VC.to_vec();
+    response.extend_from_slice(&crypto_select.to_be_bytes());
+    response.extend_from_slice(&0u16.to_be_bytes());
+    encrypt.apply(&mut response);
+    stream.write_all(&response)?;
+
+    Ok(Accepted {
+        info_hash,
+        cipher: (crypto_select == CRYPTO_RC4).then_some(StreamCipher { encrypt, decrypt }),
+        initial_payload,
+    })
+}
+
+/// Skips padding until `marker` is found, it must start within `MAX_PADDING` bytes
+fn synchronize<S: Read>(stream: &mut S, marker: &[u8]) -> Result<(), PeerError> {
+    let mut window = Vec::with_capacity(MAX_PADDING + marker.len());
+    let mut byte = [0; 1];
+
+    while window.len() < MAX_PADDING + marker.len() {
+        stream.read_exact(&mut byte)?;
+        window.push(byte[0]);
+        if window.ends_with(marker) {
+            return Ok(());
+        }
+    }
+
+    Err(PeerError::Encryption("handshake not found after padding"))
+}
+
+/// Sent along our public key, so it comes from the OS CSPRNG like the private key
+fn random_padding() -> Vec<u8> {
+    secure_random_bytes(random_u32() as usize % (MAX_PADDING + 1))
+}
+
+fn hash(parts: &[&[u8]]) -> Vec<u8> {
+    sha1_it(&parts.concat())
+}
+
+fn rc4(name: &[u8], secret: &[u8], info_hash: &[u8]) -> Rc4 {
+    let mut rc4 = Rc4::new(&hash(&[name, secret, info_hash]));
+    rc4.apply(&mut [0; RC4_DISCARD]);
+    rc4
+}
+
+/// 160 bit private key is plenty for a 768 bit group
+fn generate_keys() -> (Vec<u8>, [u8; KEY_LENGTH]) {
+    let private_key = secure_random_bytes(20);
+    let public_key = BigUint::from(2).pow_mod(&private_key, &prime());
+    (private_key, public_key.to_be_bytes())
+}
+
+fn shared_secret(peer_public_key: &[u8; KEY_LENGTH], private_key: &[u8]) -> [u8; KEY_LENGTH] {
+    BigUint::from_be_bytes(peer_public_key)
+        .pow_mod(private_key, &prime())
+        .to_be_bytes()
+}
+
+fn prime() -> BigUint {
+    BigUint::from_be_bytes(&hex::decode(DH_PRIME).unwrap().try_into().unwrap())
+}
+
+const LIMBS: usize = KEY_LENGTH / 8;
+
+/// Just enough of a 768 bit unsigned integer for the key exchange, limbs are little endian
+#[derive(Clone, Copy, PartialEq)]
+struct BigUint([u64; LIMBS]);
+
+impl BigUint {
+    fn from(value: u64) -> Self {
+        let mut limbs = [0; LIMBS];
+        limbs[0] = value;
+        BigUint(limbs)
+    }
+
+    fn from_be_bytes(bytes: &[u8; KEY_LENGTH]) -> Self {
+        let mut limbs = [0; LIMBS];
+        for (i, chunk) in bytes.rchunks(8).enumerate() {
+            limbs[i] = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+        BigUint(limbs)
+    }
+
+    fn to_be_bytes(self) -> [u8; KEY_LENGTH] {
+        let mut bytes = [0; KEY_LENGTH];
+        for (i, chunk) in bytes.rchunks_mut(8).enumerate() {
+            chunk.copy_from_slice(&self.0[i].to_be_bytes());
+        }
+        bytes
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.0[index / 64] >> (index % 64) & 1 == 1
+    }
+
+    fn is_at_least(&self, other: &Self) -> bool {
+        for i in (0..LIMBS).rev() {
+            if self.0[i] != other.0[i] {
+                return self.0[i] > other.0[i];
+            }
+        }
+        true
+    }
+
+    /// Sum and whether it overflowed
+    fn add(&self, other: &Self) -> (Self, bool) {
+        let mut sum = [0; LIMBS];
+        let mut carry = false;
+        for (i, limb) in sum.iter_mut().enumerate() {
+            let (value, carry1) = self.0[i].overflowing_add(other.0[i]);
+            let (value, carry2) = value.overflowing_add(carry as u64);
+            *limb = value;
+            carry = carry1 || carry2;
+        }
+        (BigUint(sum), carry)
+    }
+
+    fn wrapping_sub(&self, other: &Self) -> Self {
+        let mut difference = [0; LIMBS];
+        let mut borrow = false;
+        for (i, limb) in difference.iter_mut().enumerate() {
+            let (value, borrow1) = self.0[i].overflowing_sub(other.0[i]);
+            let (value, borrow2) = value.overflowing_sub(borrow as u64);
+            *limb = value;
+            borrow = borrow1 || borrow2;
+        }
+        BigUint(difference)
+    }
+
+    /// Both operands must be smaller than `modulus`
+    fn add_mod(&self, other: &Self, modulus: &Self) -> Self {
+        let (sum, overflow) = self.add(other);
+        // the real sum is below 2 * modulus, one subtraction is enough
+        if overflow || sum.is_at_least(modulus) {
+            sum.wrapping_sub(modulus)
+        } else {
+            sum
+        }
+    }
+
+    /// Double and add, slow but short and we only need a few hundred of them
+    fn mul_mod(&self, other: &Self, modulus: &Self) -> Self {
+        let mut product = BigUint::from(0);
+        for index in (0..LIMBS * 64).rev() {
+            product = product.add_mod(&product, modulus);
+            if other.bit(index) {
+                product = product.add_mod(self, modulus);
+            }
+        }
+        product
+    }
+
+    fn pow_mod(&self, exponent: &[u8], modulus: &Self) -> Self {
+        let base = if self.is_at_least(modulus) {
+            self.wrapping_sub(modulus)
+        } else {
+            *self
+        };
+
+        let mut result = BigUint::from(1);
+        for byte in exponent {
+            for bit in (0..8).rev() {
+                result = result.mul_mod(&result, modulus);
+                if byte >> bit & 1 == 1 {
+                    result = result.mul_mod(&base, modulus);
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Shutdown, TcpListener, TcpStream},
+        thread,
+        time::Duration,
+    };
+
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    #[test]
+    fn rc4_known_answers() {
+        for (key, plaintext, ciphertext) in [
+            ("Key", "Plaintext", "bbf316e8d940af0ad3"),
+            ("Wiki", "pedia", "1021bf0420"),
+            ("Secret", "Attack at dawn", "45a01f645fc35b383552544b9bf5"),
+        ] {
+            let mut data = plaintext.as_bytes().to_vec();
+            Rc4::new(key.as_bytes()).apply(&mut data);
+            assert_eq!(hex::encode(data), ciphertext);
+        }
+    }
+
+    #[test]
+    fn pow_mod_known_values() {
+        let small = |value| BigUint::from(value);
+        assert!(small(3).pow_mod(&[5], &small(7)) == small(5));
+        assert!(small(2).pow_mod(&[1, 0], &small(1000)) == small(936));
+
+        // Fermat: 2^(p-1) = 1 mod p for the prime of the key exchange
+        let prime = prime();
+        let exponent = prime.wrapping_sub(&small(1)).to_be_bytes();
+        assert!(small(2).pow_mod(&exponent, &prime) == small(1));
+    }
+
+    #[test]
+    fn both_sides_derive_the_same_secret() {
+        let (private_a, public_a) = generate_keys();
+        let (private_b, public_b) = generate_keys();
+        assert_eq!(
+            shared_secret(&public_b, &private_a),
+            shared_secret(&public_a, &private_b)
+        );
+    }
+
+    /// Runs the initiator offering `crypto_provide` against a receiver with `policy`
+    /// over a local TCP connection, and returns both sides with their streams
+    fn handshake(
+        crypto_provide: u32,
+        policy: EncryptionPolicy,
+    ) -> (
+        Result<Option<StreamCipher>, PeerError>,
+        TcpStream,
+        Result<Accepted, PeerError>,
+        TcpStream,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let receiver = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let accepted = accept(&mut stream, &[], &[[1; 20], INFO_HASH], policy);
+            // like the peer server, a failed handshake closes the connection
+            if accepted.is_err() {
+                stream.shutdown(Shutdown::Both).unwrap();
+            }
+            (accepted, stream)
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let initiated = initiate_with(&mut stream, &INFO_HASH, crypto_provide, b"handshake");
+        let (accepted, accepted_stream) = receiver.join().unwrap();
+        (initiated, stream, accepted, accepted_stream)
+    }
+
+    #[test]
+    fn rc4_is_selected_when_offered() {
+        let (initiated, mut stream, accepted, mut accepted_stream) =
+            handshake(CRYPTO_RC4 | CRYPTO_PLAINTEXT, EncryptionPolicy::Prefer);
+        let mut cipher = initiated.unwrap().unwrap();
+        let accepted = accepted.unwrap();
+        assert_eq!(accepted.info_hash, INFO_HASH);
+        assert_eq!(accepted.initial_payload, b"handshake");
+        let mut accepted_cipher = accepted.cipher.unwrap();
+
+        let mut message = b"interested".to_vec();
+        cipher.encrypt.apply(&mut message);
+        stream.write_all(&message).unwrap();
+        let mut received = [0; 10];
+        accepted_stream.read_exact(&mut received).unwrap();
+        assert_ne!(&received, b"interested");
+        accepted_cipher.decrypt.apply(&mut received);
+        assert_eq!(&received, b"interested");
+
+        let mut message = b"unchoke".to_vec();
+        accepted_cipher.encrypt.apply(&mut message);
+        accepted_stream.write_all(&message).unwrap();
+        let mut received = [0; 7];
+        stream.read_exact(&mut received).unwrap();
+        cipher.decrypt.apply(&mut received);
+        assert_eq!(&received, b"unchoke");
+    }
+
+    #[test]
+    fn plaintext_is_selected_when_only_offer() {
+        let (initiated, _, accepted, _) = handshake(CRYPTO_PLAINTEXT, EncryptionPolicy::Prefer);
+        assert!(matches!(initiated, Ok(None)));
+        let accepted = accepted.unwrap();
+        assert!(accepted.cipher.is_none());
+        assert_eq!(accepted.initial_payload, b"handshake");
+    }
+
+    #[test]
+    fn require_rejects_plaintext() {
+        let (initiated, _, accepted, _) = handshake(CRYPTO_PLAINTEXT, EncryptionPolicy::Require);
+        assert!(matches!(
+            accepted.err(),
+            Some(PeerError::Encryption("no acceptable method provided"))
+        ));
+        assert!(initiated.is_err());
+    }
+
+    #[test]
+    fn required_encryption_offers_only_rc4() {
+        assert_eq!(EncryptionPolicy::Require.crypto_provide(), CRYPTO_RC4);
+        let (initiated, _, accepted, _) = handshake(
+            EncryptionPolicy::Require.crypto_provide(),
+            EncryptionPolicy::Prefer,
+        );
+        assert!(matches!(initiated, Ok(Some(_))));
+        assert!(accepted.unwrap().cipher.is_some());
+    }
+}
@@ -1,145 +1,803 @@
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
+    io::{self, ErrorKind, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpStream},
+    time::{Duration, Instant},
 };
 
+use thiserror::Error;
+
+use crate::{
+    mse::{self, EncryptionPolicy, StreamCipher},
+    sha1_it,
+};
+
+/// Longest message we accept, a block with its header or a bitfield of a huge torrent fit easily
+pub const MAX_MESSAGE_LENGTH: usize = 1024 * 1024;
+
+pub const PROTOCOL: &[u8; 20] = b"\x13BitTorrent protocol";
+
+/// Protocol string, reserved bytes, info hash and peer id
+pub const HANDSHAKE_LENGTH: usize = 68;
+
+/// Reserved bit announcing the extension protocol (BEP 10)
+const RESERVED_EXTENSION_BYTE: usize = 5;
+const RESERVED_EXTENSION_BIT: u8 = 0x10;
+
+/// Reserved bit announcing the fast extension (BEP 6), we always support it
+const RESERVED_FAST_BYTE: usize = 7;
+const RESERVED_FAST_BIT: u8 = 0x04;
+
+/// Pieces a peer may request while choked (BEP 6)
+pub const ALLOWED_FAST_SET_SIZE: usize = 10;
+
+/// Timeouts guarding against dead and stalled peers
+#[derive(Debug, Clone, Copy)]
+pub struct PeerTimeouts {
+    /// TCP connect
+    pub connect: Duration,
+    /// handshake and the rest of a message once its length arrived, also bounds writes
+    pub read: Duration,
+    /// peer which sent nothing, not even a keep-alive, for this long is dropped
+    pub idle: Duration,
+    /// keep-alive is sent when we didn't send anything for this long
+    pub keep_alive: Duration,
+}
+
+impl Default for PeerTimeouts {
+    fn default() -> Self {
+        PeerTimeouts {
+            connect: Duration::from_secs(10),
+            read: Duration::from_secs(30),
+            // peers send keep-alives every two minutes (BEP 3)
+            idle: Duration::from_secs(3 * 60),
+            keep_alive: Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PeerError {
+    #[error("failed to connect to peer: {0}")]
+    ConnectFailed(io::Error),
+    #[error("peer did not respond in time")]
+    Timeout,
+    #[error("peer handshake has unexpected {0}")]
+    HandshakeMismatch(&'static str),
+    #[error("peer violated protocol: {0}")]
+    ProtocolViolation(String),
+    #[error("peer sent {0} bytes long message")]
+    MessageTooLarge(usize),
+    #[error("peer disconnected")]
+    Disconnected,
+    #[error("peer encryption handshake failed: {0}")]
+    Encryption(&'static str),
+    #[error("peer rejected request for piece {0}")]
+    RequestRejected(u32),
+    #[error("peer has none of the pieces we need")]
+    MissingPieces,
+    #[error("peer io failed: {0}")]
+    Io(io::Error),
+}
+
+impl From<io::Error> for PeerError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => PeerError::Timeout,
+            ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe => PeerError::Disconnected,
+            _ => PeerError::Io(e),
+        }
+    }
+}
+
+impl From<MessageDecodeError> for PeerError {
+    fn from(e: MessageDecodeError) -> Self {
+        PeerError::ProtocolViolation(format!("{:?}", e))
+    }
+}
+
+/// First message in both directions, everything after it is length prefixed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handshake {
+    /// extension bits, unknown ones must be ignored
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(info_hash: &[u8], peer_id: &[u8; 20], extension_enabled: bool) -> Self {
+        let mut reserved = [0; 8];
+        reserved[RESERVED_FAST_BYTE] |= RESERVED_FAST_BIT;
+
+        let mut handshake = Handshake {
+            reserved,
+            info_hash: info_hash.try_into().expect("Info hash must be 20 bytes"),
+            peer_id: *peer_id,
+        };
+        if extension_enabled {
+            handshake.reserved[RESERVED_EXTENSION_BYTE] |= RESERVED_EXTENSION_BIT;
+        }
+        handshake
+    }
+
+    pub fn extension_enabled(&self) -> bool {
+        self.reserved[RESERVED_EXTENSION_BYTE] & RESERVED_EXTENSION_BIT != 0
+    }
+
+    /// Fast extension is used when both sides set the bit, we always do
+    pub fn fast_enabled(&self) -> bool {
+        self.reserved[RESERVED_FAST_BYTE] & RESERVED_FAST_BIT != 0
+    }
+
+    pub fn encode(&self) -> [u8; HANDSHAKE_LENGTH] {
+        let mut buf = [0; HANDSHAKE_LENGTH];
+        buf[..20].copy_from_slice(PROTOCOL);
+        buf[20..28].copy_from_slice(&self.reserved);
+        buf[28..48].copy_from_slice(&self.info_hash);
+        buf[48..].copy_from_slice(&self.peer_id);
+        buf
+    }
+
+    pub fn decode(buf: &[u8; HANDSHAKE_LENGTH]) -> Result<Self, PeerError> {
+        if &buf[..20] != PROTOCOL {
+            return Err(PeerError::HandshakeMismatch("protocol"));
+        }
+
+        Ok(Handshake {
+            reserved: buf[20..28].try_into().unwrap(),
+            info_hash: buf[28..48].try_into().unwrap(),
+            peer_id: buf[48..].try_into().unwrap(),
+        })
+    }
+}
+
 pub struct PeerConnection {
     pub tcp_stream: TcpStream,
     pub peer_id: String,
     pub extension_enabled: bool,
+    /// peer supports the fast extension (BEP 6)
+    pub fast_enabled: bool,
+    pub timeouts: PeerTimeouts,
+    /// RC4 streams when the connection is encrypted (MSE)
+    cipher: Option<StreamCipher>,
+    last_sent: Instant,
+    last_received: Instant,
 }
 
-pub struct Message {
-    pub payload: Vec<u8>,
-    pub message_type: MessageType,
+/// Message ids of the peer wire protocol (BEP 3), fast extension ones come from BEP 6
+/// and extended messages from BEP 10
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const NOT_INTERESTED: u8 = 3;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const CANCEL: u8 = 8;
+const PORT: u8 = 9;
+const SUGGEST_PIECE: u8 = 13;
+const HAVE_ALL: u8 = 14;
+const HAVE_NONE: u8 = 15;
+const REJECT_REQUEST: u8 = 16;
+const ALLOWED_FAST: u8 = 17;
+const EXTENDED: u8 = 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    /// zero length message keeping the connection open
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have {
+        index: u32,
+    },
+    /// one bit per piece, the highest bit of the first byte is piece 0
+    Bitfield(Vec<u8>),
+    Request {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// DHT port of the peer
+    Port(u16),
+    /// piece worth downloading from the sender, e.g. because it's in its cache
+    SuggestPiece {
+        index: u32,
+    },
+    /// replaces the bitfield of a seed
+    HaveAll,
+    /// replaces the bitfield of a peer without any piece
+    HaveNone,
+    /// request won't be answered, with the fast extension every request gets
+    /// either a piece or a reject
+    RejectRequest {
+        index: u32,
+        begin: u32,
+        length: u32,
+    },
+    /// piece which may be requested while choked
+    AllowedFast {
+        index: u32,
+    },
+    /// `id` is the extended message id, 0 for the extension handshake
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// messages of extensions we don't support are ignored, not treated as errors
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
 #[derive(Debug, PartialEq)]
-pub enum MessageType {
-    Unchoked = 1,
-    Interested = 2,
-    BitField = 5,
-    Request = 6,
-    Piece = 7,
-    Extended = 20,
+pub enum MessageDecodeError {
+    InvalidLength { id: u8, length: usize },
 }
 
-impl PeerConnection {
-    pub fn handshake(peer: &str, info_hash: &[u8], extension_enabled: bool) -> PeerConnection {
-        println!("Connection to peer {}", peer);
-        let mut stream = TcpStream::connect(peer).expect("Failed to connect to peer");
+impl Message {
+    /// Encodes message including its length prefix
+    pub fn encode(&self) -> Vec<u8> {
+        let (id, payload): (u8, Vec<u8>) = match self {
+            Message::KeepAlive => return vec![0; 4],
+            Message::Choke => (CHOKE, vec![]),
+            Message::Unchoke => (UNCHOKE, vec![]),
+            Message::Interested => (INTERESTED, vec![]),
+            Message::NotInterested => (NOT_INTERESTED, vec![]),
+            Message::Have { index } => (HAVE, index.to_be_bytes().to_vec()),
+            Message::Bitfield(bitfield) => (BITFIELD, bitfield.clone()),
+            Message::Request {
+                index,
+                begin,
+                length,
+            } => (REQUEST, encode_u32s(&[*index, *begin, *length])),
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } => {
+                let mut payload = encode_u32s(&[*index, *begin]);
+                payload.extend_from_slice(block);
+                (PIECE, payload)
+            }
+            Message::Cancel {
+                index,
+                begin,
+                length,
+            } => (CANCEL, encode_u32s(&[*index, *begin, *length])),
+            Message::Port(port) => (PORT, port.to_be_bytes().to_vec()),
+            Message::SuggestPiece { index } => (SUGGEST_PIECE, index.to_be_bytes().to_vec()),
+            Message::HaveAll => (HAVE_ALL, vec![]),
+            Message::HaveNone => (HAVE_NONE, vec![]),
+            Message::RejectRequest {
+                index,
+                begin,
+                length,
+            } => (REJECT_REQUEST, encode_u32s(&[*index, *begin, *length])),
+            Message::AllowedFast { index } => (ALLOWED_FAST, index.to_be_bytes().to_vec()),
+            Message::Extended { id, payload } => {
+                let mut extended_payload = vec![*id; 1];
+                extended_payload.extend_from_slice(payload);
+                (EXTENDED, extended_payload)
+            }
+            Message::Unknown { id, payload } => (*id, payload.clone()),
+        };
 
-        let mut payload = Vec::with_capacity(68); // 28 + 20 + 20
+        let mut message = Vec::with_capacity(5 + payload.len());
+        message.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+        message.push(id);
+        message.extend(payload);
+        message
+    }
 
-        let magic_bytes = if extension_enabled {
-            b"BitTorrent protocol\x00\x00\x00\x00\x00\x10\x00\x00"
-        } else {
-            b"BitTorrent protocol\x00\x00\x00\x00\x00\x00\x00\x00"
+    /// Decodes message from everything after the length prefix
+    pub fn decode(frame: &[u8]) -> Result<Self, MessageDecodeError> {
+        let Some((&id, payload)) = frame.split_first() else {
+            return Ok(Message::KeepAlive);
         };
 
-        payload.push(19); // 1 byte
-        payload.extend_from_slice(magic_bytes); // 27 bytes
-        payload.extend_from_slice(info_hash); // 20 bytes
-        payload.extend_from_slice(b"00112233445566778899"); // 20 bytes
+        let expect_length = |length: usize| {
+            if payload.len() == length {
+                Ok(())
+            } else {
+                Err(MessageDecodeError::InvalidLength {
+                    id,
+                    length: payload.len(),
+                })
+            }
+        };
 
-        stream
-            .write_all(&payload)
-            .expect("Failed to write to tcp stream");
+        Ok(match id {
+            CHOKE => expect_length(0).map(|_| Message::Choke)?,
+            UNCHOKE => expect_length(0).map(|_| Message::Unchoke)?,
+            INTERESTED => expect_length(0).map(|_| Message::Interested)?,
+            NOT_INTERESTED => expect_length(0).map(|_| Message::NotInterested)?,
+            HAVE | SUGGEST_PIECE | ALLOWED_FAST => {
+                expect_length(4)?;
+                let index = read_u32(payload, 0);
+                match id {
+                    HAVE => Message::Have { index },
+                    SUGGEST_PIECE => Message::SuggestPiece { index },
+                    _ => Message::AllowedFast { index },
+                }
+            }
+            HAVE_ALL => expect_length(0).map(|_| Message::HaveAll)?,
+            HAVE_NONE => expect_length(0).map(|_| Message::HaveNone)?,
+            BITFIELD => Message::Bitfield(payload.to_vec()),
+            REQUEST | CANCEL | REJECT_REQUEST => {
+                expect_length(12)?;
+                let (index, begin, length) = (
+                    read_u32(payload, 0),
+                    read_u32(payload, 4),
+                    read_u32(payload, 8),
+                );
+                match id {
+                    REQUEST => Message::Request {
+                        index,
+                        begin,
+                        length,
+                    },
+                    CANCEL => Message::Cancel {
+                        index,
+                        begin,
+                        length,
+                    },
+                    _ => Message::RejectRequest {
+                        index,
+                        begin,
+                        length,
+                    },
+                }
+            }
+            PIECE => {
+                if payload.len() < 8 {
+                    return Err(MessageDecodeError::InvalidLength {
+                        id,
+                        length: payload.len(),
+                    });
+                }
+                Message::Piece {
+                    index: read_u32(payload, 0),
+                    begin: read_u32(payload, 4),
+                    block: payload[8..].to_vec(),
+                }
+            }
+            PORT => {
+                expect_length(2)?;
+                Message::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            EXTENDED => match payload.split_first() {
+                Some((&extended_id, extended_payload)) => Message::Extended {
+                    id: extended_id,
+                    payload: extended_payload.to_vec(),
+                },
+                None => {
+                    return Err(MessageDecodeError::InvalidLength { id, length: 0 });
+                }
+            },
+            _ => Message::Unknown {
+                id,
+                payload: payload.to_vec(),
+            },
+        })
+    }
+}
 
-        let mut return_message_buf: [u8; 68] = [0; 68];
-        stream
-            .read_exact(&mut return_message_buf)
-            .expect("Failed to read peer handshake response");
+impl PeerConnection {
+    /// Connects and exchanges handshakes, `encryption` decides whether the encrypted
+    /// handshake is tried first
+    pub fn handshake(
+        peer: &SocketAddr,
+        info_hash: &[u8],
+        peer_id: &[u8; 20],
+        extension_enabled: bool,
+        timeouts: PeerTimeouts,
+        encryption: EncryptionPolicy,
+    ) -> Result<PeerConnection, PeerError> {
+        let exchange_handshakes = |stream: TcpStream, encrypted: bool| {
+            Self::exchange_handshakes(
+                stream,
+                info_hash,
+                peer_id,
+                extension_enabled,
+                timeouts,
+                encrypted.then_some(encryption),
+            )
+        };
 
-        // let received_magic_bytes = &return_message_buf[1..28];
-        // assert_eq!(received_magic_bytes, magic_bytes);
+        // a peer we can't connect to at all is not retried in plaintext
+        let stream = Self::open(peer, timeouts)?;
+        match encryption {
+            EncryptionPolicy::Disable => exchange_handshakes(stream, false),
+            EncryptionPolicy::Require => exchange_handshakes(stream, true),
+            // peers not speaking MSE usually just drop the connection, try again in plaintext
+            EncryptionPolicy::Prefer => match exchange_handshakes(stream, true) {
+                Err(e) => {
+                    println!("Encrypted handshake with {} failed: {}", peer, e);
+                    exchange_handshakes(Self::open(peer, timeouts)?, false)
+                }
+                connection => connection,
+            },
+        }
+    }
 
-        let info_hash_received = &return_message_buf[28..48];
-        assert_eq!(info_hash_received, info_hash);
+    fn open(peer: &SocketAddr, timeouts: PeerTimeouts) -> Result<TcpStream, PeerError> {
+        println!("Connection to peer {}", peer);
+        let stream =
+            TcpStream::connect_timeout(peer, timeouts.connect).map_err(|e| match e.kind() {
+                ErrorKind::TimedOut | ErrorKind::WouldBlock => PeerError::Timeout,
+                _ => PeerError::ConnectFailed(e),
+            })?;
+        stream.set_read_timeout(Some(timeouts.read))?;
+        stream.set_write_timeout(Some(timeouts.read))?;
+        Ok(stream)
+    }
 
-        let peer_id = hex::encode(&return_message_buf[48..68]);
+    fn exchange_handshakes(
+        mut stream: TcpStream,
+        info_hash: &[u8],
+        peer_id: &[u8; 20],
+        extension_enabled: bool,
+        timeouts: PeerTimeouts,
+        encryption: Option<EncryptionPolicy>,
+    ) -> Result<PeerConnection, PeerError> {
+        let handshake = Handshake::new(info_hash, peer_id, extension_enabled).encode();
+        let mut cipher = match encryption {
+            // our handshake goes along the encrypted one
+            Some(policy) => mse::initiate(&mut stream, info_hash, policy, &handshake)?,
+            None => {
+                stream.write_all(&handshake)?;
+                None
+            }
+        };
+
+        let mut buf = [0; HANDSHAKE_LENGTH];
+        stream.read_exact(&mut buf)?;
+        if let Some(cipher) = &mut cipher {
+            cipher.decrypt.apply(&mut buf);
+        }
+        let handshake = Handshake::decode(&buf)?;
+        if handshake.info_hash != info_hash {
+            return Err(PeerError::HandshakeMismatch("info hash"));
+        }
 
-        PeerConnection {
+        let now = Instant::now();
+        Ok(PeerConnection {
             tcp_stream: stream,
-            peer_id,
-            extension_enabled: return_message_buf[25] == 16,
+            peer_id: hex::encode(handshake.peer_id),
+            extension_enabled: handshake.extension_enabled(),
+            fast_enabled: handshake.fast_enabled(),
+            timeouts,
+            cipher,
+            last_sent: now,
+            last_received: now,
+        })
+    }
+
+    /// Whether messages are RC4 encrypted
+    pub fn encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    pub fn send_message(&mut self, message: &Message) -> Result<(), PeerError> {
+        let mut buf = message.encode();
+        if let Some(cipher) = &mut self.cipher {
+            cipher.encrypt.apply(&mut buf);
         }
+        self.tcp_stream.write_all(&buf)?;
+        self.last_sent = Instant::now();
+        Ok(())
     }
 
-    pub fn send_message(&mut self, message_type: MessageType, payload: Vec<u8>) {
-        let payload_len: u32 = payload.len() as u32 + 1;
+    /// Waits for the next message, sending keep-alives meanwhile. Fails with
+    /// `PeerError::Timeout` when the peer is idle for too long or stalls mid message.
+    pub fn read_message(&mut self) -> Result<Message, PeerError> {
+        let mut length_buf: [u8; 4] = [0; 4];
+        let mut filled = 0;
 
-        let mut message_payload: Vec<u8> = Vec::with_capacity(4 + payload_len as usize);
+        while filled < length_buf.len() {
+            if self.last_sent.elapsed() >= self.timeouts.keep_alive {
+                self.send_message(&Message::KeepAlive)?;
+            }
 
-        message_payload.extend_from_slice(&payload_len.to_be_bytes());
-        message_payload.push(message_type as u8);
-        message_payload.extend(payload);
+            let idle_left = self
+                .timeouts
+                .idle
+                .checked_sub(self.last_received.elapsed())
+                .ok_or(PeerError::Timeout)?;
+            let wait = if filled > 0 {
+                self.timeouts.read
+            } else {
+                let keep_alive_left = self
+                    .timeouts
+                    .keep_alive
+                    .saturating_sub(self.last_sent.elapsed());
+                idle_left.min(keep_alive_left)
+            };
+            // zero duration is rejected by set_read_timeout
+            self.tcp_stream
+                .set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
 
-        self.tcp_stream
-            .write_all(&message_payload)
-            .expect("Failed to write to tcp stream");
-    }
+            match self.tcp_stream.read(&mut length_buf[filled..]) {
+                Ok(0) => return Err(PeerError::Disconnected),
+                Ok(read) => {
+                    if let Some(cipher) = &mut self.cipher {
+                        cipher.decrypt.apply(&mut length_buf[filled..filled + read]);
+                    }
+                    filled += read
+                }
+                // checked against the deadlines above, unless the message already started
+                Err(e)
+                    if filled == 0
+                        && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
 
-    pub fn read_message(&mut self) -> Message {
-        let mut payload_size_buf: [u8; 4] = [0; 4];
-        self.tcp_stream
-            .read_exact(&mut payload_size_buf)
-            .expect("failed to read message size");
+        let length = message_length(length_buf)?;
 
-        let mut message_id_buf: [u8; 1] = [0; 1];
-        self.tcp_stream
-            .read_exact(&mut message_id_buf)
-            .expect("Failed to read message id");
+        self.tcp_stream.set_read_timeout(Some(self.timeouts.read))?;
+        let mut frame = vec![0; length];
+        self.tcp_stream.read_exact(&mut frame)?;
+        if let Some(cipher) = &mut self.cipher {
+            cipher.decrypt.apply(&mut frame);
+        }
+        self.last_received = Instant::now();
 
-        let message_type = match message_id_buf[0] {
-            1 => MessageType::Unchoked,
-            5 => MessageType::BitField,
-            7 => MessageType::Piece,
-            20 => MessageType::Extended,
-            id => panic!("Unknown message type {}", id),
-        };
+        Ok(Message::decode(&frame)?)
+    }
+}
 
-        let message = match message_type {
-            MessageType::Extended => {
-                let mut extended_message_id_buf: [u8; 1] = [0; 1];
-                self.tcp_stream
-                    .read_exact(&mut extended_message_id_buf)
-                    .expect("Failed to read extended message id");
-
-                let payload_size = match u32::from_be_bytes(payload_size_buf) {
-                    x if x == 0 => 0 as usize,
-                    x => (x - 2) as usize,
-                };
-
-                let mut payload = vec![0; payload_size];
-                self.tcp_stream
-                    .read_exact(&mut payload)
-                    .expect("Failed to read buffer");
-
-                Message {
-                    payload,
-                    message_type,
-                }
+/// Pieces the peer at `ip` may request while choked, the same on both sides of the
+/// connection (BEP 6). Only defined for IPv4, peers in the same /24 share the set.
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8], pieces_count: usize) -> Vec<u32> {
+    let size = ALLOWED_FAST_SET_SIZE.min(pieces_count);
+    let mut allowed_fast = Vec::with_capacity(size);
+
+    let mut x = (u32::from(ip) & 0xFFFFFF00).to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+
+    while allowed_fast.len() < size {
+        x = sha1_it(&x);
+        for y in x.chunks(4) {
+            let index = read_u32(y, 0) % pieces_count as u32;
+            if allowed_fast.len() < size && !allowed_fast.contains(&index) {
+                allowed_fast.push(index);
             }
-            MessageType::BitField | MessageType::Unchoked | MessageType::Piece => {
-                let payload_size = match u32::from_be_bytes(payload_size_buf) {
-                    x if x == 0 => 0 as usize,
-                    x => (x - 1) as usize,
-                };
-
-                let mut payload = vec![0; payload_size];
-                self.tcp_stream
-                    .read_exact(&mut payload)
-                    .expect("Failed to read buffer");
-
-                Message {
-                    payload,
-                    message_type,
-                }
+        }
+    }
+
+    allowed_fast
+}
+
+/// Length from the message prefix, checked against `MAX_MESSAGE_LENGTH`
+pub fn message_length(prefix: [u8; 4]) -> Result<usize, PeerError> {
+    let length = u32::from_be_bytes(prefix) as usize;
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(PeerError::MessageTooLarge(length));
+    }
+    Ok(length)
+}
+
+fn encode_u32s(values: &[u32]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .collect()
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = *b"peer-connection-test";
+
+    /// Connection to a local stand-in peer after the plaintext handshake, with the
+    /// peer's end of it
+    fn loopback(timeouts: PeerTimeouts) -> (PeerConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let remote = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut handshake = [0; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut handshake).unwrap();
+            stream
+                .write_all(&Handshake::new(&INFO_HASH, &[2; 20], false).encode())
+                .unwrap();
+            stream
+        });
+
+        let connection = PeerConnection::handshake(
+            &address,
+            &INFO_HASH,
+            &[1; 20],
+            false,
+            timeouts,
+            EncryptionPolicy::Disable,
+        )
+        .unwrap();
+        (connection, remote.join().unwrap())
+    }
+
+    fn round_trip(message: Message) {
+        let encoded = message.encode();
+        let length = message_length(encoded[..4].try_into().unwrap()).unwrap();
+        assert_eq!(length, encoded.len() - 4, "{:?}", message);
+        assert_eq!(Message::decode(&encoded[4..]), Ok(message));
+    }
+
+    #[test]
+    fn every_message_round_trips() {
+        let messages = [
+            Message::KeepAlive,
+            Message::Choke,
+            Message::Unchoke,
+            Message::Interested,
+            Message::NotInterested,
+            Message::Have { index: 7 },
+            Message::Bitfield(vec![0b1010_0000, 0xFF]),
+            Message::Request {
+                index: 1,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::Piece {
+                index: 2,
+                begin: 0,
+                block: vec![1, 2, 3],
+            },
+            Message::Cancel {
+                index: 3,
+                begin: 32768,
+                length: 100,
+            },
+            Message::Port(6881),
+            Message::SuggestPiece { index: 4 },
+            Message::HaveAll,
+            Message::HaveNone,
+            Message::RejectRequest {
+                index: 5,
+                begin: 16384,
+                length: 16384,
+            },
+            Message::AllowedFast { index: 6 },
+            Message::Extended {
+                id: 0,
+                payload: b"d1:md6:ut_pexi1eee".to_vec(),
+            },
+            Message::Extended {
+                id: 3,
+                payload: vec![],
+            },
+            Message::Unknown {
+                id: 42,
+                payload: vec![9, 9],
+            },
+        ];
+
+        for message in messages {
+            round_trip(message);
+        }
+    }
+
+    #[test]
+    fn messages_are_encoded_as_on_the_wire() {
+        assert_eq!(Message::KeepAlive.encode(), [0, 0, 0, 0]);
+        assert_eq!(Message::HaveAll.encode(), [0, 0, 0, 1, HAVE_ALL]);
+        assert_eq!(
+            Message::AllowedFast { index: 258 }.encode(),
+            [0, 0, 0, 5, ALLOWED_FAST, 0, 0, 1, 2]
+        );
+        assert_eq!(
+            Message::Extended {
+                id: 2,
+                payload: vec![b'd', b'e'],
             }
-            _ => panic!("Don't know how to handle message type {:?}", message_type),
+            .encode(),
+            [0, 0, 0, 4, EXTENDED, 2, b'd', b'e']
+        );
+    }
+
+    #[test]
+    fn wrong_payload_lengths_are_rejected() {
+        let invalid = [
+            (vec![CHOKE, 0], 1),
+            (vec![HAVE_NONE, 0, 0], 2),
+            (vec![HAVE, 0, 0, 0], 3),
+            (vec![SUGGEST_PIECE, 0, 0, 0, 0, 0], 5),
+            (vec![ALLOWED_FAST], 0),
+            (vec![REQUEST; 12], 11),
+            (vec![REJECT_REQUEST; 14], 13),
+            (vec![PORT, 0], 1),
+            (vec![PIECE, 0, 0, 0, 0, 0, 0, 0], 7),
+            (vec![EXTENDED], 0),
+        ];
+
+        for (frame, length) in invalid {
+            assert_eq!(
+                Message::decode(&frame),
+                Err(MessageDecodeError::InvalidLength {
+                    id: frame[0],
+                    length
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn lengths_above_the_maximum_are_rejected() {
+        let max = (MAX_MESSAGE_LENGTH as u32).to_be_bytes();
+        assert_eq!(message_length(max).unwrap(), MAX_MESSAGE_LENGTH);
+
+        let above = (MAX_MESSAGE_LENGTH as u32 + 1).to_be_bytes();
+        assert!(matches!(
+            message_length(above),
+            Err(PeerError::MessageTooLarge(length)) if length == MAX_MESSAGE_LENGTH + 1
+        ));
+        assert!(matches!(
+            message_length([0xFF; 4]),
+            Err(PeerError::MessageTooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn silent_peer_times_out() {
+        let timeouts = PeerTimeouts {
+            idle: Duration::from_millis(200),
+            ..PeerTimeouts::default()
         };
+        let (mut connection, _remote) = loopback(timeouts);
 
-        message
+        let started = Instant::now();
+        assert!(matches!(connection.read_message(), Err(PeerError::Timeout)));
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn idle_writer_sends_keep_alives() {
+        let timeouts = PeerTimeouts {
+            idle: Duration::from_secs(5),
+            keep_alive: Duration::from_millis(50),
+            ..PeerTimeouts::default()
+        };
+        let (mut connection, mut remote) = loopback(timeouts);
+
+        let remote = thread::spawn(move || {
+            remote
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let mut keep_alive = [0xFF; 4];
+            remote.read_exact(&mut keep_alive).unwrap();
+            remote.write_all(&Message::Unchoke.encode()).unwrap();
+            keep_alive
+        });
+
+        assert_eq!(connection.read_message().unwrap(), Message::Unchoke);
+        assert_eq!(remote.join().unwrap(), [0; 4]);
     }
 }
@@ -0,0 +1,387 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Wait before connecting again to a peer which failed, doubled with every failure
+pub const RETRY_BACKOFF: Duration = Duration::from_secs(15);
+
+/// Failures in a row after which a peer is forgotten until a peer source adds it again
+pub const MAX_FAILURES: u32 = 5;
+
+/// Where we learned about a peer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerSource {
+    Tracker,
+    Dht,
+    Pex,
+    Lsd,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate {
+    pub source: PeerSource,
+    /// BEP 11 flags, as far as we know them
+    pub flags: u8,
+}
+
+/// What we told a peer through ut_pex, kept across connections to it
+#[derive(Debug, Default)]
+pub struct PexState {
+    pub last_sent: Option<Instant>,
+    /// peers the remote peer already knows from us
+    pub advertised: HashSet<SocketAddr>,
+}
+
+/// Peers of a single torrent we can connect to, shared by every peer source and the download
+#[derive(Debug, Default)]
+pub struct PeerPool {
+    state: Mutex<PoolState>,
+    /// notified when peers are added or become available again
+    candidates_changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct PoolState {
+    candidates: HashMap<SocketAddr, Candidate>,
+    /// insertion order, so candidates are tried in turns
+    order: Vec<SocketAddr>,
+    next: usize,
+    connected: HashSet<SocketAddr>,
+    /// misbehaving peers, never handed out or added again
+    banned: HashSet<SocketAddr>,
+    /// peers which failed, not handed out before their retry time
+    backoffs: HashMap<SocketAddr, Backoff>,
+    pex: HashMap<SocketAddr, PexState>,
+    /// when a connection to the peer last went fine
+    last_good: HashMap<SocketAddr, Instant>,
+}
+
+#[derive(Debug)]
+struct Backoff {
+    failures: u32,
+    retry_at: Instant,
+}
+
+impl PoolState {
+    fn take_candidate(&mut self, now: Instant) -> Option<SocketAddr> {
+        for _ in 0..self.order.len() {
+            let peer = self.order[self.next % self.order.len()];
+            self.next = (self.next + 1) % self.order.len();
+
+            let backing_off = self
+                .backoffs
+                .get(&peer)
+                .is_some_and(|backoff| backoff.retry_at > now);
+            if !backing_off && self.connected.insert(peer) {
+                return Some(peer);
+            }
+        }
+
+        None
+    }
+
+    fn forget(&mut self, peer: &SocketAddr) {
+        self.connected.remove(peer);
+        self.candidates.remove(peer);
+        self.order.retain(|candidate| candidate != peer);
+        self.backoffs.remove(peer);
+        self.pex.remove(peer);
+        self.last_good.remove(peer);
+    }
+
+    fn retry_later(&mut self, peer: &SocketAddr, now: Instant) {
+        self.connected.remove(peer);
+        self.last_good.remove(peer);
+
+        let failures = self
+            .backoffs
+            .get(peer)
+            .map_or(0, |backoff| backoff.failures)
+            + 1;
+        if failures >= MAX_FAILURES {
+            self.forget(peer);
+        } else {
+            let retry_at = now + RETRY_BACKOFF * 2u32.pow(failures - 1);
+            self.backoffs.insert(*peer, Backoff { failures, retry_at });
+        }
+    }
+}
+
+impl PeerPool {
+    pub fn new() -> Self {
+        PeerPool::default()
+    }
+
+    /// Adds peers we don't know yet, returns how many of them were new
+    pub fn add(&self, peers: impl IntoIterator<Item = SocketAddr>, source: PeerSource) -> usize {
+        self.add_with_flags(peers.into_iter().map(|peer| (peer, 0)), source)
+    }
+
+    pub fn add_with_flags(
+        &self,
+        peers: impl IntoIterator<Item = (SocketAddr, u8)>,
+        source: PeerSource,
+    ) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let mut added = 0;
+
+        for (peer, flags) in peers {
+            if state.candidates.contains_key(&peer) || state.banned.contains(&peer) {
+                continue;
+            }
+            state.candidates.insert(peer, Candidate { source, flags });
+            state.order.push(peer);
+            added += 1;
+        }
+
+        if added > 0 {
+            self.candidates_changed.notify_all();
+        }
+        added
+    }
+
+    /// Blocks until the pool has at least one peer, returns false on timeout
+    pub fn wait_for_peers(&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .candidates_changed
+            .wait_timeout_while(state, timeout, |state| state.order.is_empty())
+            .unwrap();
+        !state.order.is_empty()
+    }
+
+    /// Next candidate we are not connected to, candidates are handed out round robin.
+    /// The candidate is marked as connected.
+    pub fn next_candidate(&self) -> Option<SocketAddr> {
+        self.state.lock().unwrap().take_candidate(Instant::now())
+    }
+
+    /// Like `next_candidate`, but waits for peers to be added, disconnected or to come
+    /// out of their backoff. Returns `None` when there was no candidate for `timeout`.
+    pub fn wait_for_candidate(&self, timeout: Duration) -> Option<SocketAddr> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if let Some(peer) = state.take_candidate(Instant::now()) {
+                return Some(peer);
+            }
+
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            let wake_at = state
+                .backoffs
+                .values()
+                .map(|backoff| backoff.retry_at)
+                .filter(|retry_at| *retry_at > now)
+                .fold(deadline, Instant::min);
+            state = self
+                .candidates_changed
+                .wait_timeout(state, wake_at - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    pub fn connected(&self, peer: SocketAddr) {
+        self.state.lock().unwrap().connected.insert(peer);
+    }
+
+    /// Peer is available again after a connection which went fine
+    pub fn disconnected(&self, peer: &SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.connected.remove(peer);
+        state.backoffs.remove(peer);
+        state.last_good.insert(*peer, Instant::now());
+        self.candidates_changed.notify_all();
+    }
+
+    /// Peer failed without misbehaving, e.g. it was unreachable or had nothing for us.
+    /// It is handed out again after a backoff, and forgotten after `MAX_FAILURES`.
+    pub fn retry_later(&self, peer: &SocketAddr) {
+        self.state.lock().unwrap().retry_later(peer, Instant::now());
+        self.candidates_changed.notify_all();
+    }
+
+    /// Forgets a peer which misbehaved, it won't be handed out or added again
+    pub fn ban(&self, peer: &SocketAddr) {
+        let mut state = self.state.lock().unwrap();
+        state.forget(peer);
+        state.banned.insert(*peer);
+    }
+
+    /// Runs `update` on the ut_pex state of a peer, so rate limit and advertised peers
+    /// carry over to the next connection
+    pub fn update_pex_state<T>(
+        &self,
+        peer: SocketAddr,
+        update: impl FnOnce(&mut PexState) -> T,
+    ) -> T {
+        update(self.state.lock().unwrap().pex.entry(peer).or_default())
+    }
+
+    /// Peers a connection went fine with during the last `max_age`, with their flags
+    pub fn recently_good_peers(&self, max_age: Duration) -> Vec<(SocketAddr, u8)> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .last_good
+            .retain(|_, last_good| last_good.elapsed() < max_age);
+        state
+            .last_good
+            .keys()
+            .map(|peer| {
+                let flags = state
+                    .candidates
+                    .get(peer)
+                    .map_or(0, |candidate| candidate.flags);
+                (*peer, flags)
+            })
+            .collect()
+    }
+
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.state.lock().unwrap().order.clone()
+    }
+
+    pub fn candidate(&self, peer: &SocketAddr) -> Option<Candidate> {
+        self.state.lock().unwrap().candidates.get(peer).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+    }
+
+    fn pool_of(peers: &[SocketAddr]) -> PoolState {
+        let pool = PeerPool::new();
+        pool.add(peers.iter().copied(), PeerSource::Tracker);
+        pool.state.into_inner().unwrap()
+    }
+
+    #[test]
+    fn candidates_are_handed_out_round_robin_once() {
+        let pool = PeerPool::new();
+        assert_eq!(
+            pool.add([peer(1), peer(2), peer(1)], PeerSource::Tracker),
+            2
+        );
+        assert_eq!(pool.add([peer(2)], PeerSource::Dht), 0);
+
+        assert_eq!(pool.next_candidate(), Some(peer(1)));
+        assert_eq!(pool.next_candidate(), Some(peer(2)));
+        assert_eq!(pool.next_candidate(), None);
+
+        pool.disconnected(&peer(1));
+        assert_eq!(pool.next_candidate(), Some(peer(1)));
+    }
+
+    #[test]
+    fn backoff_doubles_with_every_failure() {
+        let start = Instant::now();
+        let mut state = pool_of(&[peer(1)]);
+
+        for failures in 1..MAX_FAILURES {
+            let now = start + RETRY_BACKOFF * 100 * failures;
+            assert_eq!(state.take_candidate(now), Some(peer(1)));
+            state.retry_later(&peer(1), now);
+
+            let backoff = RETRY_BACKOFF * 2u32.pow(failures - 1);
+            assert_eq!(state.backoffs[&peer(1)].failures, failures);
+            assert_eq!(state.backoffs[&peer(1)].retry_at, now + backoff);
+            assert_eq!(state.take_candidate(now), None);
+            assert_eq!(
+                state.take_candidate(now + backoff - Duration::from_millis(1)),
+                None
+            );
+            assert_eq!(state.take_candidate(now + backoff), Some(peer(1)));
+            state.connected.remove(&peer(1));
+        }
+    }
+
+    #[test]
+    fn failed_peers_wait_while_others_are_handed_out() {
+        let pool = PeerPool::new();
+        pool.add([peer(1), peer(2)], PeerSource::Tracker);
+        assert_eq!(pool.next_candidate(), Some(peer(1)));
+        pool.retry_later(&peer(1));
+
+        assert_eq!(pool.next_candidate(), Some(peer(2)));
+        assert_eq!(pool.next_candidate(), None);
+        assert_eq!(pool.wait_for_candidate(Duration::from_millis(50)), None);
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn peers_are_forgotten_after_max_failures() {
+        let start = Instant::now();
+        let mut state = pool_of(&[peer(1), peer(2)]);
+
+        for failures in 1..=MAX_FAILURES {
+            let now = start + RETRY_BACKOFF * 100 * failures;
+            state.retry_later(&peer(1), now);
+            let forgotten = !state.candidates.contains_key(&peer(1));
+            assert_eq!(forgotten, failures == MAX_FAILURES);
+        }
+
+        assert_eq!(state.order, vec![peer(2)]);
+        assert!(state.backoffs.is_empty());
+
+        // a peer source may bring it back, with a clean slate
+        let pool = PeerPool {
+            state: Mutex::new(state),
+            ..Default::default()
+        };
+        assert_eq!(pool.add([peer(1)], PeerSource::Pex), 1);
+        assert_eq!(pool.candidate(&peer(1)).unwrap().source, PeerSource::Pex);
+    }
+
+    #[test]
+    fn a_good_connection_resets_the_backoff() {
+        let pool = PeerPool::new();
+        pool.add([peer(1)], PeerSource::Tracker);
+        pool.retry_later(&peer(1));
+        assert_eq!(pool.next_candidate(), None);
+
+        pool.disconnected(&peer(1));
+        assert_eq!(pool.next_candidate(), Some(peer(1)));
+        assert_eq!(
+            pool.recently_good_peers(Duration::from_secs(60)),
+            vec![(peer(1), 0)]
+        );
+    }
+
+    #[test]
+    fn banned_peers_are_never_handed_out_or_added_again() {
+        let pool = PeerPool::new();
+        pool.add([peer(1), peer(2)], PeerSource::Tracker);
+        assert_eq!(pool.next_candidate(), Some(peer(1)));
+        pool.disconnected(&peer(1));
+        pool.ban(&peer(1));
+
+        assert_eq!(pool.peers(), vec![peer(2)]);
+        assert!(pool.candidate(&peer(1)).is_none());
+        assert!(pool.recently_good_peers(Duration::from_secs(60)).is_empty());
+        assert_eq!(pool.add([peer(1)], PeerSource::Dht), 0);
+        assert_eq!(pool.next_candidate(), Some(peer(2)));
+        assert_eq!(pool.next_candidate(), None);
+    }
+}
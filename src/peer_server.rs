@@ -0,0 +1,265 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    net,
+    sync::{Arc, Mutex},
+};
+
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    task::{self, JoinHandle},
+    time,
+};
+
+use crate::{
+    async_peer_connection::AsyncPeerConnection,
+    client_config::ClientConfig,
+    mse::{self, EncryptionPolicy, StreamCipher},
+    peer_connection::{Handshake, PeerError, PeerTimeouts, HANDSHAKE_LENGTH, PROTOCOL},
+};
+
+/// Incoming connections of all torrents together
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+
+pub const DEFAULT_MAX_CONNECTIONS_PER_TORRENT: usize = 50;
+
+#[derive(Debug, Clone)]
+pub struct PeerServerConfig {
+    pub max_connections: usize,
+    pub max_connections_per_torrent: usize,
+}
+
+impl Default for PeerServerConfig {
+    fn default() -> Self {
+        PeerServerConfig {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_torrent: DEFAULT_MAX_CONNECTIONS_PER_TORRENT,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PeerServerError {
+    #[error("torrent is not served")]
+    UnknownInfoHash,
+    #[error("too many connections")]
+    TooManyConnections,
+    #[error(transparent)]
+    Peer(#[from] PeerError),
+}
+
+/// Connection accepted for a torrent, its slot is freed when the peer is dropped
+pub struct IncomingPeer {
+    pub connection: AsyncPeerConnection,
+    _permits: (OwnedSemaphorePermit, OwnedSemaphorePermit),
+}
+
+struct ServedTorrent {
+    connections: Arc<Semaphore>,
+    incoming: mpsc::Sender<IncomingPeer>,
+}
+
+/// Accepts peer connections on our announced port and hands them to torrents by info hash
+pub struct PeerServer {
+    peer_id: [u8; 20],
+    timeouts: PeerTimeouts,
+    encryption: EncryptionPolicy,
+    config: PeerServerConfig,
+    connections: Arc<Semaphore>,
+    torrents: Mutex<HashMap<[u8; 20], ServedTorrent>>,
+}
+
+impl PeerServer {
+    pub fn new(client: &ClientConfig, config: PeerServerConfig) -> Arc<Self> {
+        Arc::new(PeerServer {
+            peer_id: client.peer_id,
+            timeouts: client.peer_timeouts,
+            encryption: client.encryption,
+            connections: Arc::new(Semaphore::new(config.max_connections)),
+            config,
+            torrents: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Starts accepting peers of the torrent, they arrive on the returned channel
+    pub fn add_torrent(&self, info_hash: &[u8]) -> mpsc::Receiver<IncomingPeer> {
+        let info_hash = info_hash.try_into().expect("Info hash must be 20 bytes");
+        let (sender, receiver) = mpsc::channel(self.config.max_connections_per_torrent);
+
+        self.torrents.lock().unwrap().insert(
+            info_hash,
+            ServedTorrent {
+                connections: Arc::new(Semaphore::new(self.config.max_connections_per_torrent)),
+                incoming: sender,
+            },
+        );
+
+        receiver
+    }
+
+    /// Peers of the torrent are rejected from now on, accepted ones stay connected
+    pub fn remove_torrent(&self, info_hash: &[u8]) {
+        let info_hash: Result<[u8; 20], _> = info_hash.try_into();
+        if let Ok(info_hash) = info_hash {
+            self.torrents.lock().unwrap().remove(&info_hash);
+        }
+    }
+
+    pub fn serve(self: &Arc<Self>, listener: TcpListener) -> JoinHandle<()> {
+        let server = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        println!("Failed to accept peer: {}", e);
+                        continue;
+                    }
+                };
+
+                // over the limit the connection is closed before reading anything
+                let Ok(permit) = server.connections.clone().try_acquire_owned() else {
+                    println!("Rejecting peer {}: too many connections", peer);
+                    continue;
+                };
+
+                let server = server.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = server.accept(stream, permit).await {
+                        println!("Rejecting peer {}: {}", peer, e);
+                    }
+                });
+            }
+        })
+    }
+
+    async fn accept(
+        &self,
+        mut stream: TcpStream,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<(), PeerServerError> {
+        // plaintext handshakes start with the protocol string, encrypted ones with a public key
+        let mut received = [0; PROTOCOL.len()];
+        time::timeout(self.timeouts.read, stream.read_exact(&mut received))
+            .await
+            .map_err(|_| PeerError::Timeout)?
+            .map_err(PeerError::from)?;
+
+        let (mut stream, remote, mut cipher, received) = if &received == PROTOCOL {
+            if self.encryption == EncryptionPolicy::Require {
+                return Err(PeerError::Encryption("plaintext connections are refused").into());
+            }
+            let mut buf = [0; HANDSHAKE_LENGTH];
+            buf[..PROTOCOL.len()].copy_from_slice(PROTOCOL);
+            time::timeout(
+                self.timeouts.read,
+                stream.read_exact(&mut buf[PROTOCOL.len()..]),
+            )
+            .await
+            .map_err(|_| PeerError::Timeout)?
+            .map_err(PeerError::from)?;
+            (stream, Handshake::decode(&buf)?, None, Vec::new())
+        } else if self.encryption == EncryptionPolicy::Disable {
+            return Err(PeerError::HandshakeMismatch("protocol").into());
+        } else {
+            self.accept_encrypted(stream, received).await?
+        };
+
+        // unknown hashes are closed without our handshake, nothing tells which torrents we serve
+        let (torrent_permit, incoming) = {
+            let torrents = self.torrents.lock().unwrap();
+            let torrent = torrents
+                .get(&remote.info_hash)
+                .ok_or(PeerServerError::UnknownInfoHash)?;
+            let torrent_permit = torrent
+                .connections
+                .clone()
+                .try_acquire_owned()
+                .map_err(|_| PeerServerError::TooManyConnections)?;
+            (torrent_permit, torrent.incoming.clone())
+        };
+
+        // `serve_peer` doesn't speak the extension protocol, so we don't advertise it
+        let mut handshake = Handshake::new(&remote.info_hash, &self.peer_id, false).encode();
+        if let Some(cipher) = &mut cipher {
+            cipher.encrypt.apply(&mut handshake);
+        }
+        time::timeout(self.timeouts.read, stream.write_all(&handshake))
+            .await
+            .map_err(|_| PeerError::Timeout)?
+            .map_err(PeerError::from)?;
+
+        let connection =
+            AsyncPeerConnection::new_encrypted(stream, &remote, self.timeouts, cipher, &received)?;
+        incoming
+            .send(IncomingPeer {
+                connection,
+                _permits: (permit, torrent_permit),
+            })
+            .await
+            // torrent stopped while we were handshaking
+            .map_err(|_| PeerServerError::UnknownInfoHash)
+    }
+
+    /// Encrypted handshake is written against blocking io, it runs on a blocking thread
+    /// and the stream comes back to tokio afterwards
+    async fn accept_encrypted(
+        &self,
+        stream: TcpStream,
+        received: [u8; PROTOCOL.len()],
+    ) -> Result<(TcpStream, Handshake, Option<StreamCipher>, Vec<u8>), PeerError> {
+        let info_hashes: Vec<_> = self.torrents.lock().unwrap().keys().copied().collect();
+        let encryption = self.encryption;
+
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(self.timeouts.read))?;
+        stream.set_write_timeout(Some(self.timeouts.read))?;
+
+        let (stream, accepted) = task::spawn_blocking(move || {
+            let mut stream = stream;
+            let accepted =
+                accept_encrypted_handshake(&mut stream, &received, &info_hashes, encryption);
+            (stream, accepted)
+        })
+        .await
+        .expect("Encrypted handshake panicked");
+
+        let (remote, cipher, received) = accepted?;
+        stream.set_nonblocking(true)?;
+        Ok((TcpStream::from_std(stream)?, remote, cipher, received))
+    }
+}
+
+/// Returns the peer's BitTorrent handshake and decrypted bytes that followed it
+fn accept_encrypted_handshake(
+    stream: &mut net::TcpStream,
+    received: &[u8],
+    info_hashes: &[[u8; 20]],
+    encryption: EncryptionPolicy,
+) -> Result<(Handshake, Option<StreamCipher>, Vec<u8>), PeerError> {
+    let mse::Accepted {
+        info_hash,
+        mut cipher,
+        initial_payload: mut buf,
+    } = mse::accept(stream, received, info_hashes, encryption)?;
+
+    // the initial payload usually holds the whole handshake
+    if buf.len() < HANDSHAKE_LENGTH {
+        let mut rest = vec![0; HANDSHAKE_LENGTH - buf.len()];
+        stream.read_exact(&mut rest)?;
+        if let Some(cipher) = &mut cipher {
+            cipher.decrypt.apply(&mut rest);
+        }
+        buf.extend(rest);
+    }
+
+    let remote = Handshake::decode(buf[..HANDSHAKE_LENGTH].try_into().unwrap())?;
+    if remote.info_hash != info_hash {
+        return Err(PeerError::HandshakeMismatch("info hash"));
+    }
+    Ok((remote, cipher, buf.split_off(HANDSHAKE_LENGTH)))
+}
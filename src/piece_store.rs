@@ -0,0 +1,165 @@
+use std::{
+    cmp,
+    fs::{File, OpenOptions},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use tokio::sync::broadcast;
+
+use crate::{meta_info_file::MetaInfo, sha1_it};
+
+/// Have announcements buffered per connection, lagging connections announce all pieces again
+const HAVE_UPDATES_CAPACITY: usize = 256;
+
+/// Torrent data on disk together with the pieces we verified, shared by downloads and uploads
+pub struct PieceStore {
+    file: Mutex<File>,
+    length: usize,
+    piece_length: usize,
+    piece_hashes: Vec<String>,
+    have: Mutex<Vec<bool>>,
+    verified: broadcast::Sender<u32>,
+}
+
+impl PieceStore {
+    /// Opens existing data without trusting any of it, `verify` finds the pieces we have
+    pub fn open(path: &Path, info: &MetaInfo) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        Ok(Self::new(file, info))
+    }
+
+    /// Creates empty file of the torrent size, pieces are filled in by `write_piece`
+    pub fn create(path: &Path, info: &MetaInfo) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(info.length as u64)?;
+        Ok(Self::new(file, info))
+    }
+
+    fn new(file: File, info: &MetaInfo) -> Self {
+        PieceStore {
+            file: Mutex::new(file),
+            length: info.length,
+            piece_length: info.piece_length,
+            piece_hashes: info.piece_hashes.clone(),
+            have: Mutex::new(vec![false; info.piece_hashes.len()]),
+            verified: broadcast::channel(HAVE_UPDATES_CAPACITY).0,
+        }
+    }
+
+    /// Hashes every piece on disk, returns how many of them are complete
+    pub fn verify(&self) -> usize {
+        let verified: Vec<_> = (0..self.pieces_count())
+            .into_par_iter()
+            .filter(|index| {
+                self.read(*index, 0, self.piece_size(*index))
+                    .is_ok_and(|piece| hex::encode(sha1_it(&piece)) == self.piece_hashes[*index])
+            })
+            .collect();
+
+        let mut have = self.have.lock().unwrap();
+        for index in &verified {
+            have[*index] = true;
+        }
+        verified.len()
+    }
+
+    pub fn pieces_count(&self) -> usize {
+        self.piece_hashes.len()
+    }
+
+    /// Length of the piece, only the last one can be shorter
+    pub fn piece_size(&self, index: usize) -> usize {
+        cmp::min(self.piece_length, self.length - index * self.piece_length)
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.have
+            .lock()
+            .unwrap()
+            .get(index)
+            .copied()
+            .unwrap_or(false)
+    }
+
+    pub fn have_count(&self) -> usize {
+        self.have
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|have| **have)
+            .count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have_count() == self.pieces_count()
+    }
+
+    /// Bytes of pieces we don't have yet, `left` of tracker announces
+    pub fn missing_bytes(&self) -> u64 {
+        let have = self.have.lock().unwrap();
+        (0..self.pieces_count())
+            .filter(|index| !have[*index])
+            .map(|index| self.piece_size(index) as u64)
+            .sum()
+    }
+
+    /// One bit per piece, the highest bit of the first byte is piece 0
+    pub fn bitfield(&self) -> Vec<u8> {
+        let have = self.have.lock().unwrap();
+        let mut bitfield = vec![0; have.len().div_ceil(8)];
+        for (index, _) in have.iter().enumerate().filter(|(_, have)| **have) {
+            bitfield[index / 8] |= 0x80 >> (index % 8);
+        }
+        bitfield
+    }
+
+    /// Reads part of a piece we have, anything else is `InvalidInput`
+    pub fn read_block(&self, index: usize, begin: usize, length: usize) -> io::Result<Vec<u8>> {
+        if !self.has_piece(index) || begin + length > self.piece_size(index) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("no block {}+{} of piece {}", begin, length, index),
+            ));
+        }
+        self.read(index, begin, length)
+    }
+
+    /// Stores piece the caller already verified and tells connections about it
+    pub fn write_piece(&self, index: usize, piece: &[u8]) -> io::Result<()> {
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start((index * self.piece_length) as u64))?;
+            file.write_all(piece)?;
+        }
+
+        self.have.lock().unwrap()[index] = true;
+        // nobody listening is fine
+        let _ = self.verified.send(index as u32);
+        Ok(())
+    }
+
+    /// Indexes of pieces written from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<u32> {
+        self.verified.subscribe()
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        self.file.lock().unwrap().flush()
+    }
+
+    fn read(&self, index: usize, begin: usize, length: usize) -> io::Result<Vec<u8>> {
+        let mut block = vec![0; length];
+        let mut file = self.file.lock().unwrap();
+        file.seek(SeekFrom::Start((index * self.piece_length + begin) as u64))?;
+        file.read_exact(&mut block)?;
+        Ok(block)
+    }
+}
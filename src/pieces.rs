@@ -1,54 +1,177 @@
-use std::cmp;
+use std::{
+    cmp,
+    collections::{HashSet, VecDeque},
+    net::SocketAddr,
+    sync::Mutex,
+};
 
 use crate::{
+    client_config::ClientConfig,
+    extensions::{Dispatched, ExtensionRegistry},
     meta_info_file::MetaInfo,
-    peer_connection::{MessageType, PeerConnection},
+    peer_connection::{Message, PeerConnection, PeerError},
+    peer_pool::PeerPool,
     sha1_it,
+    ut_metadata::MetadataServer,
+    ut_pex::PeerExchange,
 };
 
-pub fn download_piece(peer: &str, info: &MetaInfo, piece_index: usize) -> (usize, Vec<u8>) {
-    let mut connection = PeerConnection::handshake(peer, &info.hash, false);
+/// Size of blocks pieces are requested in, larger requests are refused by most clients
+pub const BLOCK_SIZE: usize = 16 * 1024;
 
-    assert_eq!(
-        connection.read_message().message_type,
-        MessageType::BitField
-    );
+/// Pieces a download still needs, each connection takes one its peer has
+#[derive(Debug, Default)]
+pub struct PieceQueue {
+    pending: Mutex<VecDeque<usize>>,
+}
 
-    connection.send_message(MessageType::Interested, vec![]);
+impl PieceQueue {
+    pub fn new(pieces: impl IntoIterator<Item = usize>) -> Self {
+        PieceQueue {
+            pending: Mutex::new(pieces.into_iter().collect()),
+        }
+    }
 
-    assert_eq!(
-        connection.read_message().message_type,
-        MessageType::Unchoked
-    );
+    /// Takes the first pending piece `available` accepts
+    pub fn take(&self, available: impl Fn(usize) -> bool) -> Option<usize> {
+        let mut pending = self.pending.lock().unwrap();
+        let position = pending.iter().position(|index| available(*index))?;
+        pending.remove(position)
+    }
 
-    let mut chunks_read = 0;
+    /// Queues a piece which failed again, another connection picks it up
+    pub fn put_back(&self, piece_index: usize) {
+        self.pending.lock().unwrap().push_back(piece_index);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.lock().unwrap().is_empty()
+    }
+}
+
+/// Downloads one piece of `pieces` the peer has. Fails with `PeerError::MissingPieces`
+/// before showing interest when the peer has none of them, a piece which fails
+/// goes back to the queue.
+pub fn download_piece(
+    client: &ClientConfig,
+    peer: &SocketAddr,
+    info: &MetaInfo,
+    pieces: &PieceQueue,
+    peer_pool: &PeerPool,
+) -> Result<(usize, Vec<u8>), PeerError> {
+    let connection = PeerConnection::handshake(
+        peer,
+        &info.hash,
+        &client.peer_id,
+        true,
+        client.peer_timeouts,
+        client.encryption,
+    )?;
 
+    let mut extensions = ExtensionRegistry::new();
+    extensions.register(MetadataServer::new(&info.metadata));
+    // private torrents only get peers from their trackers (BEP 27)
+    if !info.private {
+        extensions.register(PeerExchange::new(peer_pool, *peer));
+    }
+
+    let mut session = PeerSession {
+        connection,
+        extensions,
+        choked: true,
+        allowed_fast: HashSet::new(),
+        peer_pieces: vec![false; info.piece_hashes.len()],
+        peer_pieces_known: false,
+    };
+    if session.connection.extension_enabled {
+        session
+            .extensions
+            .send_handshake(&mut session.connection, client)?;
+    }
+
+    let mut piece_index = session.take_available_piece(pieces)?;
+    let piece = session
+        .connection
+        .send_message(&Message::Interested)
+        .and_then(|()| session.wait_for_requestable_piece(pieces, &mut piece_index))
+        .and_then(|()| fetch_piece(&mut session, info, piece_index));
+    match piece {
+        Ok(piece) => {
+            let _ = session
+                .connection
+                .tcp_stream
+                .shutdown(std::net::Shutdown::Both);
+            Ok((piece_index, piece))
+        }
+        Err(e) => {
+            pieces.put_back(piece_index);
+            Err(e)
+        }
+    }
+}
+
+fn fetch_piece(
+    session: &mut PeerSession,
+    info: &MetaInfo,
+    piece_index: usize,
+) -> Result<Vec<u8>, PeerError> {
     let length_to_read = cmp::min(
         info.length - (piece_index * info.piece_length),
         info.piece_length,
     );
+    let blocks_count = length_to_read.div_ceil(BLOCK_SIZE);
 
-    loop {
-        let current_chunk_to_read: i64 = length_to_read as i64 - (16 * 1024 * chunks_read) as i64;
-        match current_chunk_to_read {
-            x if x <= 0 => break,
-            x => request_piece_part(
-                &mut connection,
-                piece_index as u32,
-                chunks_read as u32,
-                cmp::min(16 * 1024, x) as u32,
-            ),
-        }
-        chunks_read += 1;
-    }
+    let mut piece = vec![0; length_to_read];
+    let mut received = vec![false; blocks_count];
+    let mut remaining = blocks_count;
+
+    request_missing_blocks(
+        &mut session.connection,
+        piece_index,
+        length_to_read,
+        &received,
+    )?;
+
+    while remaining > 0 {
+        match session.read_message()? {
+            Message::Piece {
+                index,
+                begin,
+                block,
+            } if index as usize == piece_index => {
+                let begin = begin as usize;
+                let block_index = begin / BLOCK_SIZE;
+                if !begin.is_multiple_of(BLOCK_SIZE)
+                    || block_index >= blocks_count
+                    || received[block_index]
+                    || block.len() != cmp::min(BLOCK_SIZE, length_to_read - begin)
+                {
+                    continue;
+                }
 
-    let mut piece = Vec::with_capacity(info.piece_length);
-    for _ in 0..chunks_read {
-        let message = connection.read_message();
-        if message.message_type == MessageType::Piece {
-            piece.extend_from_slice(&message.payload[8..])
+                piece[begin..begin + block.len()].copy_from_slice(&block);
+                received[block_index] = true;
+                remaining -= 1;
+            }
+            // choking peer discards all requests it didn't answer yet (BEP 3),
+            // except for allowed fast pieces (BEP 6)
+            Message::Choke if !session.can_request(piece_index as u32) => {
+                session.wait_until_requestable(piece_index as u32)?;
+                request_missing_blocks(
+                    &mut session.connection,
+                    piece_index,
+                    length_to_read,
+                    &received,
+                )?;
+            }
+            // rejects following a choke are expected, anything else means no
+            Message::RejectRequest { index, .. }
+                if index as usize == piece_index && session.can_request(index) =>
+            {
+                return Err(PeerError::RequestRejected(index));
+            }
+            _ => {}
         }
-        // FIXME: handle different message
     }
 
     println!(
@@ -57,14 +180,153 @@ pub fn download_piece(peer: &str, info: &MetaInfo, piece_index: usize) -> (usize
         hex::encode(sha1_it(&piece))
     );
 
-    assert_eq!(info.piece_hashes[piece_index], hex::encode(sha1_it(&piece)));
+    if info.piece_hashes[piece_index] != hex::encode(sha1_it(&piece)) {
+        return Err(PeerError::ProtocolViolation(format!(
+            "piece {} failed hash check",
+            piece_index
+        )));
+    }
+
+    Ok(piece)
+}
+
+/// Connection of a piece download with the extensions served on the side
+struct PeerSession<'a> {
+    connection: PeerConnection,
+    extensions: ExtensionRegistry<'a>,
+    choked: bool,
+    /// pieces we may request while choked (BEP 6)
+    allowed_fast: HashSet<u32>,
+    /// pieces the peer announced through bitfield, have, have all or have none
+    peer_pieces: Vec<bool>,
+    /// the peer sent its bitfield, have all or have none
+    peer_pieces_known: bool,
+}
+
+impl PeerSession<'_> {
+    /// Reads next message which is not an extension message served on the side
+    fn read_message(&mut self) -> Result<Message, PeerError> {
+        loop {
+            self.extensions.poll(&mut self.connection)?;
+
+            let message = self.connection.read_message()?;
+            match message {
+                Message::Choke => self.choked = true,
+                Message::Unchoke => self.choked = false,
+                Message::AllowedFast { index } if self.connection.fast_enabled => {
+                    self.allowed_fast.insert(index);
+                }
+                Message::Bitfield(ref bitfield) => {
+                    for (index, has) in self.peer_pieces.iter_mut().enumerate() {
+                        *has = bitfield
+                            .get(index / 8)
+                            .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0);
+                    }
+                    self.peer_pieces_known = true;
+                }
+                Message::HaveAll | Message::HaveNone if self.connection.fast_enabled => {
+                    self.peer_pieces.fill(message == Message::HaveAll);
+                    self.peer_pieces_known = true;
+                }
+                Message::Have { index } => {
+                    if let Some(has) = self.peer_pieces.get_mut(index as usize) {
+                        *has = true;
+                    }
+                }
+                _ => {}
+            }
+
+            if self
+                .extensions
+                .handle_message(&mut self.connection, &message)?
+                == Dispatched::Unhandled
+            {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Takes a queued piece the peer has. Peers announce their pieces right after the
+    /// handshake, one which doesn't within the read timeout is taken to have none.
+    fn take_available_piece(&mut self, pieces: &PieceQueue) -> Result<usize, PeerError> {
+        let idle = self.connection.timeouts.idle;
+        self.connection.timeouts.idle = self.connection.timeouts.read;
+
+        let piece_index = loop {
+            if let Some(piece_index) = pieces.take(|index| self.peer_pieces[index]) {
+                break Ok(piece_index);
+            }
+            if self.peer_pieces_known {
+                break Err(PeerError::MissingPieces);
+            }
+            match self.read_message() {
+                Ok(_) => {}
+                Err(PeerError::Timeout) => break Err(PeerError::MissingPieces),
+                Err(e) => break Err(e),
+            }
+        };
 
-    connection
-        .tcp_stream
-        .shutdown(std::net::Shutdown::Both)
-        .expect("Failed to close tcp stream");
+        self.connection.timeouts.idle = idle;
+        piece_index
+    }
+
+    fn can_request(&self, piece_index: u32) -> bool {
+        !self.choked || self.allowed_fast.contains(&piece_index)
+    }
+
+    /// Waits for unchoke, or for the piece to become allowed fast. While choked the piece
+    /// is swapped for a queued one the peer allows us to download fast (BEP 6).
+    fn wait_for_requestable_piece(
+        &mut self,
+        pieces: &PieceQueue,
+        piece_index: &mut usize,
+    ) -> Result<(), PeerError> {
+        while !self.can_request(*piece_index as u32) {
+            let allowed_fast = pieces.take(|index| {
+                self.peer_pieces[index] && self.allowed_fast.contains(&(index as u32))
+            });
+            match allowed_fast {
+                Some(allowed_fast) => {
+                    pieces.put_back(*piece_index);
+                    *piece_index = allowed_fast;
+                }
+                None => {
+                    self.read_message()?;
+                }
+            }
+        }
+        Ok(())
+    }
 
-    (piece_index, piece)
+    /// Waits for unchoke, or for the piece to become allowed fast
+    fn wait_until_requestable(&mut self, piece_index: u32) -> Result<(), PeerError> {
+        while !self.can_request(piece_index) {
+            self.read_message()?;
+        }
+        Ok(())
+    }
+}
+
+fn request_missing_blocks(
+    connection: &mut PeerConnection,
+    piece_index: usize,
+    piece_length: usize,
+    received: &[bool],
+) -> Result<(), PeerError> {
+    for (block_index, _) in received
+        .iter()
+        .enumerate()
+        .filter(|(_, received)| !**received)
+    {
+        let bytes_to_read = cmp::min(BLOCK_SIZE, piece_length - block_index * BLOCK_SIZE);
+        request_piece_part(
+            connection,
+            piece_index as u32,
+            block_index as u32,
+            bytes_to_read as u32,
+        )?;
+    }
+    Ok(())
 }
 
 pub fn request_piece_part(
@@ -72,11 +334,10 @@ pub fn request_piece_part(
     piece_index: u32,
     offset_block: u32,
     bytes_to_read: u32,
-) {
-    let begin: u32 = offset_block * 16 * 1024;
-    let mut payload = Vec::with_capacity(12);
-    payload.extend_from_slice(&piece_index.to_be_bytes());
-    payload.extend_from_slice(&begin.to_be_bytes());
-    payload.extend_from_slice(&bytes_to_read.to_be_bytes());
-    connection.send_message(MessageType::Request, payload);
+) -> Result<(), PeerError> {
+    connection.send_message(&Message::Request {
+        index: piece_index,
+        begin: offset_block * BLOCK_SIZE as u32,
+        length: bytes_to_read,
+    })
 }
@@ -0,0 +1,583 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::{Arc, Mutex, Weak},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+
+use crate::{
+    discover_peers::{encode_compact_peers, ScrapeStats},
+    random_bytes,
+};
+
+/// How often we ask peers to announce
+pub const DEFAULT_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Peers returned when announce doesn't specify `numwant`
+pub const DEFAULT_NUMWANT: usize = 50;
+
+/// We never return more peers than this, regardless of `numwant`
+pub const MAX_NUMWANT: usize = 200;
+
+/// Request line and headers of an HTTP announce or scrape, anything longer is refused
+pub const MAX_REQUEST_SIZE: u64 = 16 * 1024;
+
+/// Slow clients get this long to send their request
+const HTTP_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Expired peers are removed at least this often, also from swarms nobody announces to
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+const UDP_CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(2 * 60);
+
+#[derive(Debug, Clone)]
+pub struct TrackerServerConfig {
+    pub interval: Duration,
+    /// peers which didn't announce for this long are removed from swarms
+    pub peer_expiry: Duration,
+    /// only these info hashes are tracked when set
+    pub whitelist: Option<HashSet<Vec<u8>>>,
+}
+
+impl Default for TrackerServerConfig {
+    fn default() -> Self {
+        TrackerServerConfig {
+            interval: DEFAULT_ANNOUNCE_INTERVAL,
+            peer_expiry: DEFAULT_ANNOUNCE_INTERVAL * 2,
+            whitelist: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServerAnnounceEvent {
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+/// Announce as received by the tracker, independent of HTTP or UDP
+#[derive(Debug)]
+pub struct ServerAnnounce {
+    pub info_hash: Vec<u8>,
+    pub peer_id: Vec<u8>,
+    pub address: SocketAddr,
+    pub left: u64,
+    pub event: ServerAnnounceEvent,
+    pub numwant: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct ServerAnnounceResponse {
+    pub interval: Duration,
+    pub complete: u64,
+    pub incomplete: u64,
+    pub peers: Vec<SocketAddr>,
+}
+
+struct SwarmPeer {
+    address: SocketAddr,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<Vec<u8>, SwarmPeer>,
+    downloaded: u64,
+}
+
+impl Swarm {
+    fn expire_peers(&mut self, peer_expiry: Duration) {
+        self.peers
+            .retain(|_, peer| peer.last_seen.elapsed() < peer_expiry);
+    }
+
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count() as u64;
+        ScrapeStats {
+            complete,
+            downloaded: self.downloaded,
+            incomplete: self.peers.len() as u64 - complete,
+        }
+    }
+}
+
+/// BitTorrent tracker speaking HTTP and UDP (BEP 3, BEP 15), peers are kept in memory
+pub struct TrackerServer {
+    config: TrackerServerConfig,
+    swarms: Mutex<HashMap<Vec<u8>, Swarm>>,
+    udp_connections: Mutex<HashMap<u64, (IpAddr, Instant)>>,
+}
+
+impl TrackerServer {
+    pub fn new(config: TrackerServerConfig) -> Arc<Self> {
+        let check_interval =
+            (config.peer_expiry / 2).clamp(Duration::from_millis(100), EXPIRY_CHECK_INTERVAL);
+        let server = Arc::new(TrackerServer {
+            config,
+            swarms: Mutex::new(HashMap::new()),
+            udp_connections: Mutex::new(HashMap::new()),
+        });
+
+        // stops once the last user of the server is gone
+        let weak = Arc::downgrade(&server);
+        thread::spawn(move || loop {
+            thread::sleep(check_interval);
+            match Weak::upgrade(&weak) {
+                Some(server) => server.expire_peers(),
+                None => return,
+            }
+        });
+
+        server
+    }
+
+    /// Number of torrents with at least one peer
+    pub fn torrents_count(&self) -> usize {
+        self.swarms.lock().unwrap().len()
+    }
+
+    /// Removes peers which didn't announce for `peer_expiry` and swarms left empty,
+    /// download counts of dropped swarms start over
+    fn expire_peers(&self) {
+        let mut swarms = self.swarms.lock().unwrap();
+        for swarm in swarms.values_mut() {
+            swarm.expire_peers(self.config.peer_expiry);
+        }
+        swarms.retain(|_, swarm| !swarm.peers.is_empty());
+    }
+
+    pub fn announce(&self, announce: ServerAnnounce) -> Result<ServerAnnounceResponse, String> {
+        self.check_whitelist(&announce.info_hash)?;
+
+        let mut swarms = self.swarms.lock().unwrap();
+        let swarm = swarms.entry(announce.info_hash.clone()).or_default();
+
+        swarm.expire_peers(self.config.peer_expiry);
+
+        match announce.event {
+            ServerAnnounceEvent::Stopped => {
+                swarm.peers.remove(&announce.peer_id);
+            }
+            event => {
+                if event == ServerAnnounceEvent::Completed {
+                    swarm.downloaded += 1;
+                }
+                swarm.peers.insert(
+                    announce.peer_id.clone(),
+                    SwarmPeer {
+                        address: announce.address,
+                        left: announce.left,
+                        last_seen: Instant::now(),
+                    },
+                );
+            }
+        }
+
+        let numwant = announce.numwant.unwrap_or(DEFAULT_NUMWANT).min(MAX_NUMWANT);
+        let peers = swarm
+            .peers
+            .iter()
+            .filter(|(peer_id, _)| **peer_id != announce.peer_id)
+            // seeders don't need other seeders
+            .filter(|(_, peer)| announce.left > 0 || peer.left > 0)
+            .map(|(_, peer)| peer.address)
+            .take(numwant)
+            .collect();
+
+        let stats = swarm.stats();
+        if swarm.peers.is_empty() {
+            swarms.remove(&announce.info_hash);
+        }
+        Ok(ServerAnnounceResponse {
+            interval: self.config.interval,
+            complete: stats.complete,
+            incomplete: stats.incomplete,
+            peers,
+        })
+    }
+
+    /// Statistics of requested torrents, `None` for torrents nobody announced
+    pub fn scrape(&self, info_hashes: &[Vec<u8>]) -> Vec<Option<ScrapeStats>> {
+        let mut swarms = self.swarms.lock().unwrap();
+        info_hashes
+            .iter()
+            .map(|info_hash| {
+                let swarm = swarms.get_mut(info_hash)?;
+                swarm.expire_peers(self.config.peer_expiry);
+                if swarm.peers.is_empty() {
+                    swarms.remove(info_hash);
+                    return None;
+                }
+                Some(swarm.stats())
+            })
+            .collect()
+    }
+
+    fn check_whitelist(&self, info_hash: &[u8]) -> Result<(), String> {
+        match &self.config.whitelist {
+            Some(whitelist) if !whitelist.contains(info_hash) => {
+                Err(String::from("torrent is not tracked"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Accepts HTTP announces and scrapes, every connection is handled on its own thread
+    pub fn serve_http(self: &Arc<Self>, listener: TcpListener) -> JoinHandle<()> {
+        let server = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let server = server.clone();
+                thread::spawn(move || {
+                    if let Err(e) = server.handle_http(stream) {
+                        println!("Failed to handle tracker http request: {}", e);
+                    }
+                });
+            }
+        })
+    }
+
+    /// Answers UDP tracker protocol packets until the socket fails
+    pub fn serve_udp(self: &Arc<Self>, socket: UdpSocket) -> JoinHandle<()> {
+        let server = self.clone();
+        thread::spawn(move || {
+            let mut buf = [0; 2048];
+            loop {
+                let (size, from) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        println!("Tracker udp socket failed: {}", e);
+                        return;
+                    }
+                };
+
+                if let Some(response) = server.handle_udp(&buf[..size], from) {
+                    let _ = socket.send_to(&response, from);
+                }
+            }
+        })
+    }
+
+    fn handle_http(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(HTTP_READ_TIMEOUT))?;
+        let remote = stream.peer_addr()?;
+        let mut reader = BufReader::new(stream.take(MAX_REQUEST_SIZE));
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // we only need the request line, headers are skipped
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+                break;
+            }
+        }
+        if reader.get_ref().limit() == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request is too large",
+            ));
+        }
+
+        let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let params = parse_query(query);
+
+        let body = match path.rsplit('/').next().unwrap_or("") {
+            segment if segment.starts_with("announce") => self.http_announce(&params, remote),
+            segment if segment.starts_with("scrape") => self.http_scrape(&params),
+            _ => Err(String::from("unknown endpoint")),
+        }
+        .unwrap_or_else(|failure_reason| {
+            serde_bencode::to_bytes(&FailureResponse { failure_reason }).unwrap()
+        });
+
+        let mut stream = reader.into_inner().into_inner();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )?;
+        stream.write_all(&body)?;
+        stream.flush()
+    }
+
+    fn http_announce(
+        &self,
+        params: &HashMap<String, Vec<Vec<u8>>>,
+        remote: SocketAddr,
+    ) -> Result<Vec<u8>, String> {
+        let param = |name: &str| params.get(name).and_then(|values| values.first());
+        let number_param = |name: &str| {
+            param(name).and_then(|value| String::from_utf8_lossy(value).parse::<u64>().ok())
+        };
+
+        let info_hash = param("info_hash")
+            .filter(|info_hash| info_hash.len() == 20)
+            .ok_or("missing info_hash")?;
+        let peer_id = param("peer_id")
+            .filter(|peer_id| peer_id.len() == 20)
+            .ok_or("missing peer_id")?;
+        let port = number_param("port")
+            .and_then(|port| u16::try_from(port).ok())
+            .ok_or("missing port")?;
+        let ip = param("ip")
+            .and_then(|ip| String::from_utf8_lossy(ip).parse::<IpAddr>().ok())
+            .unwrap_or(remote.ip());
+        let event = match param("event").map(|event| event.as_slice()) {
+            Some(b"started") => ServerAnnounceEvent::Started,
+            Some(b"completed") => ServerAnnounceEvent::Completed,
+            Some(b"stopped") => ServerAnnounceEvent::Stopped,
+            _ => ServerAnnounceEvent::None,
+        };
+
+        let response = self.announce(ServerAnnounce {
+            info_hash: info_hash.clone(),
+            peer_id: peer_id.clone(),
+            address: SocketAddr::new(ip, port),
+            // a missing `left` would count the peer as a seeder
+            left: number_param("left").ok_or("missing left")?,
+            event,
+            numwant: number_param("numwant").map(|numwant| numwant as usize),
+        })?;
+
+        let (peers, peers6) = encode_compact_peers(&response.peers);
+        Ok(serde_bencode::to_bytes(&HttpAnnounceResponse {
+            interval: response.interval.as_secs(),
+            complete: response.complete,
+            incomplete: response.incomplete,
+            peers: ByteBuf::from(peers),
+            peers6: ByteBuf::from(peers6),
+        })
+        .unwrap())
+    }
+
+    fn http_scrape(&self, params: &HashMap<String, Vec<Vec<u8>>>) -> Result<Vec<u8>, String> {
+        let info_hashes = params.get("info_hash").cloned().unwrap_or_default();
+        let stats = self.scrape(&info_hashes);
+
+        // unknown torrents are left out
+        let files = info_hashes
+            .into_iter()
+            .zip(stats)
+            .filter_map(|(info_hash, stats)| {
+                stats.map(|stats| {
+                    let file = HttpScrapeFile {
+                        complete: stats.complete,
+                        downloaded: stats.downloaded,
+                        incomplete: stats.incomplete,
+                    };
+                    (ByteBuf::from(info_hash), file)
+                })
+            })
+            .collect();
+
+        Ok(serde_bencode::to_bytes(&HttpScrapeResponse { files }).unwrap())
+    }
+
+    fn handle_udp(&self, packet: &[u8], from: SocketAddr) -> Option<Vec<u8>> {
+        if packet.len() < 16 {
+            return None;
+        }
+
+        let connection_id = u64::from_be_bytes(packet[..8].try_into().unwrap());
+        let action = read_u32(packet, 8);
+        let transaction_id = &packet[12..16];
+
+        let mut response = Vec::new();
+
+        let result = if action == 0 {
+            if connection_id != UDP_PROTOCOL_ID {
+                return None;
+            }
+            let connection_id = self.new_udp_connection(from);
+            Ok(connection_id.to_be_bytes().to_vec())
+        } else if !self.valid_udp_connection(connection_id, from) {
+            Err(String::from("invalid connection id"))
+        } else if action == 1 {
+            self.udp_announce(packet, from)
+        } else if action == 2 {
+            Ok(self.udp_scrape(packet))
+        } else {
+            Err(String::from("unknown action"))
+        };
+
+        match result {
+            Ok(body) => {
+                response.extend_from_slice(&action.to_be_bytes());
+                response.extend_from_slice(transaction_id);
+                response.extend(body);
+            }
+            Err(message) => {
+                response.extend_from_slice(&3u32.to_be_bytes());
+                response.extend_from_slice(transaction_id);
+                response.extend_from_slice(message.as_bytes());
+            }
+        }
+
+        Some(response)
+    }
+
+    fn udp_announce(&self, packet: &[u8], from: SocketAddr) -> Result<Vec<u8>, String> {
+        if packet.len() < 98 {
+            return Err(String::from("announce packet too short"));
+        }
+
+        let event = match read_u32(packet, 80) {
+            1 => ServerAnnounceEvent::Completed,
+            2 => ServerAnnounceEvent::Started,
+            3 => ServerAnnounceEvent::Stopped,
+            _ => ServerAnnounceEvent::None,
+        };
+        let ip = match read_u32(packet, 84) {
+            0 => from.ip(),
+            ip => IpAddr::from(ip.to_be_bytes()),
+        };
+        let numwant = match read_u32(packet, 92) as i32 {
+            numwant if numwant < 0 => None,
+            numwant => Some(numwant as usize),
+        };
+        let port = u16::from_be_bytes([packet[96], packet[97]]);
+
+        let response = self.announce(ServerAnnounce {
+            info_hash: packet[16..36].to_vec(),
+            peer_id: packet[36..56].to_vec(),
+            address: SocketAddr::new(ip, port),
+            left: u64::from_be_bytes(packet[64..72].try_into().unwrap()),
+            event,
+            numwant,
+        })?;
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&(response.interval.as_secs() as u32).to_be_bytes());
+        body.extend_from_slice(&(response.incomplete as u32).to_be_bytes());
+        body.extend_from_slice(&(response.complete as u32).to_be_bytes());
+
+        // peers of the same address family as the announcing client (BEP 15)
+        let (peers, peers6) = encode_compact_peers(&response.peers);
+        body.extend(if from.is_ipv6() { peers6 } else { peers });
+
+        Ok(body)
+    }
+
+    fn udp_scrape(&self, packet: &[u8]) -> Vec<u8> {
+        let info_hashes: Vec<_> = packet[16..]
+            .chunks_exact(20)
+            .map(|info_hash| info_hash.to_vec())
+            .collect();
+
+        let zero = ScrapeStats {
+            complete: 0,
+            downloaded: 0,
+            incomplete: 0,
+        };
+
+        // packet layout doesn't allow leaving torrents out, unknown ones are zeroes
+        let mut body = Vec::new();
+        for stats in self.scrape(&info_hashes) {
+            let stats = stats.unwrap_or(zero);
+            body.extend_from_slice(&(stats.complete as u32).to_be_bytes());
+            body.extend_from_slice(&(stats.downloaded as u32).to_be_bytes());
+            body.extend_from_slice(&(stats.incomplete as u32).to_be_bytes());
+        }
+
+        body
+    }
+
+    fn new_udp_connection(&self, from: SocketAddr) -> u64 {
+        let connection_id = u64::from_le_bytes(random_bytes(8).try_into().unwrap());
+
+        let mut connections = self.udp_connections.lock().unwrap();
+        connections.retain(|_, (_, created_at)| created_at.elapsed() < UDP_CONNECTION_ID_LIFETIME);
+        connections.insert(connection_id, (from.ip(), Instant::now()));
+
+        connection_id
+    }
+
+    fn valid_udp_connection(&self, connection_id: u64, from: SocketAddr) -> bool {
+        let connections = self.udp_connections.lock().unwrap();
+        matches!(
+            connections.get(&connection_id),
+            // client can use the connection id from any of its sockets
+            Some((ip, created_at))
+                if *ip == from.ip() && created_at.elapsed() < UDP_CONNECTION_ID_LIFETIME
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct FailureResponse {
+    #[serde(rename = "failure reason")]
+    failure_reason: String,
+}
+
+#[derive(Serialize)]
+struct HttpAnnounceResponse {
+    complete: u64,
+    incomplete: u64,
+    interval: u64,
+    peers: ByteBuf,
+    peers6: ByteBuf,
+}
+
+#[derive(Serialize)]
+struct HttpScrapeFile {
+    complete: u64,
+    downloaded: u64,
+    incomplete: u64,
+}
+
+#[derive(Serialize)]
+struct HttpScrapeResponse {
+    files: BTreeMap<ByteBuf, HttpScrapeFile>,
+}
+
+/// Query values are percent decoded into raw bytes, `info_hash` and `peer_id` are binary
+fn parse_query(query: &str) -> HashMap<String, Vec<Vec<u8>>> {
+    let mut params: HashMap<String, Vec<Vec<u8>>> = HashMap::new();
+
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let key = String::from_utf8_lossy(&percent_decode(key)).to_string();
+        params.entry(key).or_default().push(percent_decode(value));
+    }
+
+    params
+}
+
+fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => match hex::decode(&bytes[i + 1..i + 3]) {
+                Ok(byte) => {
+                    decoded.push(byte[0]);
+                    i += 3;
+                    continue;
+                }
+                Err(_) => decoded.push(b'%'),
+            },
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    decoded
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
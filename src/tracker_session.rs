@@ -0,0 +1,273 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    client_config::ClientConfig,
+    discover_peers::{
+        announce, announce_in_background, AnnounceEvent, AnnounceRequest, AnnounceResponse,
+        TrackerError,
+    },
+    peer_pool::{PeerPool, PeerSource},
+};
+
+/// How long we wait before trying again when tracker didn't respond
+pub const ANNOUNCE_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Shortest interval we re-announce in, whatever the tracker asks for
+pub const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long dropping a session waits for `stopped` to be announced, a background
+/// announce stuck retransmitting to a dead UDP tracker is left behind
+const STOP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Transfer statistics reported to the tracker, shared with the download
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    left: AtomicU64,
+}
+
+impl TransferStats {
+    pub fn new(left: u64) -> Self {
+        TransferStats {
+            left: AtomicU64::new(left),
+            ..Default::default()
+        }
+    }
+
+    pub fn record_download(&self, bytes: u64) {
+        self.downloaded.fetch_add(bytes, Ordering::Relaxed);
+        // verified bytes are never downloaded twice, but don't underflow on a bad caller
+        let _ = self
+            .left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                Some(left.saturating_sub(bytes))
+            });
+    }
+
+    pub fn record_upload(&self, bytes: u64) {
+        self.uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+}
+
+enum SessionCommand {
+    Completed,
+    Stop,
+}
+
+/// Keeps announcing a torrent to its trackers for the whole download:
+/// `started` at the beginning, periodic announces honoring tracker interval,
+/// `completed` once all pieces are verified and `stopped` when dropped.
+/// Failed `started` and `completed` announces are repeated until a tracker gets them.
+/// Peers of every response are added to the download's peer pool.
+pub struct TrackerSession {
+    commands: Sender<SessionCommand>,
+    /// disconnects when the announcing thread ends
+    finished: Receiver<()>,
+}
+
+struct SessionState {
+    client: ClientConfig,
+    /// `announce-list` tiers, trackers which responded move to the front of their tier (BEP 12)
+    tiers: Vec<Vec<String>>,
+    info_hash: Vec<u8>,
+    stats: Arc<TransferStats>,
+    /// tracker ids are only sent back to the tracker which issued them
+    tracker_ids: HashMap<String, String>,
+    peer_pool: Arc<PeerPool>,
+    started: bool,
+    completed: bool,
+}
+
+impl TrackerSession {
+    /// Sends `started` event and keeps re-announcing in the background
+    pub fn start(
+        client: &ClientConfig,
+        tiers: &[Vec<String>],
+        info_hash: &[u8],
+        stats: Arc<TransferStats>,
+        peer_pool: Arc<PeerPool>,
+    ) -> Self {
+        let mut state = SessionState {
+            client: client.clone(),
+            tiers: tiers.to_vec(),
+            info_hash: info_hash.to_vec(),
+            stats,
+            tracker_ids: HashMap::new(),
+            peer_pool,
+            started: false,
+            completed: false,
+        };
+
+        // first announce happens right away, the download waits for its peers
+        let mut next_announce = state.announce_pending(false);
+
+        let (commands, receiver) = mpsc::channel();
+        let (finished_sender, finished) = mpsc::channel::<()>();
+
+        thread::spawn(move || {
+            // dropped however the thread ends
+            let _finished = finished_sender;
+            loop {
+                match receiver.recv_timeout(next_announce) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Ok(SessionCommand::Completed) => state.completed = true,
+                    Ok(SessionCommand::Stop) | Err(RecvTimeoutError::Disconnected) => {
+                        // trackers which never saw `started` don't know about us
+                        if state.started {
+                            if let Err(e) = state.announce(Some(AnnounceEvent::Stopped), false) {
+                                println!("Failed to announce stop to tracker: {}", e);
+                            }
+                        }
+                        return;
+                    }
+                };
+
+                next_announce = state.announce_pending(true);
+            }
+        });
+
+        TrackerSession { commands, finished }
+    }
+
+    /// Announces `completed`, to be called when the last piece is verified
+    pub fn completed(&self) {
+        let _ = self.commands.send(SessionCommand::Completed);
+    }
+}
+
+impl Drop for TrackerSession {
+    fn drop(&mut self) {
+        let _ = self.commands.send(SessionCommand::Stop);
+        let _ = self.finished.recv_timeout(STOP_TIMEOUT);
+    }
+}
+
+impl SessionState {
+    /// Announces `started` or `completed` until a tracker gets them, a regular announce
+    /// otherwise. Returns when to announce next.
+    fn announce_pending(&mut self, background: bool) -> Duration {
+        let event = if !self.started {
+            Some(AnnounceEvent::Started)
+        } else if self.completed {
+            Some(AnnounceEvent::Completed)
+        } else {
+            None
+        };
+
+        match self.announce(event, background) {
+            Ok(response) => {
+                match event {
+                    Some(AnnounceEvent::Started) => self.started = true,
+                    Some(AnnounceEvent::Completed) => self.completed = false,
+                    _ => {}
+                }
+                // download may have finished while `started` was still pending
+                if self.completed && event != Some(AnnounceEvent::Completed) {
+                    Duration::ZERO
+                } else {
+                    announce_interval(&response)
+                }
+            }
+            Err(e) => {
+                println!("Failed to announce to tracker: {}", e);
+                ANNOUNCE_RETRY_INTERVAL
+            }
+        }
+    }
+
+    /// Tries tiers in order and trackers of a tier in order until one responds,
+    /// `background` announces use the full UDP retransmission schedule
+    fn announce(
+        &mut self,
+        event: Option<AnnounceEvent>,
+        background: bool,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let mut last_error = TrackerError::InvalidUrl(String::from("no trackers"));
+
+        for tier in 0..self.tiers.len() {
+            for position in 0..self.tiers[tier].len() {
+                let tracker_url = self.tiers[tier][position].clone();
+                match self.announce_to(&tracker_url, event, background) {
+                    Ok(response) => {
+                        let tracker_url = self.tiers[tier].remove(position);
+                        self.tiers[tier].insert(0, tracker_url);
+                        return Ok(response);
+                    }
+                    Err(e) => last_error = e,
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn announce_to(
+        &mut self,
+        tracker_url: &str,
+        event: Option<AnnounceEvent>,
+        background: bool,
+    ) -> Result<AnnounceResponse, TrackerError> {
+        let announce = if background {
+            announce_in_background
+        } else {
+            announce
+        };
+        let response = announce(
+            tracker_url,
+            &AnnounceRequest {
+                info_hash: &self.info_hash,
+                uploaded: self.stats.uploaded(),
+                downloaded: self.stats.downloaded(),
+                left: self.stats.left(),
+                event,
+                tracker_id: self.tracker_ids.get(tracker_url).map(String::as_str),
+                client: &self.client,
+            },
+        )?;
+
+        if let Some(warning_message) = &response.warning_message {
+            println!("Tracker warning: {}", warning_message);
+        }
+        if let Some(tracker_id) = &response.tracker_id {
+            self.tracker_ids
+                .insert(tracker_url.to_string(), tracker_id.clone());
+        }
+        if event != Some(AnnounceEvent::Stopped) {
+            self.peer_pool
+                .add(response.peers.iter().copied(), PeerSource::Tracker);
+        }
+
+        Ok(response)
+    }
+}
+
+/// Tracker interval, never below `MIN_ANNOUNCE_INTERVAL` so a bogus 0 doesn't spin
+fn announce_interval(response: &AnnounceResponse) -> Duration {
+    let interval = match response.min_interval {
+        Some(min_interval) => response.interval.max(min_interval),
+        None => response.interval,
+    };
+    interval.max(MIN_ANNOUNCE_INTERVAL)
+}
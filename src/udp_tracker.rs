@@ -0,0 +1,581 @@
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    discover_peers::{
+        parse_compact_peers, AnnounceEvent, AnnounceRequest, AnnounceResponse, ScrapeStats,
+        TrackerError,
+    },
+    random_u32,
+};
+
+/// Magic constant identifying the UDP tracker protocol in connect requests (BEP 15)
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// Connection ID can be used for one minute after it was received
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Trackers are not allowed to answer with more than this, anything larger is truncated anyway
+const MAX_PACKET_SIZE: usize = 2048;
+
+/// Longest a single announce or scrape blocks by default, same as the async tracker client
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(20);
+
+/// Connection IDs of all UDP trackers we talked to, shared by every announce and scrape
+static CONNECTION_IDS: OnceLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> = OnceLock::new();
+
+/// Client for a single UDP tracker
+pub struct UdpTracker {
+    socket: UdpSocket,
+    tracker_addr: SocketAddr,
+    /// first response timeout, doubled with every retransmission
+    pub base_timeout: Duration,
+    pub max_retransmissions: u32,
+    /// limit of the whole transaction, `None` runs the full retransmission schedule
+    pub deadline: Option<Duration>,
+}
+
+impl UdpTracker {
+    pub fn new(tracker_url: &str) -> Result<Self, TrackerError> {
+        let tracker_addr = tracker_host(tracker_url)?
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| TrackerError::InvalidUrl(tracker_url.into()))?;
+
+        Ok(UdpTracker {
+            socket: UdpSocket::bind(bind_addr(&tracker_addr))?,
+            tracker_addr,
+            // 15 * 2 ^ n seconds, n up to 8 (BEP 15)
+            base_timeout: Duration::from_secs(15),
+            max_retransmissions: 8,
+            deadline: Some(DEFAULT_DEADLINE),
+        })
+    }
+
+    pub fn announce(&self, request: &AnnounceRequest) -> Result<AnnounceResponse, TrackerError> {
+        let response = self.transact(ACTION_ANNOUNCE, |packet| {
+            write_announce_body(packet, request)
+        })?;
+        parse_announce_body(&response, self.tracker_addr.is_ipv6())
+    }
+
+    /// Returns statistics for every info hash in the same order, the protocol allows
+    /// up to about 74 hashes per request
+    pub fn scrape(&self, info_hashes: &[&[u8]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let response = self.transact(ACTION_SCRAPE, |packet| {
+            for info_hash in info_hashes {
+                packet.extend_from_slice(info_hash);
+            }
+        })?;
+        parse_scrape_body(&response, info_hashes.len())
+    }
+
+    /// Sends request with a valid connection ID, retransmitting with exponential backoff.
+    /// Returns response body after action and transaction id.
+    fn transact(
+        &self,
+        action: u32,
+        write_body: impl Fn(&mut Vec<u8>),
+    ) -> Result<Vec<u8>, TrackerError> {
+        let deadline = self.deadline.map(|deadline| Instant::now() + deadline);
+        // attempt timeout shortened to what is left until the deadline
+        let timeout = |attempt: u32| {
+            let timeout = self.base_timeout * 2u32.pow(attempt);
+            match deadline {
+                Some(deadline) => timeout.min(deadline.saturating_duration_since(Instant::now())),
+                None => timeout,
+            }
+        };
+
+        for attempt in 0..=self.max_retransmissions {
+            if timeout(attempt).is_zero() {
+                break;
+            }
+
+            let connection_id = match cached_connection_id(&self.tracker_addr) {
+                Some(connection_id) => connection_id,
+                None => match self.connect(timeout(attempt)) {
+                    Ok(connection_id) => connection_id,
+                    Err(TrackerError::Timeout) => continue,
+                    Err(e) => return Err(e),
+                },
+            };
+
+            let packet = request_packet(Some(connection_id), action, &write_body);
+            match self.send_and_receive(packet, action, timeout(attempt)) {
+                Err(TrackerError::Timeout) => continue,
+                result => return result,
+            }
+        }
+
+        Err(TrackerError::Timeout)
+    }
+
+    fn connect(&self, timeout: Duration) -> Result<u64, TrackerError> {
+        let packet = request_packet(None, ACTION_CONNECT, |_| {});
+        let response = self.send_and_receive(packet, ACTION_CONNECT, timeout)?;
+        parse_connect_body(&response, &self.tracker_addr)
+    }
+
+    fn send_and_receive(
+        &self,
+        packet: Vec<u8>,
+        action: u32,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, TrackerError> {
+        self.socket.send_to(&packet, self.tracker_addr)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut buf = [0; MAX_PACKET_SIZE];
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(TrackerError::Timeout);
+            }
+            self.socket.set_read_timeout(Some(remaining))?;
+
+            let (size, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Err(TrackerError::Timeout)
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            if from != self.tracker_addr {
+                continue;
+            }
+            if let Some(response) = parse_response(&buf[..size], &packet, action) {
+                return response;
+            }
+        }
+    }
+}
+
+/// Announces to `udp://` tracker, gives up after `DEFAULT_DEADLINE`
+pub fn announce(
+    tracker_url: &str,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
+    UdpTracker::new(tracker_url)?.announce(request)
+}
+
+/// Announces to `udp://` tracker with the full spec retransmission schedule,
+/// which may take hours, so only for announces nobody waits for
+pub fn announce_in_background(
+    tracker_url: &str,
+    request: &AnnounceRequest,
+) -> Result<AnnounceResponse, TrackerError> {
+    UdpTracker {
+        deadline: None,
+        ..UdpTracker::new(tracker_url)?
+    }
+    .announce(request)
+}
+
+/// Splits `udp://host:port/...` into host and port, IPv6 hosts lose their brackets
+fn tracker_host(tracker_url: &str) -> Result<(String, u16), TrackerError> {
+    let url =
+        url::Url::parse(tracker_url).map_err(|_| TrackerError::InvalidUrl(tracker_url.into()))?;
+    let (Some(host), Some(port)) = (url.host_str(), url.port()) else {
+        return Err(TrackerError::InvalidUrl(tracker_url.into()));
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    Ok((host.to_string(), port))
+}
+
+fn bind_addr(tracker_addr: &SocketAddr) -> SocketAddr {
+    if tracker_addr.is_ipv6() {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    }
+}
+
+/// Builds request with a fresh transaction id, connect requests go without connection id
+fn request_packet(
+    connection_id: Option<u64>,
+    action: u32,
+    write_body: impl Fn(&mut Vec<u8>),
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(98);
+    packet.extend_from_slice(&connection_id.unwrap_or(PROTOCOL_ID).to_be_bytes());
+    packet.extend_from_slice(&action.to_be_bytes());
+    packet.extend_from_slice(&random_u32().to_be_bytes());
+    write_body(&mut packet);
+    packet
+}
+
+/// Returns response body after action and transaction id,
+/// `None` for late answers to previous transactions
+fn parse_response(
+    response: &[u8],
+    request: &[u8],
+    action: u32,
+) -> Option<Result<Vec<u8>, TrackerError>> {
+    // transaction id sits right after connection id and action
+    if response.len() < 8 || read_u32(response, 4) != read_u32(request, 12) {
+        return None;
+    }
+
+    let response_action = read_u32(response, 0);
+    if response_action == ACTION_ERROR {
+        let message = String::from_utf8_lossy(&response[8..]).to_string();
+        return Some(Err(TrackerError::Failure(message)));
+    }
+    if response_action != action {
+        return Some(Err(TrackerError::MalformedPacket));
+    }
+
+    Some(Ok(response[8..].to_vec()))
+}
+
+/// Parses connection id and remembers it for further requests to the tracker
+fn parse_connect_body(body: &[u8], tracker_addr: &SocketAddr) -> Result<u64, TrackerError> {
+    if body.len() < 8 {
+        return Err(TrackerError::MalformedPacket);
+    }
+
+    let connection_id = u64::from_be_bytes(body[..8].try_into().unwrap());
+    CONNECTION_IDS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .insert(*tracker_addr, (connection_id, Instant::now()));
+
+    Ok(connection_id)
+}
+
+fn cached_connection_id(tracker_addr: &SocketAddr) -> Option<u64> {
+    let connection_ids = CONNECTION_IDS.get_or_init(Default::default).lock().unwrap();
+
+    match connection_ids.get(tracker_addr) {
+        Some((connection_id, received_at)) if received_at.elapsed() < CONNECTION_ID_LIFETIME => {
+            Some(*connection_id)
+        }
+        _ => None,
+    }
+}
+
+fn write_announce_body(packet: &mut Vec<u8>, request: &AnnounceRequest) {
+    let event = match request.event {
+        None => 0,
+        Some(AnnounceEvent::Completed) => 1,
+        Some(AnnounceEvent::Started) => 2,
+        Some(AnnounceEvent::Stopped) => 3,
+    };
+
+    let client = request.client;
+    // only IPv4 can be announced, 0 means the address the packet came from
+    let ip = match client.ip {
+        Some(IpAddr::V4(ip)) => u32::from(ip),
+        _ => 0,
+    };
+    let numwant = client.numwant.map_or(-1, |numwant| numwant as i32);
+
+    packet.extend_from_slice(request.info_hash);
+    packet.extend_from_slice(&client.peer_id);
+    packet.extend_from_slice(&request.downloaded.to_be_bytes());
+    packet.extend_from_slice(&request.left.to_be_bytes());
+    packet.extend_from_slice(&request.uploaded.to_be_bytes());
+    packet.extend_from_slice(&(event as u32).to_be_bytes());
+    packet.extend_from_slice(&ip.to_be_bytes());
+    packet.extend_from_slice(&client.key.to_be_bytes());
+    packet.extend_from_slice(&numwant.to_be_bytes());
+    packet.extend_from_slice(&client.port.to_be_bytes());
+}
+
+/// Peers are 18 bytes long when announce went over IPv6
+fn parse_announce_body(body: &[u8], ipv6: bool) -> Result<AnnounceResponse, TrackerError> {
+    if body.len() < 12 {
+        return Err(TrackerError::MalformedPacket);
+    }
+
+    let peer_size = if ipv6 { 18 } else { 6 };
+    let peers = parse_compact_peers(&body[12..], peer_size).ok_or(TrackerError::MalformedPacket)?;
+
+    Ok(AnnounceResponse {
+        interval: Duration::from_secs(read_u32(body, 0) as u64),
+        min_interval: None,
+        complete: Some(read_u32(body, 8) as u64),
+        incomplete: Some(read_u32(body, 4) as u64),
+        tracker_id: None,
+        warning_message: None,
+        peers,
+    })
+}
+
+fn parse_scrape_body(
+    body: &[u8],
+    info_hashes_count: usize,
+) -> Result<Vec<ScrapeStats>, TrackerError> {
+    if body.len() < info_hashes_count * 12 {
+        return Err(TrackerError::MalformedPacket);
+    }
+
+    Ok(body
+        .chunks_exact(12)
+        .take(info_hashes_count)
+        .map(|stats| ScrapeStats {
+            complete: read_u32(stats, 0) as u64,
+            downloaded: read_u32(stats, 4) as u64,
+            incomplete: read_u32(stats, 8) as u64,
+        })
+        .collect())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
+    };
+
+    use super::*;
+    use crate::client_config::ClientConfig;
+
+    const CONNECTION_ID: u64 = 0x0123_4567_89ab_cdef;
+
+    /// Local stand-in for a UDP tracker, `respond` gets each request and answers it
+    /// unless it returns `None`. Every request is kept for inspection.
+    fn stand_in_tracker(
+        respond: impl Fn(&[u8]) -> Option<Vec<u8>> + Send + 'static,
+    ) -> (UdpTracker, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let address = socket.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let received = requests.clone();
+        thread::spawn(move || {
+            let mut buf = [0; MAX_PACKET_SIZE];
+            while let Ok((size, from)) = socket.recv_from(&mut buf) {
+                let request = buf[..size].to_vec();
+                received.lock().unwrap().push(request.clone());
+                if let Some(response) = respond(&request) {
+                    socket.send_to(&response, from).unwrap();
+                }
+            }
+        });
+
+        let mut tracker = UdpTracker::new(&format!("udp://{}/announce", address)).unwrap();
+        tracker.base_timeout = Duration::from_millis(100);
+        tracker.max_retransmissions = 2;
+        (tracker, requests)
+    }
+
+    /// Response header echoing transaction id of the request
+    fn response(request: &[u8], action: u32) -> Vec<u8> {
+        let mut response = action.to_be_bytes().to_vec();
+        response.extend_from_slice(&request[12..16]);
+        response
+    }
+
+    fn connect_response(request: &[u8]) -> Vec<u8> {
+        let mut response = response(request, ACTION_CONNECT);
+        response.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+        response
+    }
+
+    fn action(request: &[u8]) -> u32 {
+        read_u32(request, 8)
+    }
+
+    fn announce_request<'a>(info_hash: &'a [u8], client: &'a ClientConfig) -> AnnounceRequest<'a> {
+        AnnounceRequest {
+            info_hash,
+            uploaded: 1,
+            downloaded: 2,
+            left: 3,
+            event: Some(AnnounceEvent::Started),
+            tracker_id: None,
+            client,
+        }
+    }
+
+    #[test]
+    fn announce_connects_first() {
+        let (tracker, requests) = stand_in_tracker(|request| match action(request) {
+            ACTION_CONNECT => Some(connect_response(request)),
+            ACTION_ANNOUNCE => {
+                let mut response = response(request, ACTION_ANNOUNCE);
+                response.extend_from_slice(&1800u32.to_be_bytes());
+                response.extend_from_slice(&2u32.to_be_bytes());
+                response.extend_from_slice(&3u32.to_be_bytes());
+                response.extend_from_slice(&[127, 0, 0, 1, 0x1a, 0xe1]);
+                response.extend_from_slice(&[10, 0, 0, 1, 0xc8, 0xd5]);
+                Some(response)
+            }
+            _ => None,
+        });
+        let client = ClientConfig::default();
+
+        let response = tracker
+            .announce(&announce_request(&[7; 20], &client))
+            .unwrap();
+
+        assert_eq!(response.interval, Duration::from_secs(1800));
+        assert_eq!(response.incomplete, Some(2));
+        assert_eq!(response.complete, Some(3));
+        assert_eq!(
+            response.peers,
+            vec![
+                "127.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+                "10.0.0.1:51413".parse().unwrap()
+            ]
+        );
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0][..8], PROTOCOL_ID.to_be_bytes());
+        assert_eq!(action(&requests[0]), ACTION_CONNECT);
+        let announce = &requests[1];
+        assert_eq!(announce.len(), 98);
+        assert_eq!(announce[..8], CONNECTION_ID.to_be_bytes());
+        assert_eq!(announce[16..36], [7; 20]);
+        assert_eq!(announce[36..56], client.peer_id);
+        assert_eq!(u64::from_be_bytes(announce[64..72].try_into().unwrap()), 3);
+        // started event
+        assert_eq!(read_u32(announce, 80), 2);
+        assert_eq!(announce[96..98], client.port.to_be_bytes());
+    }
+
+    #[test]
+    fn scrape_returns_stats_in_request_order() {
+        let (tracker, requests) = stand_in_tracker(|request| match action(request) {
+            ACTION_CONNECT => Some(connect_response(request)),
+            ACTION_SCRAPE => {
+                let mut response = response(request, ACTION_SCRAPE);
+                for (complete, downloaded, incomplete) in [(5u32, 10u32, 1u32), (0, 0, 4)] {
+                    response.extend_from_slice(&complete.to_be_bytes());
+                    response.extend_from_slice(&downloaded.to_be_bytes());
+                    response.extend_from_slice(&incomplete.to_be_bytes());
+                }
+                Some(response)
+            }
+            _ => None,
+        });
+
+        let stats = tracker.scrape(&[&[1; 20], &[2; 20]]).unwrap();
+
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    complete: 5,
+                    downloaded: 10,
+                    incomplete: 1
+                },
+                ScrapeStats {
+                    complete: 0,
+                    downloaded: 0,
+                    incomplete: 4
+                }
+            ]
+        );
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[1][16..36], [1; 20]);
+        assert_eq!(requests[1][36..56], [2; 20]);
+    }
+
+    #[test]
+    fn lost_connect_is_retransmitted() {
+        let connects = Mutex::new(0);
+        let (tracker, requests) = stand_in_tracker(move |request| match action(request) {
+            ACTION_CONNECT => {
+                let mut connects = connects.lock().unwrap();
+                *connects += 1;
+                // first connect request gets lost
+                (*connects > 1).then(|| connect_response(request))
+            }
+            ACTION_SCRAPE => {
+                let mut response = response(request, ACTION_SCRAPE);
+                response.extend_from_slice(&[0; 12]);
+                Some(response)
+            }
+            _ => None,
+        });
+
+        tracker.scrape(&[&[1; 20]]).unwrap();
+
+        let requests = requests.lock().unwrap();
+        let actions: Vec<_> = requests.iter().map(|request| action(request)).collect();
+        assert_eq!(actions, vec![ACTION_CONNECT, ACTION_CONNECT, ACTION_SCRAPE]);
+        // retransmission is a new transaction
+        assert_ne!(requests[0][12..16], requests[1][12..16]);
+    }
+
+    #[test]
+    fn gives_up_after_max_retransmissions() {
+        let (tracker, requests) = stand_in_tracker(|_| None);
+
+        let result = tracker.scrape(&[&[1; 20]]);
+
+        assert!(matches!(result, Err(TrackerError::Timeout)));
+        assert_eq!(
+            requests.lock().unwrap().len(),
+            tracker.max_retransmissions as usize + 1
+        );
+    }
+
+    #[test]
+    fn deadline_cuts_retransmissions_short() {
+        let (mut tracker, requests) = stand_in_tracker(|_| None);
+        tracker.max_retransmissions = 8;
+        tracker.deadline = Some(Duration::from_millis(500));
+
+        let started = Instant::now();
+        let result = tracker.scrape(&[&[1; 20]]);
+
+        assert!(matches!(result, Err(TrackerError::Timeout)));
+        // full schedule would take 100ms * (2 ^ 9 - 1)
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(requests.lock().unwrap().len() < 9);
+    }
+
+    #[test]
+    fn error_response_is_failure() {
+        let (tracker, _) = stand_in_tracker(|request| match action(request) {
+            ACTION_CONNECT => Some(connect_response(request)),
+            _ => {
+                let mut response = response(request, ACTION_ERROR);
+                response.extend_from_slice(b"unregistered torrent");
+                Some(response)
+            }
+        });
+        let client = ClientConfig::default();
+
+        let result = tracker.announce(&announce_request(&[7; 20], &client));
+
+        assert!(
+            matches!(result, Err(TrackerError::Failure(message)) if message == "unregistered torrent")
+        );
+    }
+
+    #[test]
+    fn late_response_to_other_transaction_is_ignored() {
+        let request = request_packet(Some(CONNECTION_ID), ACTION_SCRAPE, |_| {});
+        let mut late = ACTION_SCRAPE.to_be_bytes().to_vec();
+        late.extend_from_slice(&(read_u32(&request, 12).wrapping_add(1)).to_be_bytes());
+
+        assert!(parse_response(&late, &request, ACTION_SCRAPE).is_none());
+    }
+}
@@ -0,0 +1,195 @@
+use std::{collections::VecDeque, net::IpAddr, sync::Arc};
+
+use tokio::{
+    net::tcp::OwnedWriteHalf,
+    sync::broadcast::error::RecvError,
+    time::{self, Instant},
+};
+
+use crate::{
+    async_peer_connection::{AsyncPeerConnection, PeerWriter},
+    choker::Choker,
+    peer_connection::{allowed_fast_set, Message, PeerError},
+    piece_store::PieceStore,
+    pieces::BLOCK_SIZE,
+    tracker_session::TransferStats,
+};
+
+/// Requests queued per peer, further requests are dropped until we catch up
+pub const MAX_PENDING_REQUESTS: usize = 250;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BlockRequest {
+    index: u32,
+    begin: u32,
+    length: u32,
+}
+
+/// Uploads pieces of `store` to a connected peer until either side disconnects,
+/// `choker` decides when the peer may download
+pub async fn serve_peer(
+    connection: AsyncPeerConnection,
+    store: Arc<PieceStore>,
+    stats: Arc<TransferStats>,
+    choker: &Arc<Choker>,
+) -> Result<(), PeerError> {
+    let keep_alive = connection.timeouts.keep_alive;
+    let fast = connection.fast_enabled;
+    let allowed_fast = match connection.peer.ip() {
+        IpAddr::V4(ip) if fast => allowed_fast_set(ip, &connection.info_hash, store.pieces_count()),
+        _ => Vec::new(),
+    };
+    let mut choker_peer = choker.add_peer(connection.peer.ip());
+    let mut have_updates = store.subscribe();
+    let (mut reader, writer) = connection.into_split();
+    let mut writer = TimedWriter {
+        writer,
+        last_sent: Instant::now(),
+    };
+
+    // peers having nothing may skip the bitfield (BEP 3), unless the fast extension is on
+    let have_count = store.have_count();
+    if fast && have_count == store.pieces_count() {
+        writer.send(&Message::HaveAll).await?;
+    } else if fast && have_count == 0 {
+        writer.send(&Message::HaveNone).await?;
+    } else if have_count > 0 {
+        writer.send(&Message::Bitfield(store.bitfield())).await?;
+    }
+    for index in &allowed_fast {
+        if store.has_piece(*index as usize) {
+            writer.send(&Message::AllowedFast { index: *index }).await?;
+        }
+    }
+
+    let mut choked = true;
+    let mut requests: VecDeque<BlockRequest> = VecDeque::new();
+
+    loop {
+        tokio::select! {
+            // reading is cancel safe, a partially received message stays buffered
+            message = reader.read_message() => match message? {
+                Message::Interested => choker_peer.set_interested(true),
+                Message::NotInterested => choker_peer.set_interested(false),
+                Message::Request { index, begin, length } => {
+                    let request = BlockRequest { index, begin, length };
+                    check_request(&store, &request)?;
+                    let allowed = !choked || allowed_fast.contains(&index);
+                    if allowed && requests.len() < MAX_PENDING_REQUESTS {
+                        requests.push_back(request);
+                    } else if fast {
+                        writer.send(&request.reject()).await?;
+                    }
+                    // without the fast extension requests of choked peers are just discarded
+                }
+                Message::Cancel { index, begin, length } => {
+                    let cancelled = BlockRequest { index, begin, length };
+                    let pending = requests.len();
+                    requests.retain(|request| *request != cancelled);
+                    // every request gets a piece or a reject with the fast extension
+                    if fast && requests.len() < pending {
+                        writer.send(&cancelled.reject()).await?;
+                    }
+                }
+                _ => {}
+            },
+            Ok(()) = choker_peer.choked.changed() => {
+                choked = *choker_peer.choked.borrow_and_update();
+                if choked {
+                    writer.send(&Message::Choke).await?;
+                    // choking discards all requests we didn't answer yet (BEP 3),
+                    // with the fast extension allowed fast ones stay and the rest is rejected
+                    let (kept, rejected) = requests
+                        .drain(..)
+                        .partition(|request| fast && allowed_fast.contains(&request.index));
+                    requests = kept;
+                    if fast {
+                        for request in rejected {
+                            writer.send(&request.reject()).await?;
+                        }
+                    }
+                } else {
+                    writer.send(&Message::Unchoke).await?;
+                }
+            }
+            update = have_updates.recv() => match update {
+                Ok(index) => writer.send(&Message::Have { index }).await?,
+                Err(RecvError::Lagged(_)) => {
+                    for index in (0..store.pieces_count()).filter(|index| store.has_piece(*index)) {
+                        writer.send(&Message::Have { index: index as u32 }).await?;
+                    }
+                }
+                Err(RecvError::Closed) => {}
+            },
+            _ = time::sleep_until(writer.last_sent + keep_alive) => {
+                writer.send(&Message::KeepAlive).await?;
+            }
+            // a request is served once no message is waiting, so cancels arrive in time
+            _ = async {}, if !requests.is_empty() => {
+                let request = requests.pop_front().unwrap();
+                let block_store = store.clone();
+                let block = tokio::task::spawn_blocking(move || {
+                    block_store.read_block(
+                        request.index as usize,
+                        request.begin as usize,
+                        request.length as usize,
+                    )
+                })
+                .await
+                .expect("Block reading panicked")?;
+
+                stats.record_upload(block.len() as u64);
+                choker_peer.record_upload(block.len() as u64);
+                writer
+                    .send(&Message::Piece {
+                        index: request.index,
+                        begin: request.begin,
+                        block,
+                    })
+                    .await?;
+            }
+        }
+    }
+}
+
+impl BlockRequest {
+    fn reject(&self) -> Message {
+        Message::RejectRequest {
+            index: self.index,
+            begin: self.begin,
+            length: self.length,
+        }
+    }
+}
+
+/// Requests for pieces we don't have or beyond their end are protocol violations
+fn check_request(store: &PieceStore, request: &BlockRequest) -> Result<(), PeerError> {
+    let index = request.index as usize;
+    let (begin, length) = (request.begin as usize, request.length as usize);
+
+    if !store.has_piece(index)
+        || length == 0
+        || length > BLOCK_SIZE
+        || begin + length > store.piece_size(index)
+    {
+        return Err(PeerError::ProtocolViolation(format!(
+            "invalid request of {} bytes at {} in piece {}",
+            length, begin, index
+        )));
+    }
+    Ok(())
+}
+
+/// Remembers when we sent anything, keep-alives are only needed in silence
+struct TimedWriter {
+    writer: PeerWriter<OwnedWriteHalf>,
+    last_sent: Instant,
+}
+
+impl TimedWriter {
+    async fn send(&mut self, message: &Message) -> Result<(), PeerError> {
+        self.writer.send_message(message).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
@@ -0,0 +1,980 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{
+        mpsc::{self, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bencode::decode_bencoded_value,
+    client_config::ClientConfig,
+    extensions::{Dispatched, Extension, ExtensionHandshake, ExtensionRegistry},
+    peer_connection::{Message, PeerConnection, PeerError, PeerTimeouts},
+    sha1_it,
+};
+
+/// Metadata is exchanged in pieces of 16 KiB, only the last one can be shorter (BEP 9)
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+/// Upper bound for `metadata_size` announced by a peer, protects us from allocating
+/// whatever a malicious peer asks for
+pub const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+#[derive(Debug)]
+pub enum MetadataError {
+    ExtensionNotSupported,
+    MissingMetadataSize,
+    MetadataTooLarge(usize),
+    PieceRejected(usize),
+    InvalidMessage,
+    InvalidPiece(usize),
+    HashMismatch,
+    /// info dictionary lacks a key we need, e.g. `length` of multi-file torrents
+    InvalidInfo(&'static str),
+    NoPeerSucceeded,
+    Peer(PeerError),
+}
+
+impl From<PeerError> for MetadataError {
+    fn from(e: PeerError) -> Self {
+        MetadataError::Peer(e)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct MetadataMessagePayload {
+    msg_type: i64,
+    piece: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MetadataDataPayload {
+    msg_type: i64,
+    piece: i64,
+    total_size: i64,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MetadataMessage {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: usize,
+    },
+}
+
+impl MetadataMessage {
+    /// Serializes message into ut_metadata payload (without the extended message id)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            MetadataMessage::Request { piece } => {
+                serde_bencode::to_bytes(&MetadataMessagePayload {
+                    msg_type: REQUEST,
+                    piece: *piece as i64,
+                })
+                .unwrap()
+            }
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                let mut bytes = serde_bencode::to_bytes(&MetadataDataPayload {
+                    msg_type: DATA,
+                    piece: *piece as i64,
+                    total_size: *total_size as i64,
+                })
+                .unwrap();
+                bytes.extend_from_slice(data);
+                bytes
+            }
+            MetadataMessage::Reject { piece } => serde_bencode::to_bytes(&MetadataMessagePayload {
+                msg_type: REJECT,
+                piece: *piece as i64,
+            })
+            .unwrap(),
+        }
+    }
+
+    /// Parses ut_metadata message payload (without the extended message id)
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetadataError> {
+        // the bencoded dictionary is followed by raw piece data in `data` messages,
+        // so we need to know where the dictionary ends
+        let mut iterator = bytes.iter().copied();
+        let dict =
+            decode_bencoded_value(&mut iterator).map_err(|_| MetadataError::InvalidMessage)?;
+        let trailing = &bytes[bytes.len() - iterator.len()..];
+
+        let msg_type = dict["msg_type"]
+            .as_i64()
+            .ok_or(MetadataError::InvalidMessage)?;
+        let piece = dict["piece"]
+            .as_i64()
+            .and_then(|piece| usize::try_from(piece).ok())
+            .ok_or(MetadataError::InvalidMessage)?;
+
+        match msg_type {
+            REQUEST => Ok(MetadataMessage::Request { piece }),
+            DATA => {
+                let total_size = dict["total_size"]
+                    .as_i64()
+                    .and_then(|size| usize::try_from(size).ok())
+                    .ok_or(MetadataError::InvalidMessage)?;
+                Ok(MetadataMessage::Data {
+                    piece,
+                    total_size,
+                    data: trailing.to_vec(),
+                })
+            }
+            REJECT => Ok(MetadataMessage::Reject { piece }),
+            _ => Err(MetadataError::InvalidMessage),
+        }
+    }
+}
+
+/// How many peers we ask for metadata at the same time
+pub const MAX_METADATA_PEERS: usize = 8;
+
+/// Peer which doesn't deliver any piece for this long is replaced by another one
+pub const METADATA_PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outstanding ut_metadata requests per peer
+const MAX_IN_FLIGHT_PIECES: usize = 2;
+
+/// Metadata download shared by all peer workers, pieces are handed out one by one
+/// so a single magnet is resolved by several peers in parallel
+struct MetadataSwarm {
+    info_hash: Vec<u8>,
+    client: ClientConfig,
+    peer_timeouts: PeerTimeouts,
+    state: Mutex<SwarmState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct SwarmState {
+    metadata_size: Option<usize>,
+    pieces: Vec<Option<Vec<u8>>>,
+    // worker which provided the piece, used to ban peers after hash mismatch
+    providers: Vec<Option<usize>>,
+    pending: Vec<usize>,
+    assigned: HashMap<usize, usize>,
+    last_progress: HashMap<usize, Instant>,
+    dropped_workers: HashSet<usize>,
+    metadata: Option<Vec<u8>>,
+}
+
+impl MetadataSwarm {
+    fn new(info_hash: &[u8], client: &ClientConfig) -> Self {
+        MetadataSwarm {
+            info_hash: info_hash.to_vec(),
+            client: client.clone(),
+            // blocked read turns into an error instead of keeping the thread around forever
+            peer_timeouts: PeerTimeouts {
+                idle: METADATA_PEER_TIMEOUT,
+                ..client.peer_timeouts
+            },
+            state: Mutex::new(SwarmState::default()),
+            changed: Condvar::new(),
+        }
+    }
+
+    fn metadata(&self) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().metadata.clone()
+    }
+
+    fn start_worker(&self, worker: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.last_progress.insert(worker, Instant::now());
+    }
+
+    /// Hands out next missing piece, blocks while all missing pieces are assigned to other peers.
+    /// Returns `None` when the worker should stop.
+    fn take_piece(
+        &self,
+        worker: usize,
+        metadata_size: usize,
+        wait: bool,
+    ) -> Result<Option<usize>, MetadataError> {
+        let mut state = self.state.lock().unwrap();
+
+        loop {
+            if state.metadata.is_some() || state.dropped_workers.contains(&worker) {
+                return Ok(None);
+            }
+
+            match state.metadata_size {
+                None => {
+                    let pieces_count = metadata_size.div_ceil(METADATA_PIECE_SIZE);
+                    state.metadata_size = Some(metadata_size);
+                    state.pieces = vec![None; pieces_count];
+                    state.providers = vec![None; pieces_count];
+                    state.pending = (0..pieces_count).rev().collect();
+                }
+                Some(size) if size != metadata_size => {
+                    return Err(MetadataError::InvalidMessage);
+                }
+                Some(_) => {}
+            }
+
+            if let Some(piece) = state.pending.pop() {
+                state.assigned.insert(piece, worker);
+                return Ok(Some(piece));
+            }
+
+            if !wait {
+                return Ok(None);
+            }
+
+            // waiting for other peers is not being slow
+            if let Some(last_progress) = state.last_progress.get_mut(&worker) {
+                *last_progress = Instant::now();
+            }
+
+            state = self
+                .changed
+                .wait_timeout(state, Duration::from_secs(1))
+                .unwrap()
+                .0;
+        }
+    }
+
+    fn store_piece(&self, worker: usize, piece: usize, data: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.assigned.get(&piece) != Some(&worker) {
+            // piece was reassigned after this worker timed out
+            return;
+        }
+        state.assigned.remove(&piece);
+        state.pieces[piece] = Some(data);
+        state.providers[piece] = Some(worker);
+        state.last_progress.insert(worker, Instant::now());
+
+        if state.pieces.iter().all(Option::is_some) {
+            let metadata: Vec<u8> = state.pieces.iter().flatten().flatten().copied().collect();
+
+            if sha1_it(&metadata) == self.info_hash {
+                state.metadata = Some(metadata);
+            } else {
+                println!("Metadata hash mismatch, dropping peers which provided it");
+                let providers: Vec<_> = state.providers.iter().flatten().copied().collect();
+                state.dropped_workers.extend(providers);
+                state.metadata_size = None;
+                state.pieces.clear();
+                state.providers.clear();
+                state.pending.clear();
+                state.assigned.clear();
+            }
+        }
+
+        self.changed.notify_all();
+    }
+
+    /// Returns pieces of a finished or timed out worker back to the pool
+    fn release_worker(&self, worker: usize) {
+        let mut state = self.state.lock().unwrap();
+
+        let pieces: Vec<_> = state
+            .assigned
+            .iter()
+            .filter(|(_, assigned_worker)| **assigned_worker == worker)
+            .map(|(piece, _)| *piece)
+            .collect();
+        for piece in pieces {
+            state.assigned.remove(&piece);
+            state.pending.push(piece);
+        }
+        state.last_progress.remove(&worker);
+
+        self.changed.notify_all();
+    }
+
+    /// Drops workers which didn't deliver anything for `METADATA_PEER_TIMEOUT`
+    fn drop_slow_workers(&self) -> Vec<usize> {
+        let slow_workers: Vec<_> = {
+            let mut state = self.state.lock().unwrap();
+            let slow_workers: Vec<_> = state
+                .last_progress
+                .iter()
+                .filter(|(_, last_progress)| last_progress.elapsed() > METADATA_PEER_TIMEOUT)
+                .map(|(worker, _)| *worker)
+                .collect();
+            state.dropped_workers.extend(slow_workers.iter().copied());
+            slow_workers
+        };
+
+        for worker in &slow_workers {
+            self.release_worker(*worker);
+        }
+
+        slow_workers
+    }
+}
+
+/// Notifies the coordinator when worker thread ends, also when it panics
+struct WorkerGuard {
+    swarm: Arc<MetadataSwarm>,
+    worker: usize,
+    finished: Sender<usize>,
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.swarm.release_worker(self.worker);
+        let _ = self.finished.send(self.worker);
+    }
+}
+
+/// Downloads metadata pieces from a single peer until the whole info dictionary is known
+fn metadata_worker(
+    swarm: &MetadataSwarm,
+    worker: usize,
+    peer: &SocketAddr,
+) -> Result<(), MetadataError> {
+    let mut peer_connection = PeerConnection::handshake(
+        peer,
+        &swarm.info_hash,
+        &swarm.client.peer_id,
+        true,
+        swarm.peer_timeouts,
+        swarm.client.encryption,
+    )?;
+
+    if !peer_connection.extension_enabled {
+        return Err(MetadataError::ExtensionNotSupported);
+    }
+
+    // the exchange is driven from here, ut_metadata messages come back to us
+    let mut extensions = ExtensionRegistry::new();
+    let metadata_id = extensions.advertise("ut_metadata");
+    extensions.send_handshake(&mut peer_connection, &swarm.client)?;
+
+    // peer can send bitfield and other messages before the extension handshake
+    let handshake = loop {
+        let message = peer_connection.read_message()?;
+        extensions.handle_message(&mut peer_connection, &message)?;
+        if let Some(handshake) = extensions.peer_handshake() {
+            break handshake.clone();
+        }
+    };
+
+    let peer_extension_id = handshake
+        .extension_id("ut_metadata")
+        .ok_or(MetadataError::ExtensionNotSupported)?;
+
+    let metadata_size = handshake
+        .metadata_size
+        .and_then(|size| usize::try_from(size).ok())
+        .filter(|size| *size > 0)
+        .ok_or(MetadataError::MissingMetadataSize)?;
+
+    if metadata_size > MAX_METADATA_SIZE {
+        return Err(MetadataError::MetadataTooLarge(metadata_size));
+    }
+
+    let pieces_count = metadata_size.div_ceil(METADATA_PIECE_SIZE);
+    let mut in_flight: Vec<usize> = Vec::with_capacity(MAX_IN_FLIGHT_PIECES);
+
+    loop {
+        while in_flight.len() < MAX_IN_FLIGHT_PIECES {
+            // wait for pieces only when we have nothing else to read
+            match swarm.take_piece(worker, metadata_size, in_flight.is_empty())? {
+                Some(piece) => {
+                    send_metadata_message(
+                        &mut peer_connection,
+                        peer_extension_id,
+                        MetadataMessage::Request { piece },
+                    )?;
+                    in_flight.push(piece);
+                }
+                None => break,
+            }
+        }
+
+        if in_flight.is_empty() {
+            return Ok(());
+        }
+
+        let message = peer_connection.read_message()?;
+        let payload = match extensions.handle_message(&mut peer_connection, &message)? {
+            Dispatched::Advertised { id, payload } if id == metadata_id => payload,
+            _ => continue,
+        };
+
+        match MetadataMessage::from_bytes(payload)? {
+            MetadataMessage::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                if !in_flight.contains(&piece)
+                    || piece >= pieces_count
+                    || total_size != metadata_size
+                    || data.len() != expected_piece_size(metadata_size, piece)
+                {
+                    return Err(MetadataError::InvalidPiece(piece));
+                }
+                in_flight.retain(|in_flight_piece| *in_flight_piece != piece);
+                swarm.store_piece(worker, piece, data);
+            }
+            MetadataMessage::Reject { piece } => return Err(MetadataError::PieceRejected(piece)),
+            // we don't have the metadata ourselves yet
+            MetadataMessage::Request { piece } => send_metadata_message(
+                &mut peer_connection,
+                peer_extension_id,
+                MetadataMessage::Reject { piece },
+            )?,
+        }
+    }
+}
+
+/// Resolves info dictionary by asking several peers at once, metadata pieces are spread
+/// between them, peers without ut_metadata or too slow are replaced by the next ones
+pub fn fetch_metadata_from_peers(
+    client: &ClientConfig,
+    peers: &[SocketAddr],
+    info_hash: &[u8],
+) -> Result<Vec<u8>, MetadataError> {
+    let swarm = Arc::new(MetadataSwarm::new(info_hash, client));
+    let (finished_sender, finished_receiver) = mpsc::channel();
+
+    let mut peers = peers.iter().cloned().enumerate();
+    let mut active_workers = HashSet::new();
+
+    loop {
+        if let Some(metadata) = swarm.metadata() {
+            return Ok(metadata);
+        }
+
+        while active_workers.len() < MAX_METADATA_PEERS {
+            let Some((worker, peer)) = peers.next() else {
+                break;
+            };
+
+            swarm.start_worker(worker);
+            let guard = WorkerGuard {
+                swarm: swarm.clone(),
+                worker,
+                finished: finished_sender.clone(),
+            };
+            thread::spawn(move || {
+                if let Err(e) = metadata_worker(&guard.swarm, worker, &peer) {
+                    println!("Failed to fetch metadata from peer {}: {:?}", peer, e);
+                }
+            });
+            active_workers.insert(worker);
+        }
+
+        if active_workers.is_empty() {
+            return Err(MetadataError::NoPeerSucceeded);
+        }
+
+        match finished_receiver.recv_timeout(Duration::from_secs(1)) {
+            Ok(worker) => {
+                active_workers.remove(&worker);
+            }
+            Err(_) => {
+                for worker in swarm.drop_slow_workers() {
+                    active_workers.remove(&worker);
+                }
+            }
+        }
+    }
+}
+
+/// Answers ut_metadata requests of a peer once we know the info dictionary
+pub struct MetadataServer<'a> {
+    metadata: &'a [u8],
+    peer_extension_id: Option<u8>,
+}
+
+impl<'a> MetadataServer<'a> {
+    pub fn new(metadata: &'a [u8]) -> Self {
+        MetadataServer {
+            metadata,
+            peer_extension_id: None,
+        }
+    }
+
+    fn metadata_piece(&self, piece: usize) -> Option<&[u8]> {
+        let start = piece.checked_mul(METADATA_PIECE_SIZE)?;
+        if start >= self.metadata.len() {
+            return None;
+        }
+        let end = start + expected_piece_size(self.metadata.len(), piece);
+        Some(&self.metadata[start..end])
+    }
+}
+
+impl Extension for MetadataServer<'_> {
+    fn name(&self) -> &'static str {
+        "ut_metadata"
+    }
+
+    fn extend_handshake(&self, handshake: &mut ExtensionHandshake) {
+        handshake.metadata_size = Some(self.metadata.len() as i64);
+    }
+
+    fn on_handshake(
+        &mut self,
+        _connection: &mut PeerConnection,
+        _handshake: &ExtensionHandshake,
+        peer_extension_id: Option<u8>,
+    ) -> Result<(), PeerError> {
+        self.peer_extension_id = peer_extension_id;
+        Ok(())
+    }
+
+    fn on_message(
+        &mut self,
+        connection: &mut PeerConnection,
+        payload: &[u8],
+    ) -> Result<(), PeerError> {
+        if let (Some(peer_extension_id), Ok(MetadataMessage::Request { piece })) =
+            (self.peer_extension_id, MetadataMessage::from_bytes(payload))
+        {
+            let response = match self.metadata_piece(piece) {
+                Some(data) => MetadataMessage::Data {
+                    piece,
+                    total_size: self.metadata.len(),
+                    data: data.to_vec(),
+                },
+                None => MetadataMessage::Reject { piece },
+            };
+            send_metadata_message(connection, peer_extension_id, response)?;
+        }
+        Ok(())
+    }
+}
+
+fn send_metadata_message(
+    connection: &mut PeerConnection,
+    peer_extension_id: u8,
+    message: MetadataMessage,
+) -> Result<(), PeerError> {
+    connection.send_message(&Message::Extended {
+        id: peer_extension_id,
+        payload: message.to_bytes(),
+    })
+}
+
+fn expected_piece_size(metadata_size: usize, piece: usize) -> usize {
+    usize::min(
+        METADATA_PIECE_SIZE,
+        metadata_size - piece * METADATA_PIECE_SIZE,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        mse::EncryptionPolicy,
+        peer_connection::{message_length, Handshake, HANDSHAKE_LENGTH},
+    };
+
+    /// Our ut_metadata id in the handshake of the stand-in peer
+    const STAND_IN_METADATA_ID: u8 = 3;
+
+    fn client() -> ClientConfig {
+        ClientConfig {
+            encryption: EncryptionPolicy::Disable,
+            peer_timeouts: PeerTimeouts {
+                connect: Duration::from_secs(2),
+                read: Duration::from_secs(2),
+                idle: Duration::from_secs(5),
+                keep_alive: Duration::from_secs(5),
+            },
+            ..Default::default()
+        }
+    }
+
+    fn metadata(length: usize) -> Vec<u8> {
+        let mut metadata = format!("d4:name{}:", length - 9).into_bytes();
+        metadata.resize(length - 1, b'x');
+        metadata.push(b'e');
+        metadata
+    }
+
+    fn send(stream: &mut TcpStream, message: &Message) {
+        stream.write_all(&message.encode()).unwrap();
+    }
+
+    fn receive(stream: &mut TcpStream) -> Option<Message> {
+        let mut prefix = [0; 4];
+        stream.read_exact(&mut prefix).ok()?;
+        let mut frame = vec![0; message_length(prefix).ok()?];
+        stream.read_exact(&mut frame).ok()?;
+        Message::decode(&frame).ok()
+    }
+
+    /// Handshakes with the peer connecting to us and returns its extension handshake
+    fn accept_peer(
+        stream: &mut TcpStream,
+        info_hash: &[u8],
+        metadata_size: Option<usize>,
+    ) -> ExtensionHandshake {
+        let mut buf = [0; HANDSHAKE_LENGTH];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(Handshake::decode(&buf).unwrap().info_hash, info_hash);
+        stream
+            .write_all(&Handshake::new(info_hash, &[9; 20], true).encode())
+            .unwrap();
+
+        let handshake = ExtensionHandshake {
+            m: [(String::from("ut_metadata"), STAND_IN_METADATA_ID as i64)].into(),
+            metadata_size: metadata_size.map(|size| size as i64),
+            ..Default::default()
+        };
+        send(
+            stream,
+            &Message::Extended {
+                id: 0,
+                payload: serde_bencode::to_bytes(&handshake).unwrap(),
+            },
+        );
+
+        loop {
+            if let Some(Message::Extended { id: 0, payload }) = receive(stream) {
+                return serde_bencode::from_bytes(&payload).unwrap();
+            }
+        }
+    }
+
+    /// Local peer answering ut_metadata requests with `respond`, `metadata_size` is the
+    /// size announced in its extension handshake
+    fn stand_in_peer(
+        info_hash: Vec<u8>,
+        metadata_size: usize,
+        respond: impl Fn(usize) -> MetadataMessage + Send + Sync + 'static,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let respond = Arc::new(respond);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (mut stream, info_hash, respond) =
+                    (stream.unwrap(), info_hash.clone(), respond.clone());
+                thread::spawn(move || {
+                    let handshake = accept_peer(&mut stream, &info_hash, Some(metadata_size));
+                    let peer_metadata_id = handshake.extension_id("ut_metadata").unwrap();
+                    while let Some(message) = receive(&mut stream) {
+                        let Message::Extended { id, payload } = message else {
+                            continue;
+                        };
+                        assert_eq!(id, STAND_IN_METADATA_ID);
+                        if let Ok(MetadataMessage::Request { piece }) =
+                            MetadataMessage::from_bytes(&payload)
+                        {
+                            let response = Message::Extended {
+                                id: peer_metadata_id,
+                                payload: respond(piece).to_bytes(),
+                            };
+                            send(&mut stream, &response);
+                        }
+                    }
+                });
+            }
+        });
+
+        address
+    }
+
+    /// Stand-in peer serving `metadata` the way a well behaved peer does
+    fn serving_peer(metadata: Vec<u8>) -> SocketAddr {
+        let info_hash = sha1_it(&metadata);
+        let metadata_size = metadata.len();
+        stand_in_peer(info_hash, metadata_size, move |piece| {
+            let start = piece * METADATA_PIECE_SIZE;
+            match metadata.get(start..start + expected_piece_size(metadata.len(), piece)) {
+                Some(data) => MetadataMessage::Data {
+                    piece,
+                    total_size: metadata.len(),
+                    data: data.to_vec(),
+                },
+                None => MetadataMessage::Reject { piece },
+            }
+        })
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        for message in [
+            MetadataMessage::Request { piece: 0 },
+            MetadataMessage::Reject { piece: 7 },
+            MetadataMessage::Data {
+                piece: 1,
+                total_size: 20000,
+                // trailing data may look like bencode itself
+                data: b"d1:ai1ee".repeat(10),
+            },
+        ] {
+            assert_eq!(
+                MetadataMessage::from_bytes(&message.to_bytes()).unwrap(),
+                message
+            );
+        }
+
+        assert_eq!(
+            MetadataMessage::Request { piece: 2 }.to_bytes(),
+            b"d8:msg_typei0e5:piecei2ee"
+        );
+        assert_eq!(
+            MetadataMessage::from_bytes(b"d8:msg_typei1e5:piecei0e10:total_sizei3eeabc").unwrap(),
+            MetadataMessage::Data {
+                piece: 0,
+                total_size: 3,
+                data: b"abc".to_vec()
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_messages_are_rejected() {
+        for payload in [
+            &b"d8:msg_typei3e5:piecei0ee"[..],
+            b"d8:msg_typei0ee",
+            b"d8:msg_typei0e5:piecei-1ee",
+            b"d8:msg_typei1e5:piecei0ee",
+            b"garbage",
+        ] {
+            assert!(matches!(
+                MetadataMessage::from_bytes(payload),
+                Err(MetadataError::InvalidMessage)
+            ));
+        }
+    }
+
+    #[test]
+    fn only_last_piece_is_shorter() {
+        let metadata_size = 2 * METADATA_PIECE_SIZE + 100;
+        assert_eq!(expected_piece_size(metadata_size, 0), METADATA_PIECE_SIZE);
+        assert_eq!(expected_piece_size(metadata_size, 1), METADATA_PIECE_SIZE);
+        assert_eq!(expected_piece_size(metadata_size, 2), 100);
+        assert_eq!(
+            expected_piece_size(METADATA_PIECE_SIZE, 0),
+            METADATA_PIECE_SIZE
+        );
+    }
+
+    #[test]
+    fn worker_downloads_every_piece() {
+        let metadata = metadata(2 * METADATA_PIECE_SIZE + 100);
+        let peer = serving_peer(metadata.clone());
+        let swarm = MetadataSwarm::new(&sha1_it(&metadata), &client());
+
+        swarm.start_worker(0);
+        metadata_worker(&swarm, 0, &peer).unwrap();
+        assert_eq!(swarm.metadata(), Some(metadata));
+    }
+
+    #[test]
+    fn oversized_metadata_is_refused() {
+        let info_hash = vec![1; 20];
+        let peer = stand_in_peer(info_hash.clone(), MAX_METADATA_SIZE + 1, |_| {
+            unreachable!("nothing may be requested")
+        });
+        let swarm = MetadataSwarm::new(&info_hash, &client());
+
+        assert!(matches!(
+            metadata_worker(&swarm, 0, &peer),
+            Err(MetadataError::MetadataTooLarge(size)) if size == MAX_METADATA_SIZE + 1
+        ));
+    }
+
+    #[test]
+    fn piece_of_wrong_size_is_invalid() {
+        let metadata = metadata(METADATA_PIECE_SIZE + 100);
+        let info_hash = sha1_it(&metadata);
+        let peer = stand_in_peer(info_hash.clone(), metadata.len(), |piece| {
+            MetadataMessage::Data {
+                piece,
+                total_size: METADATA_PIECE_SIZE + 100,
+                data: vec![b'x'; 10],
+            }
+        });
+        let swarm = MetadataSwarm::new(&info_hash, &client());
+
+        swarm.start_worker(0);
+        assert!(matches!(
+            metadata_worker(&swarm, 0, &peer),
+            Err(MetadataError::InvalidPiece(_))
+        ));
+    }
+
+    #[test]
+    fn rejected_piece_fails_the_worker() {
+        let metadata = metadata(100);
+        let info_hash = sha1_it(&metadata);
+        let peer = stand_in_peer(info_hash.clone(), metadata.len(), |piece| {
+            MetadataMessage::Reject { piece }
+        });
+        let swarm = MetadataSwarm::new(&info_hash, &client());
+
+        swarm.start_worker(0);
+        assert!(matches!(
+            metadata_worker(&swarm, 0, &peer),
+            Err(MetadataError::PieceRejected(0))
+        ));
+    }
+
+    #[test]
+    fn server_answers_requests() {
+        let metadata = metadata(METADATA_PIECE_SIZE + 100);
+        let info_hash = sha1_it(&metadata);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = listener.local_addr().unwrap();
+
+        let requester = {
+            let info_hash = info_hash.clone();
+            thread::spawn(move || {
+                let mut stream = listener.accept().unwrap().0;
+                let handshake = accept_peer(&mut stream, &info_hash, None);
+                let metadata_id = handshake.extension_id("ut_metadata").unwrap();
+
+                let mut responses = Vec::new();
+                for piece in [1, 0, 2] {
+                    let request = MetadataMessage::Request { piece }.to_bytes();
+                    send(
+                        &mut stream,
+                        &Message::Extended {
+                            id: metadata_id,
+                            payload: request,
+                        },
+                    );
+                    loop {
+                        if let Some(Message::Extended { id, payload }) = receive(&mut stream) {
+                            assert_eq!(id, STAND_IN_METADATA_ID);
+                            responses.push(MetadataMessage::from_bytes(&payload).unwrap());
+                            break;
+                        }
+                    }
+                }
+                (handshake.metadata_size, responses)
+            })
+        };
+
+        let client = client();
+        let mut connection = PeerConnection::handshake(
+            &peer,
+            &info_hash,
+            &client.peer_id,
+            true,
+            client.peer_timeouts,
+            client.encryption,
+        )
+        .unwrap();
+        let mut extensions = ExtensionRegistry::new();
+        extensions.register(MetadataServer::new(&metadata));
+        extensions.send_handshake(&mut connection, &client).unwrap();
+        // requester hangs up after its last response
+        while let Ok(message) = connection.read_message() {
+            extensions
+                .handle_message(&mut connection, &message)
+                .unwrap();
+        }
+
+        let (metadata_size, responses) = requester.join().unwrap();
+        assert_eq!(metadata_size, Some(metadata.len() as i64));
+        assert_eq!(
+            responses,
+            [
+                MetadataMessage::Data {
+                    piece: 1,
+                    total_size: metadata.len(),
+                    data: metadata[METADATA_PIECE_SIZE..].to_vec(),
+                },
+                MetadataMessage::Data {
+                    piece: 0,
+                    total_size: metadata.len(),
+                    data: metadata[..METADATA_PIECE_SIZE].to_vec(),
+                },
+                MetadataMessage::Reject { piece: 2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn server_rejects_pieces_out_of_range() {
+        let metadata = metadata(100);
+        let server = MetadataServer::new(&metadata);
+
+        assert_eq!(server.metadata_piece(0), Some(&metadata[..]));
+        assert_eq!(server.metadata_piece(1), None);
+        assert_eq!(server.metadata_piece(usize::MAX), None);
+    }
+
+    #[test]
+    fn swarm_assembles_pieces_of_several_workers() {
+        let metadata = metadata(2 * METADATA_PIECE_SIZE + 100);
+        let swarm = MetadataSwarm::new(&sha1_it(&metadata), &client());
+        let piece_data = |piece: usize| {
+            let start = piece * METADATA_PIECE_SIZE;
+            metadata[start..start + expected_piece_size(metadata.len(), piece)].to_vec()
+        };
+
+        let first = swarm.take_piece(0, metadata.len(), false).unwrap().unwrap();
+        let second = swarm.take_piece(1, metadata.len(), false).unwrap().unwrap();
+        let third = swarm.take_piece(1, metadata.len(), false).unwrap().unwrap();
+        assert_eq!((first, second, third), (0, 1, 2));
+        assert_eq!(swarm.take_piece(0, metadata.len(), false).unwrap(), None);
+        assert!(matches!(
+            swarm.take_piece(0, metadata.len() + 1, false),
+            Err(MetadataError::InvalidMessage)
+        ));
+
+        // worker 1 gives up, its unfinished piece goes to worker 0
+        swarm.store_piece(1, 1, piece_data(1));
+        swarm.release_worker(1);
+        assert_eq!(swarm.take_piece(0, metadata.len(), false).unwrap(), Some(2));
+        // late piece of the released worker is ignored
+        swarm.store_piece(1, 2, vec![0; 100]);
+
+        swarm.store_piece(0, 0, piece_data(0));
+        assert_eq!(swarm.metadata(), None);
+        swarm.store_piece(0, 2, piece_data(2));
+        assert_eq!(swarm.metadata(), Some(metadata.clone()));
+        assert_eq!(swarm.take_piece(0, metadata.len(), true).unwrap(), None);
+    }
+
+    #[test]
+    fn hash_mismatch_drops_providers_and_starts_over() {
+        let metadata = metadata(METADATA_PIECE_SIZE + 100);
+        let swarm = MetadataSwarm::new(&sha1_it(&metadata), &client());
+
+        assert_eq!(swarm.take_piece(0, metadata.len(), false).unwrap(), Some(0));
+        assert_eq!(swarm.take_piece(1, metadata.len(), false).unwrap(), Some(1));
+        swarm.store_piece(0, 0, metadata[..METADATA_PIECE_SIZE].to_vec());
+        swarm.store_piece(1, 1, vec![b'?'; 100]);
+        assert_eq!(swarm.metadata(), None);
+
+        // both providers are dropped, a fresh worker starts from the first piece
+        assert_eq!(swarm.take_piece(0, metadata.len(), true).unwrap(), None);
+        assert_eq!(swarm.take_piece(1, metadata.len(), true).unwrap(), None);
+        assert_eq!(swarm.take_piece(2, metadata.len(), false).unwrap(), Some(0));
+        assert_eq!(swarm.take_piece(2, metadata.len(), false).unwrap(), Some(1));
+    }
+}
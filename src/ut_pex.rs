@@ -0,0 +1,325 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    discover_peers::{encode_compact_peers, parse_compact_peers},
+    extensions::{Extension, ExtensionHandshake},
+    peer_connection::{Message, PeerConnection, PeerError},
+    peer_pool::{PeerPool, PeerSource, PexState},
+};
+
+/// Peers must not send ut_pex messages more often than once a minute (BEP 11)
+pub const PEX_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Peers a connection went fine with this recently are advertised, older ones are dropped
+pub const GOOD_PEER_MAX_AGE: Duration = Duration::from_secs(10 * 60);
+
+/// Upper bound of added and dropped peers in a single message
+pub const MAX_PEX_PEERS: usize = 50;
+
+pub const FLAG_PREFERS_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_SUPPORTS_UTP: u8 = 0x04;
+pub const FLAG_SUPPORTS_HOLEPUNCH: u8 = 0x08;
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+#[derive(Default, Serialize, Deserialize)]
+struct PexPayload {
+    #[serde(default)]
+    added: ByteBuf,
+    #[serde(rename = "added.f", default)]
+    added_flags: ByteBuf,
+    #[serde(default)]
+    added6: ByteBuf,
+    #[serde(rename = "added6.f", default)]
+    added6_flags: ByteBuf,
+    #[serde(default)]
+    dropped: ByteBuf,
+    #[serde(default)]
+    dropped6: ByteBuf,
+}
+
+/// Peers connected and disconnected since the previous message
+#[derive(Debug, Default, PartialEq)]
+pub struct PexMessage {
+    pub added: Vec<(SocketAddr, u8)>,
+    pub dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    /// Serializes message into ut_pex payload (without the extended message id)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let added: Vec<_> = self.added.iter().map(|(peer, _)| *peer).collect();
+        let (added, added6) = encode_compact_peers(&added);
+        let (dropped, dropped6) = encode_compact_peers(&self.dropped);
+
+        // flags follow the same order as peers in the compact strings
+        let flags = |ipv6: bool| {
+            self.added
+                .iter()
+                .filter(|(peer, _)| peer.is_ipv6() == ipv6)
+                .map(|(_, flags)| *flags)
+                .collect::<Vec<_>>()
+        };
+
+        serde_bencode::to_bytes(&PexPayload {
+            added: ByteBuf::from(added),
+            added_flags: ByteBuf::from(flags(false)),
+            added6: ByteBuf::from(added6),
+            added6_flags: ByteBuf::from(flags(true)),
+            dropped: ByteBuf::from(dropped),
+            dropped6: ByteBuf::from(dropped6),
+        })
+        .unwrap()
+    }
+
+    /// Parses ut_pex message payload (without the extended message id)
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let payload: PexPayload = serde_bencode::from_bytes(bytes).ok()?;
+
+        let with_flags = |peers: Vec<SocketAddr>, flags: &[u8]| {
+            peers
+                .into_iter()
+                .enumerate()
+                .map(|(i, peer)| (peer, flags.get(i).copied().unwrap_or(0)))
+                .collect::<Vec<_>>()
+        };
+
+        let mut added = with_flags(
+            parse_compact_peers(&payload.added, 6)?,
+            &payload.added_flags,
+        );
+        added.extend(with_flags(
+            parse_compact_peers(&payload.added6, 18)?,
+            &payload.added6_flags,
+        ));
+
+        let mut dropped = parse_compact_peers(&payload.dropped, 6)?;
+        dropped.extend(parse_compact_peers(&payload.dropped6, 18)?);
+
+        Some(PexMessage { added, dropped })
+    }
+}
+
+/// Exchanges peers of the pool with a single connected peer,
+/// what was sent to the peer is kept in the pool
+pub struct PeerExchange<'a> {
+    pool: &'a PeerPool,
+    peer: SocketAddr,
+    peer_extension_id: Option<u8>,
+}
+
+impl<'a> PeerExchange<'a> {
+    pub fn new(pool: &'a PeerPool, peer: SocketAddr) -> Self {
+        PeerExchange {
+            pool,
+            peer,
+            peer_extension_id: None,
+        }
+    }
+
+    /// Sends peers which turned good or stale since the last message, at most once per
+    /// `PEX_INTERVAL`. Candidates we never connected to are not passed on.
+    pub fn send_update(&mut self, connection: &mut PeerConnection) -> Result<(), PeerError> {
+        let Some(peer_extension_id) = self.peer_extension_id else {
+            return Ok(());
+        };
+
+        let good: Vec<_> = self
+            .pool
+            .recently_good_peers(GOOD_PEER_MAX_AGE)
+            .into_iter()
+            .filter(|(peer, _)| *peer != self.peer)
+            .collect();
+
+        let pex_message = self
+            .pool
+            .update_pex_state(self.peer, |state| next_message(state, &good));
+        let Some(pex_message) = pex_message else {
+            return Ok(());
+        };
+
+        connection.send_message(&Message::Extended {
+            id: peer_extension_id,
+            payload: pex_message.to_bytes(),
+        })
+    }
+}
+
+/// Message telling the peer what changed since `state`, `None` when nothing did
+/// or the previous message was sent less than `PEX_INTERVAL` ago
+fn next_message(state: &mut PexState, good: &[(SocketAddr, u8)]) -> Option<PexMessage> {
+    if state
+        .last_sent
+        .is_some_and(|last_sent| last_sent.elapsed() < PEX_INTERVAL)
+    {
+        return None;
+    }
+
+    let added: Vec<_> = good
+        .iter()
+        .filter(|(peer, _)| !state.advertised.contains(peer))
+        .take(MAX_PEX_PEERS)
+        .copied()
+        .collect();
+    let dropped: Vec<_> = state
+        .advertised
+        .iter()
+        .filter(|peer| !good.iter().any(|(good, _)| good == *peer))
+        .take(MAX_PEX_PEERS)
+        .copied()
+        .collect();
+
+    if added.is_empty() && dropped.is_empty() {
+        return None;
+    }
+
+    for peer in &dropped {
+        state.advertised.remove(peer);
+    }
+    state.advertised.extend(added.iter().map(|(peer, _)| *peer));
+    state.last_sent = Some(Instant::now());
+    Some(PexMessage { added, dropped })
+}
+
+impl Extension for PeerExchange<'_> {
+    fn name(&self) -> &'static str {
+        "ut_pex"
+    }
+
+    fn on_handshake(
+        &mut self,
+        connection: &mut PeerConnection,
+        _handshake: &ExtensionHandshake,
+        peer_extension_id: Option<u8>,
+    ) -> Result<(), PeerError> {
+        self.peer_extension_id = peer_extension_id;
+        // first message contains all our peers, it waits only if an earlier connection
+        // to the peer sent one less than `PEX_INTERVAL` ago
+        self.send_update(connection)
+    }
+
+    fn on_message(
+        &mut self,
+        _connection: &mut PeerConnection,
+        payload: &[u8],
+    ) -> Result<(), PeerError> {
+        if let Some(pex_message) = PexMessage::from_bytes(payload) {
+            let added = self.pool.add_with_flags(
+                pex_message.added.into_iter().take(MAX_PEX_PEERS),
+                PeerSource::Pex,
+            );
+            if added > 0 {
+                println!("Peer {} told us about {} new peers", self.peer, added);
+            }
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self, connection: &mut PeerConnection) -> Result<(), PeerError> {
+        self.send_update(connection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    fn peer6(port: u16) -> SocketAddr {
+        SocketAddr::from(([0xfe80, 0, 0, 0, 0, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let message = PexMessage {
+            added: vec![
+                (peer(1), FLAG_SEED),
+                (peer6(2), FLAG_PREFERS_ENCRYPTION | FLAG_REACHABLE),
+                (peer(3), 0),
+            ],
+            dropped: vec![peer(4), peer6(5)],
+        };
+
+        let bytes = message.to_bytes();
+        let payload: PexPayload = serde_bencode::from_bytes(&bytes).unwrap();
+        assert_eq!(payload.added.len(), 2 * 6);
+        assert_eq!(payload.added_flags.as_slice(), [FLAG_SEED, 0]);
+        assert_eq!(payload.added6.len(), 18);
+        assert_eq!(
+            payload.added6_flags.as_slice(),
+            [FLAG_PREFERS_ENCRYPTION | FLAG_REACHABLE]
+        );
+        assert_eq!(payload.dropped.len(), 6);
+        assert_eq!(payload.dropped6.len(), 18);
+
+        // IPv4 peers come first after parsing
+        assert_eq!(
+            PexMessage::from_bytes(&bytes).unwrap(),
+            PexMessage {
+                added: vec![
+                    (peer(1), FLAG_SEED),
+                    (peer(3), 0),
+                    (peer6(2), FLAG_PREFERS_ENCRYPTION | FLAG_REACHABLE),
+                ],
+                dropped: vec![peer(4), peer6(5)],
+            }
+        );
+    }
+
+    #[test]
+    fn missing_keys_and_flags_are_empty() {
+        let message = PexMessage::from_bytes(b"d5:added6:\x0a\x00\x00\x01\x00\x01e").unwrap();
+        assert_eq!(message.added, vec![(peer(1), 0)]);
+        assert!(message.dropped.is_empty());
+
+        assert!(PexMessage::from_bytes(b"d5:added5:aaaaae").is_none());
+        assert!(PexMessage::from_bytes(b"garbage").is_none());
+    }
+
+    #[test]
+    fn messages_are_capped_and_rate_limited() {
+        let mut state = PexState::default();
+        let good: Vec<_> = (1..=MAX_PEX_PEERS as u16 + 10)
+            .map(|port| (peer(port), 0))
+            .collect();
+
+        let first = next_message(&mut state, &good).unwrap();
+        assert_eq!(first.added.len(), MAX_PEX_PEERS);
+        assert!(next_message(&mut state, &good).is_none());
+
+        // the rest goes out in the next message, peers which are no longer good get dropped
+        state.last_sent = Some(Instant::now() - PEX_INTERVAL);
+        let second = next_message(&mut state, &good[1..]).unwrap();
+        assert_eq!(second.added, good[MAX_PEX_PEERS..]);
+        assert_eq!(second.dropped, vec![peer(1)]);
+
+        state.last_sent = None;
+        assert!(next_message(&mut state, &good[1..]).is_none());
+    }
+
+    #[test]
+    fn only_recently_good_peers_are_advertised() {
+        let pool = PeerPool::new();
+        pool.add([peer(1), peer(2), peer(3)], PeerSource::Tracker);
+        for _ in 0..3 {
+            pool.next_candidate().unwrap();
+        }
+        pool.disconnected(&peer(1));
+        pool.retry_later(&peer(2));
+
+        assert_eq!(
+            pool.recently_good_peers(GOOD_PEER_MAX_AGE),
+            vec![(peer(1), 0)]
+        );
+        assert!(pool.recently_good_peers(Duration::ZERO).is_empty());
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    collections::HashSet,
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use bittorrent_starter_rust::{
+    client_config::ClientConfig,
+    discover_peers::{discover_peers, scrape, TrackerError},
+    tracker_server::{ServerAnnounce, ServerAnnounceEvent, TrackerServer, TrackerServerConfig},
+};
+
+const INFO_HASH: [u8; 20] = *b"tracker-test-hash-01";
+const OTHER_INFO_HASH: [u8; 20] = *b"tracker-test-hash-02";
+
+/// Local tracker on ephemeral ports, returns its HTTP and UDP announce urls
+fn start_tracker(config: TrackerServerConfig) -> (Arc<TrackerServer>, String, String) {
+    let server = TrackerServer::new(config);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let http_url = format!("http://{}/announce", listener.local_addr().unwrap());
+    server.serve_http(listener);
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let udp_url = format!("udp://{}/announce", socket.local_addr().unwrap());
+    server.serve_udp(socket);
+
+    (server, http_url, udp_url)
+}
+
+fn client(port: u16) -> ClientConfig {
+    ClientConfig {
+        port,
+        ..Default::default()
+    }
+}
+
+fn peer(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[test]
+fn http_announce_returns_compact_peers() {
+    let (_server, http_url, _) = start_tracker(TrackerServerConfig::default());
+
+    let first = discover_peers(&client(6001), &INFO_HASH, 100, &http_url).unwrap();
+    assert!(first.peers.is_empty());
+
+    let second = discover_peers(&client(6002), &INFO_HASH, 100, &http_url).unwrap();
+    assert_eq!(second.peers, vec![peer(6001)]);
+    assert_eq!(second.incomplete, Some(2));
+    assert_eq!(second.complete, Some(0));
+    assert_eq!(
+        second.interval,
+        TrackerServerConfig::default().interval,
+        "tracker interval is passed on"
+    );
+
+    // a seeder is told about leechers only
+    let seeder = discover_peers(&client(6003), &INFO_HASH, 0, &http_url).unwrap();
+    let peers: HashSet<_> = seeder.peers.into_iter().collect();
+    assert_eq!(peers, HashSet::from([peer(6001), peer(6002)]));
+}
+
+#[test]
+fn udp_announce_shares_swarm_with_http() {
+    let (_server, http_url, udp_url) = start_tracker(TrackerServerConfig::default());
+
+    discover_peers(&client(6011), &INFO_HASH, 100, &http_url).unwrap();
+    let response = discover_peers(&client(6012), &INFO_HASH, 100, &udp_url).unwrap();
+
+    assert_eq!(response.peers, vec![peer(6011)]);
+    assert_eq!(response.incomplete, Some(2));
+}
+
+#[test]
+fn scrape_counts_seeders_and_leechers() {
+    let (_server, http_url, udp_url) = start_tracker(TrackerServerConfig::default());
+
+    discover_peers(&client(6021), &INFO_HASH, 0, &http_url).unwrap();
+    discover_peers(&client(6022), &INFO_HASH, 100, &http_url).unwrap();
+
+    for url in [&http_url, &udp_url] {
+        let stats = scrape(&client(6023), url, &[&INFO_HASH, &OTHER_INFO_HASH]).unwrap();
+        let info_hash_stats = stats[INFO_HASH.as_slice()];
+        assert_eq!(info_hash_stats.complete, 1, "{}", url);
+        assert_eq!(info_hash_stats.incomplete, 1, "{}", url);
+    }
+
+    // HTTP leaves unknown torrents out, UDP has to answer with zeroes
+    let http_stats = scrape(&client(6023), &http_url, &[&OTHER_INFO_HASH]).unwrap();
+    assert!(!http_stats.contains_key(OTHER_INFO_HASH.as_slice()));
+    let udp_stats = scrape(&client(6023), &udp_url, &[&OTHER_INFO_HASH]).unwrap();
+    assert_eq!(udp_stats[OTHER_INFO_HASH.as_slice()].incomplete, 0);
+}
+
+#[test]
+fn whitelist_rejects_other_torrents() {
+    let (_server, http_url, udp_url) = start_tracker(TrackerServerConfig {
+        whitelist: Some(HashSet::from([INFO_HASH.to_vec()])),
+        ..Default::default()
+    });
+
+    for url in [&http_url, &udp_url] {
+        assert!(discover_peers(&client(6031), &INFO_HASH, 100, url).is_ok());
+
+        let result = discover_peers(&client(6031), &OTHER_INFO_HASH, 100, url);
+        assert!(
+            matches!(&result, Err(TrackerError::Failure(reason)) if reason == "torrent is not tracked"),
+            "{}: {:?}",
+            url,
+            result
+        );
+    }
+}
+
+#[test]
+fn silent_peers_expire() {
+    let (_server, http_url, _) = start_tracker(TrackerServerConfig {
+        peer_expiry: Duration::from_millis(300),
+        ..Default::default()
+    });
+
+    discover_peers(&client(6041), &INFO_HASH, 100, &http_url).unwrap();
+    let response = discover_peers(&client(6042), &INFO_HASH, 100, &http_url).unwrap();
+    assert_eq!(response.peers, vec![peer(6041)]);
+
+    thread::sleep(Duration::from_millis(500));
+
+    let response = discover_peers(&client(6043), &INFO_HASH, 100, &http_url).unwrap();
+    assert!(response.peers.is_empty());
+    assert_eq!(response.incomplete, Some(1));
+}
+
+#[test]
+fn announce_without_left_is_rejected() {
+    let (_server, http_url, _) = start_tracker(TrackerServerConfig::default());
+    let address = http_url
+        .trim_start_matches("http://")
+        .trim_end_matches("/announce");
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    write!(
+        stream,
+        "GET /announce?info_hash={}&peer_id={}&port=6051 HTTP/1.1\r\nHost: {}\r\n\r\n",
+        "%41".repeat(20),
+        "%42".repeat(20),
+        address
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    assert!(
+        response.ends_with("d14:failure reason12:missing lefte"),
+        "{}",
+        response
+    );
+}
+
+#[test]
+fn empty_swarms_are_dropped() {
+    let (_server, http_url, _) = start_tracker(TrackerServerConfig {
+        peer_expiry: Duration::from_millis(300),
+        ..Default::default()
+    });
+
+    discover_peers(&client(6061), &INFO_HASH, 100, &http_url).unwrap();
+    let stats = scrape(&client(6062), &http_url, &[&INFO_HASH]).unwrap();
+    assert_eq!(stats[INFO_HASH.as_slice()].incomplete, 1);
+
+    // scrape doesn't count expired peers and forgets the swarm they leave behind
+    thread::sleep(Duration::from_millis(500));
+    let stats = scrape(&client(6062), &http_url, &[&INFO_HASH]).unwrap();
+    assert!(!stats.contains_key(INFO_HASH.as_slice()));
+}
+
+#[test]
+fn swarms_expire_without_requests() {
+    let (server, _, _) = start_tracker(TrackerServerConfig {
+        peer_expiry: Duration::from_millis(200),
+        ..Default::default()
+    });
+    server
+        .announce(ServerAnnounce {
+            info_hash: INFO_HASH.to_vec(),
+            peer_id: vec![1; 20],
+            address: peer(6071),
+            left: 100,
+            event: ServerAnnounceEvent::Started,
+            numwant: None,
+        })
+        .unwrap();
+    assert_eq!(server.torrents_count(), 1);
+
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(server.torrents_count(), 0);
+}
+
+#[test]
+fn oversized_request_is_refused() {
+    let (_server, http_url, _) = start_tracker(TrackerServerConfig::default());
+    let address = http_url
+        .trim_start_matches("http://")
+        .trim_end_matches("/announce");
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    // tracker may hang up before everything is sent
+    let _ = write!(
+        stream,
+        "GET /announce?{} HTTP/1.1\r\n\r\n",
+        "a".repeat(64 * 1024)
+    );
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+
+    assert!(response.is_empty(), "{}", response);
+}
@@ -0,0 +1,96 @@
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use bittorrent_starter_rust::{
+    client_config::ClientConfig,
+    discover_peers::{discover_peers, scrape},
+    peer_pool::PeerPool,
+    tracker_server::{TrackerServer, TrackerServerConfig},
+    tracker_session::{TrackerSession, TransferStats},
+};
+
+const INFO_HASH: [u8; 20] = *b"session-test-hash-01";
+
+/// Local HTTP tracker on an ephemeral port, returns its announce url
+fn start_tracker() -> (Arc<TrackerServer>, String) {
+    let server = TrackerServer::new(TrackerServerConfig::default());
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let http_url = format!("http://{}/announce", listener.local_addr().unwrap());
+    server.serve_http(listener);
+
+    (server, http_url)
+}
+
+/// Url nobody listens on, announces to it fail right away
+fn dead_tracker() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    format!("http://{}/announce", listener.local_addr().unwrap())
+}
+
+fn client(port: u16) -> ClientConfig {
+    ClientConfig {
+        port,
+        ..Default::default()
+    }
+}
+
+fn peer(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[test]
+fn session_falls_back_to_next_tracker_of_tier() {
+    let (_server, http_url) = start_tracker();
+    let tiers = vec![vec![dead_tracker(), http_url.clone()]];
+    let stats = Arc::new(TransferStats::new(100));
+
+    let session = TrackerSession::start(
+        &client(6101),
+        &tiers,
+        &INFO_HASH,
+        stats.clone(),
+        Arc::new(PeerPool::new()),
+    );
+    let response = discover_peers(&client(6102), &INFO_HASH, 100, &http_url).unwrap();
+    assert_eq!(response.peers, vec![peer(6101)]);
+
+    stats.record_download(100);
+    session.completed();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = scrape(&client(6102), &http_url, &[&INFO_HASH]).unwrap();
+        if stats[INFO_HASH.as_slice()].complete == 1 {
+            break;
+        }
+        assert!(Instant::now() < deadline, "completed was not announced");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    // dropping the session announces `stopped`
+    drop(session);
+    let response = discover_peers(&client(6103), &INFO_HASH, 100, &http_url).unwrap();
+    assert_eq!(response.peers, vec![peer(6102)]);
+}
+
+#[test]
+fn session_without_reachable_tracker_keeps_running() {
+    let tiers = vec![vec![dead_tracker()], vec![dead_tracker()]];
+    let peer_pool = Arc::new(PeerPool::new());
+
+    let session = TrackerSession::start(
+        &client(6111),
+        &tiers,
+        &INFO_HASH,
+        Arc::new(TransferStats::new(100)),
+        peer_pool.clone(),
+    );
+    session.completed();
+    drop(session);
+
+    assert!(peer_pool.is_empty());
+}